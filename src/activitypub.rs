@@ -0,0 +1,1093 @@
+//! ActivityPub federation, so quiet users can be followed from Mastodon and
+//! other fediverse servers. Each profile is published as an actor at
+//! `/user/<id>` (for clients that ask for ActivityPub JSON), found through
+//! WebFinger, and its posts are delivered to remote followers as they're made.
+//!
+//! Outgoing requests are signed with one instance-wide RSA key pair, read
+//! from the `AP_PRIVATE_KEY` and `AP_PUBLIC_KEY` secrets. Without them,
+//! federation is switched off.
+use crate::console_logf;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::blocks::{self, Account};
+use crate::models::followers::{self, FollowRequest, Follower};
+use crate::models::posts::{self, Visibility};
+use crate::models::users;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use signatures::{Signature, SigningKey};
+use std::collections::BTreeSet;
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    AbortSignal, Headers, Request, RequestInit, Response, ResponseInit, ServiceWorkerGlobalScope,
+};
+
+mod signatures;
+
+const ACTIVITY_JSON: &str = "application/activity+json";
+const AS_CONTEXT: &str = "https://www.w3.org/ns/activitystreams";
+const SECURITY_CONTEXT: &str = "https://w3id.org/security/v1";
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// How many recent posts the outbox lists.
+const OUTBOX_SIZE: usize = 20;
+
+/// How long a request to another server gets before we give up on it, so
+/// one slow server can't hold up the rest.
+const REMOTE_TIMEOUT_MS: u32 = 10_000;
+
+/// URLs of a quiet user's ActivityPub objects. They all hang off the
+/// profile page, which doubles as the actor ID.
+struct ActorUrls {
+    id: Url,
+}
+
+impl ActorUrls {
+    fn new(base: &Url, user_id: Uuid) -> Self {
+        let mut id = base.clone();
+        id.set_path(&format!("/user/{}", user_id));
+        id.set_query(None);
+        id.set_fragment(None);
+        Self { id }
+    }
+
+    fn child(&self, path: &str) -> Url {
+        let mut url = self.id.clone();
+        url.set_path(&format!("{}/{}", self.id.path(), path));
+        url
+    }
+
+    fn inbox(&self) -> Url {
+        self.child("inbox")
+    }
+
+    fn outbox(&self) -> Url {
+        self.child("outbox")
+    }
+
+    fn followers(&self) -> Url {
+        self.child("followers")
+    }
+
+    fn note(&self, post_id: Uuid) -> Url {
+        self.child(&format!("posts/{}", post_id))
+    }
+
+    fn key_id(&self) -> String {
+        format!("{}#main-key", self.id)
+    }
+}
+
+fn actor_document(base: &Url, profile: &users::Profile, public_pem: &str) -> Value {
+    let urls = ActorUrls::new(base, profile.id);
+    json!({
+        "@context": [AS_CONTEXT, SECURITY_CONTEXT],
+        "id": urls.id,
+        "type": "Person",
+        "preferredUsername": profile.username,
+        "name": profile.username,
+        "summary": format!("<p>{}</p>", handlebars::html_escape(&profile.bio)),
+        "url": urls.id,
+        "inbox": urls.inbox(),
+        "outbox": urls.outbox(),
+        "followers": urls.followers(),
+        "manuallyApprovesFollowers": profile.is_private,
+        "icon": { "type": "Image", "url": profile.pic },
+        "published": profile.date_joined.to_rfc3339(),
+        "publicKey": {
+            "id": urls.key_id(),
+            "owner": urls.id,
+            "publicKeyPem": public_pem,
+        },
+    })
+}
+
+/// Who a post is addressed to, as `to` and `cc`, the way Mastodon does it.
+/// Unlisted posts copy in everyone rather than addressing them, so servers
+/// don't show them on public timelines.
+fn audience(urls: &ActorUrls, visibility: Visibility) -> (Value, Value) {
+    match visibility {
+        Visibility::Public => (json!([PUBLIC]), json!([urls.followers()])),
+        Visibility::Unlisted => (json!([urls.followers()]), json!([PUBLIC])),
+        Visibility::FollowersOnly => (json!([urls.followers()]), json!([])),
+    }
+}
+
+fn note(base: &Url, post: &posts::Post, visibility: Visibility) -> Value {
+    let urls = ActorUrls::new(base, post.user_id);
+    let (to, cc) = audience(&urls, visibility);
+    let mut content = format!("<p>{}</p>", handlebars::html_escape(&post.text));
+    if let Some(link) = &post.link {
+        let link = handlebars::html_escape(link.as_str());
+        content.push_str(&format!("<p><a href=\"{}\">{}</a></p>", link, link));
+    }
+    json!({
+        "id": urls.note(post.id),
+        "type": "Note",
+        "attributedTo": urls.id,
+        "content": content,
+        "published": post.created.to_rfc3339(),
+        "to": to,
+        "cc": cc,
+    })
+}
+
+fn create_activity(base: &Url, post: &posts::Post, visibility: Visibility) -> Value {
+    let urls = ActorUrls::new(base, post.user_id);
+    let (to, cc) = audience(&urls, visibility);
+    json!({
+        "@context": AS_CONTEXT,
+        "id": format!("{}#create", urls.note(post.id)),
+        "type": "Create",
+        "actor": urls.id,
+        "published": post.created.to_rfc3339(),
+        "to": to,
+        "cc": cc,
+        "object": note(base, post, visibility),
+    })
+}
+
+/// Tells other servers the user's account is gone, so they can remove it
+/// and its posts.
+fn delete_actor_activity(base: &Url, user_id: Uuid) -> Value {
+    let urls = ActorUrls::new(base, user_id);
+    json!({
+        "@context": AS_CONTEXT,
+        "id": format!("{}#delete", urls.id),
+        "type": "Delete",
+        "actor": urls.id,
+        "to": [PUBLIC],
+        "object": urls.id,
+    })
+}
+
+fn outbox_document(base: &Url, user_id: Uuid, total: usize, posts: &[posts::Post]) -> Value {
+    let urls = ActorUrls::new(base, user_id);
+    let items: Vec<_> = posts
+        .iter()
+        .map(|p| create_activity(base, p, p.visibility))
+        .collect();
+    json!({
+        "@context": AS_CONTEXT,
+        "id": urls.outbox(),
+        "type": "OrderedCollection",
+        "totalItems": total,
+        "orderedItems": items,
+    })
+}
+
+fn webfinger_document(base: &Url, subject: &str, profile: &users::Profile) -> Value {
+    let urls = ActorUrls::new(base, profile.id);
+    json!({
+        "subject": subject,
+        "aliases": [urls.id],
+        "links": [
+            { "rel": "self", "type": ACTIVITY_JSON, "href": urls.id },
+            { "rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": urls.id },
+        ],
+    })
+}
+
+/// How federation talks to other servers. The worker uses `Fetch`; tests
+/// substitute a stub server.
+pub trait Transport {
+    /// GETs an ActivityPub JSON document.
+    fn get_json(&self, url: &Url) -> LocalBoxFuture<'_, Fallible<Value>>;
+
+    /// POSTs an activity to an inbox.
+    fn post(
+        &self,
+        url: &Url,
+        headers: Vec<(String, String)>,
+        body: String,
+    ) -> LocalBoxFuture<'_, Fallible<()>>;
+}
+
+/// What an activity arriving in a user's inbox asks us to do.
+pub enum Incoming {
+    Follow {
+        follower: Follower,
+        /// The Follow activity itself, which the Accept has to quote.
+        activity: Value,
+    },
+    Unfollow {
+        actor: Url,
+    },
+    /// Something we don't act on, like a like.
+    Ignored,
+}
+
+/// Checks the signature on a POST to `user_id`'s inbox and works out what
+/// the activity in it means. `header` looks up request headers by
+/// lowercase name.
+pub async fn receive<T, F>(
+    transport: &T,
+    user_id: Uuid,
+    url: &Url,
+    header: F,
+    body: &str,
+    now: DateTime<Utc>,
+) -> Fallible<Incoming>
+where
+    T: Transport,
+    F: Fn(&str) -> Option<String>,
+{
+    let urls = ActorUrls::new(url, user_id);
+    let Some(sig) = header("signature") else {
+        return Err(unauthorized("request isn't signed".to_owned()));
+    };
+    let sig = Signature::parse(&sig).map_err(unauthorized)?;
+    let mut signer_url = Url::parse(&sig.key_id)
+        .map_err(|_| unauthorized(format!("keyId {} isn't a URL", sig.key_id)))?;
+    signer_url.set_fragment(None);
+    let signer = transport.get_json(&signer_url).await?;
+    if signer["publicKey"]["id"].as_str() != Some(&sig.key_id) {
+        return Err(unauthorized(format!(
+            "{} doesn't publish key {}",
+            signer_url, sig.key_id
+        )));
+    }
+    let Some(public_pem) = signer["publicKey"]["publicKeyPem"].as_str() else {
+        return Err(unauthorized(format!("{} has no public key", signer_url)));
+    };
+    sig.verify_post(url, &header, body.as_bytes(), public_pem, now)
+        .map_err(unauthorized)?;
+
+    let activity: Value = serde_json::from_str(body).map_err(|e| Error {
+        internal: format!("invalid activity JSON: {}", e),
+        status: StatusCode::BAD_REQUEST,
+        external: Message::new("error-invalid-activity").arg("reason", e.to_string()),
+    })?;
+    let actor = activity["actor"].as_str().and_then(|a| Url::parse(a).ok());
+    let Some(actor) = actor else {
+        return Err(bad_activity("activity has no actor"));
+    };
+    if signer["id"].as_str() != Some(actor.as_str()) {
+        return Err(unauthorized(format!(
+            "{} was signed by someone else",
+            actor
+        )));
+    }
+
+    match activity["type"].as_str() {
+        Some("Follow") => {
+            if activity["object"].as_str() != Some(urls.id.as_str()) {
+                return Err(bad_activity("Follow is for a different user"));
+            }
+            let inbox = signer["endpoints"]["sharedInbox"]
+                .as_str()
+                .or_else(|| signer["inbox"].as_str())
+                .and_then(|i| Url::parse(i).ok());
+            let Some(inbox) = inbox else {
+                return Err(bad_activity("follower has no inbox"));
+            };
+            Ok(Incoming::Follow {
+                follower: Follower { actor, inbox },
+                activity,
+            })
+        }
+        Some("Undo") if activity["object"]["type"].as_str() == Some("Follow") => {
+            Ok(Incoming::Unfollow { actor })
+        }
+        _ => Ok(Incoming::Ignored),
+    }
+}
+
+/// Tells a new follower's server that the follow went through.
+pub async fn accept_follow<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    base: &Url,
+    user_id: Uuid,
+    follower: &Follower,
+    follow: Value,
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let urls = ActorUrls::new(base, user_id);
+    answer_follow(transport, key, &urls, follower, follow, "Accept", now).await
+}
+
+/// Tells someone's server that a private user turned down their follow request.
+pub async fn reject_follow<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    base: &Url,
+    user_id: Uuid,
+    follower: &Follower,
+    follow: Value,
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let urls = ActorUrls::new(base, user_id);
+    answer_follow(transport, key, &urls, follower, follow, "Reject", now).await
+}
+
+async fn answer_follow<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    urls: &ActorUrls,
+    follower: &Follower,
+    follow: Value,
+    answer: &str,
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let activity = json!({
+        "@context": AS_CONTEXT,
+        "id": format!("{}#{}s/{}", urls.id, answer.to_lowercase(), Uuid::new_v4()),
+        "type": answer,
+        "actor": urls.id,
+        "object": follow,
+    });
+    deliver(transport, key, urls, &follower.inbox, &activity, now).await
+}
+
+/// Sends a new post to every follower's inbox, addressed as `visibility`
+/// says. Private users' posts should be `FollowersOnly`, whatever the post
+/// says.
+pub async fn deliver_post<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    base: &Url,
+    post: &posts::Post,
+    visibility: Visibility,
+    followers: &[Follower],
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let urls = ActorUrls::new(base, post.user_id);
+    let activity = create_activity(base, post, visibility);
+    deliver_to_followers(transport, key, &urls, &activity, followers, now).await
+}
+
+/// Tells every follower's server that the user deleted their account.
+pub async fn deliver_account_deletion<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    base: &Url,
+    user_id: Uuid,
+    followers: &[Follower],
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let urls = ActorUrls::new(base, user_id);
+    let activity = delete_actor_activity(base, user_id);
+    deliver_to_followers(transport, key, &urls, &activity, followers, now).await
+}
+
+/// Every inbox is sent to at once. Delivery carries on past failures, which
+/// are reported together at the end.
+async fn deliver_to_followers<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    urls: &ActorUrls,
+    activity: &Value,
+    followers: &[Follower],
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    // Followers on the same server usually share an inbox, which only needs the post once.
+    let inboxes: BTreeSet<_> = followers.iter().map(|f| &f.inbox).collect();
+    let deliveries = inboxes
+        .into_iter()
+        .map(|inbox| deliver(transport, key, urls, inbox, activity, now));
+    let failures: Vec<_> = future::join_all(deliveries)
+        .await
+        .into_iter()
+        .filter_map(|delivered| delivered.err().map(|e| e.internal))
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error {
+            internal: failures.join("; "),
+            status: StatusCode::BAD_GATEWAY,
+            external: Message::new("error-delivery-failed"),
+        })
+    }
+}
+
+async fn deliver<T: Transport>(
+    transport: &T,
+    key: &SigningKey,
+    sender: &ActorUrls,
+    inbox: &Url,
+    activity: &Value,
+    now: DateTime<Utc>,
+) -> Fallible<()> {
+    let body = activity.to_string();
+    let mut headers = key
+        .sign_post(&sender.key_id(), inbox, body.as_bytes(), now)
+        .map_err(|e| Error {
+            internal: e,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            external: Message::new("error-sign-activity"),
+        })?;
+    headers.push(("content-type".to_owned(), ACTIVITY_JSON.to_owned()));
+    transport.post(inbox, headers, body).await
+}
+
+fn unauthorized(internal: String) -> Error {
+    Error {
+        internal,
+        status: StatusCode::UNAUTHORIZED,
+        external: Message::new("error-bad-signature"),
+    }
+}
+
+fn bad_activity(reason: &str) -> Error {
+    Error {
+        internal: reason.to_owned(),
+        status: StatusCode::BAD_REQUEST,
+        external: Message::new("error-invalid-activity").arg("reason", reason.to_owned()),
+    }
+}
+
+fn remote_error(url: &Url, e: impl std::fmt::Debug) -> Error {
+    Error {
+        internal: format!("request to {} failed: {:?}", url, e),
+        status: StatusCode::BAD_GATEWAY,
+        external: Message::new("error-remote-unreachable"),
+    }
+}
+
+/// Makes requests with the Workers `fetch` API.
+pub struct Fetch;
+
+impl Fetch {
+    async fn fetch(
+        url: &Url,
+        method: &str,
+        headers: &[(String, String)],
+        body: Option<&str>,
+    ) -> Fallible<Response> {
+        let start = || -> Result<js_sys::Promise, JsValue> {
+            let h = Headers::new()?;
+            for (k, v) in headers {
+                h.append(k, v)?;
+            }
+            let init = RequestInit::new();
+            init.set_method(method);
+            init.set_headers(&JsValue::from(h));
+            init.set_signal(Some(&AbortSignal::timeout_with_u32(REMOTE_TIMEOUT_MS)));
+            if let Some(body) = body {
+                init.set_body(&JsValue::from_str(body));
+            }
+            let req = Request::new_with_str_and_init(url.as_str(), &init)?;
+            let global: ServiceWorkerGlobalScope = js_sys::global().unchecked_into();
+            Ok(global.fetch_with_request(&req))
+        };
+        let promise = start().map_err(|e| remote_error(url, e))?;
+        let resp: Response = JsFuture::from(promise)
+            .await
+            .map_err(|e| remote_error(url, e))?
+            .unchecked_into();
+        if !resp.ok() {
+            return Err(remote_error(url, format!("HTTP {}", resp.status())));
+        }
+        Ok(resp)
+    }
+}
+
+impl Transport for Fetch {
+    fn get_json(&self, url: &Url) -> LocalBoxFuture<'_, Fallible<Value>> {
+        let url = url.clone();
+        async move {
+            let headers = [("accept".to_owned(), ACTIVITY_JSON.to_owned())];
+            let resp = Self::fetch(&url, "GET", &headers, None).await?;
+            let json_f = resp.json().map_err(|e| remote_error(&url, e))?;
+            let json = JsFuture::from(json_f)
+                .await
+                .map_err(|e| remote_error(&url, e))?;
+            from_js(&json).map_err(|e| remote_error(&url, e))
+        }
+        .boxed_local()
+    }
+
+    fn post(
+        &self,
+        url: &Url,
+        headers: Vec<(String, String)>,
+        body: String,
+    ) -> LocalBoxFuture<'_, Fallible<()>> {
+        let url = url.clone();
+        async move {
+            Self::fetch(&url, "POST", &headers, Some(&body)).await?;
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// The instance's signing key, or None if federation isn't set up.
+fn instance_key() -> Fallible<Option<SigningKey>> {
+    match (global_var("AP_PRIVATE_KEY"), global_var("AP_PUBLIC_KEY")) {
+        (Some(private_pem), Some(public_pem)) => SigningKey::from_pem(&private_pem, &public_pem)
+            .map(Some)
+            .map_err(|e| Error {
+                internal: e,
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-federation-misconfigured"),
+            }),
+        _ => Ok(None),
+    }
+}
+
+fn required_instance_key() -> Fallible<SigningKey> {
+    let Some(key) = instance_key()? else {
+        return Err(Error {
+            internal: "AP_PRIVATE_KEY or AP_PUBLIC_KEY isn't set".to_owned(),
+            status: StatusCode::NOT_FOUND,
+            external: Message::new("error-no-federation"),
+        });
+    };
+    Ok(key)
+}
+
+/// Whether the client wants ActivityPub JSON instead of an HTML page.
+pub fn wants_activity_json(req: &Request) -> bool {
+    match req.headers().get("accept") {
+        Ok(Some(accept)) => {
+            accept.contains(ACTIVITY_JSON) || accept.contains("application/ld+json")
+        }
+        _ => false,
+    }
+}
+
+fn request_url(req: &Request) -> Fallible<Url> {
+    Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        status: StatusCode::BAD_REQUEST,
+        external: Message::new("error-invalid-url"),
+    })
+}
+
+fn not_found(what: &str) -> Error {
+    Error {
+        internal: format!("{} not found", what),
+        status: StatusCode::NOT_FOUND,
+        external: Message::new("error-not-found"),
+    }
+}
+
+fn json_response(val: &Value, content_type: &str, status: StatusCode) -> Fallible<Response> {
+    let build = || -> Result<Response, JsValue> {
+        let headers = Headers::new()?;
+        headers.append("content-type", content_type)?;
+        let init = ResponseInit::new();
+        init.set_status(status.as_u16());
+        init.set_headers(&JsValue::from(headers));
+        Response::new_with_opt_str_and_init(Some(&val.to_string()), &init)
+    };
+    build().map_err(|e| Error {
+        internal: format!("{:?}", e),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-build-response"),
+    })
+}
+
+/// `GET /.well-known/webfinger?resource=acct:<username>@<host>`
+pub async fn render_webfinger(req: Request) -> Result<Response, Response> {
+    let url = request_url(&req).map_err(|e| e.into_response())?;
+    let resource = url
+        .query_pairs()
+        .find(|(k, _)| k == "resource")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    let host = url.host_str().unwrap_or_default();
+    let username = resource
+        .strip_prefix("acct:")
+        .and_then(|acct| acct.strip_suffix(&format!("@{}", host)));
+    let Some(username) = username else {
+        return Err(not_found(&resource).into_response());
+    };
+    let profile = users::profile_by_username(username)
+        .await
+        .map_err(|e| e.into_response())?;
+    let Some(profile) = profile else {
+        return Err(not_found(&resource).into_response());
+    };
+    let doc = webfinger_document(&url, &resource, &profile);
+    json_response(&doc, "application/jrd+json", StatusCode::OK).map_err(|e| e.into_response())
+}
+
+pub async fn render_actor(req: Request, user_id: Uuid) -> Result<Response, Response> {
+    let actor = async {
+        let url = request_url(&req)?;
+        let key = required_instance_key()?;
+        let Some(profile) = users::profile_by_id(user_id).await? else {
+            return Err(not_found(&format!("user {}", user_id)));
+        };
+        json_response(
+            &actor_document(&url, &profile, &key.public_pem),
+            ACTIVITY_JSON,
+            StatusCode::OK,
+        )
+    };
+    actor.await.map_err(|e| e.into_response())
+}
+
+pub async fn render_outbox(req: Request, user_id: Uuid) -> Result<Response, Response> {
+    let outbox = async {
+        let url = request_url(&req)?;
+        // A private user's posts only go to the inboxes of the followers
+        // they've approved, so their outbox is empty. So are their unlisted
+        // and followers-only posts.
+        let mut all = match posts::visible_posts_by_user(user_id, None).await {
+            Err(e) if e.status == StatusCode::FORBIDDEN => Vec::new(),
+            all => all?,
+        };
+        all.retain(|p| p.listed_for(None));
+        let total = all.len();
+        let query = posts::PageQuery {
+            before: None,
+            limit: OUTBOX_SIZE,
+        };
+        let page = posts::Page::of(all, &query);
+        let doc = outbox_document(&url, user_id, total, &page.posts);
+        json_response(&doc, ACTIVITY_JSON, StatusCode::OK)
+    };
+    outbox.await.map_err(|e| e.into_response())
+}
+
+/// The follower count, but not who the followers are.
+pub async fn render_followers(req: Request, user_id: Uuid) -> Result<Response, Response> {
+    let collection = async {
+        let url = request_url(&req)?;
+        let followers = followers::followers_of(user_id).await?;
+        let doc = json!({
+            "@context": AS_CONTEXT,
+            "id": ActorUrls::new(&url, user_id).followers(),
+            "type": "OrderedCollection",
+            "totalItems": followers.len(),
+        });
+        json_response(&doc, ACTIVITY_JSON, StatusCode::OK)
+    };
+    collection.await.map_err(|e| e.into_response())
+}
+
+pub async fn render_note(req: Request, user_id: Uuid, post_id: Uuid) -> Result<Response, Response> {
+    let doc = async {
+        let url = request_url(&req)?;
+        let Some(post) = posts::post_by_user(user_id, None, post_id).await? else {
+            return Err(not_found(&format!("post {}", post_id)));
+        };
+        let mut doc = note(&url, &post, post.visibility);
+        doc["@context"] = json!(AS_CONTEXT);
+        json_response(&doc, ACTIVITY_JSON, StatusCode::OK)
+    };
+    doc.await.map_err(|e| e.into_response())
+}
+
+/// `POST /user/<id>/inbox`
+pub async fn receive_inbox(req: Request, user_id: Uuid) -> Result<Response, Response> {
+    let inbox = async {
+        let url = request_url(&req)?;
+        let key = required_instance_key()?;
+        let text_f = req.text().map_err(|e| remote_error(&url, e))?;
+        let body = JsFuture::from(text_f)
+            .await
+            .map_err(|e| remote_error(&url, e))?
+            .as_string()
+            .unwrap_or_default();
+        let headers = req.headers();
+        let header = |name: &str| headers.get(name).ok().flatten();
+        let now = Utc::now();
+        match receive(&Fetch, user_id, &url, header, &body, now).await? {
+            Incoming::Follow { follower, activity } => {
+                let Some(profile) = users::profile_by_id(user_id).await? else {
+                    return Err(not_found(&format!("user {}", user_id)));
+                };
+                let blocked = blocks::blocks_of(user_id).await?;
+                if blocked.blocks(&Account::Remote(follower.actor.clone())) {
+                    return Err(Error {
+                        internal: format!(
+                            "{} is blocked from following {}",
+                            follower.actor, user_id
+                        ),
+                        external: Message::new("error-blocked"),
+                        status: StatusCode::FORBIDDEN,
+                    });
+                }
+                if profile.is_private {
+                    let request = FollowRequest {
+                        actor: follower.actor.clone(),
+                        inbox: follower.inbox,
+                        activity: activity.to_string(),
+                        created: now,
+                    };
+                    followers::add_follow_request(user_id, request).await?;
+                    console_logf!("{} asked to follow {}", follower.actor, user_id);
+                } else {
+                    followers::add_follower(user_id, follower.clone()).await?;
+                    accept_follow(&Fetch, &key, &url, user_id, &follower, activity, now).await?;
+                    console_logf!("{} followed {}", follower.actor, user_id);
+                }
+            }
+            Incoming::Unfollow { actor } => {
+                followers::remove_follower(user_id, &actor).await?;
+                followers::remove_follow_request(user_id, &actor).await?;
+                console_logf!("{} unfollowed {}", actor, user_id);
+            }
+            Incoming::Ignored => {}
+        }
+        json_response(&json!({}), ACTIVITY_JSON, StatusCode::ACCEPTED)
+    };
+    inbox.await.map_err(|e| e.into_response())
+}
+
+/// Delivers a post that was just made to the author's remote followers.
+/// `base` is any URL on this server.
+pub async fn announce_post(base: &Url, post: &posts::Post) -> Fallible<()> {
+    let Some(key) = instance_key()? else {
+        return Ok(());
+    };
+    let followers = followers::followers_of(post.user_id).await?;
+    let visibility = if users::is_private(post.user_id).await? {
+        Visibility::FollowersOnly
+    } else {
+        post.visibility
+    };
+    deliver_post(&Fetch, &key, base, post, visibility, &followers, Utc::now()).await
+}
+
+/// What a private user sends to approve or deny a follow request.
+#[derive(Deserialize)]
+struct FollowAnswer {
+    actor: Url,
+    approve: bool,
+}
+
+/// `POST /settings/follow-requests`, from the settings page.
+pub async fn answer_follow_request(req: Request) -> Result<Response, Response> {
+    let viewer = users::viewer(&req).await.ok().flatten();
+    let lang = i18n::request_language(&req, viewer.as_ref());
+    let answer = async {
+        let viewer = users::signed_in(&req).await?;
+        let answer: FollowAnswer = users::read_json(&req, "error-malformed-follow-answer").await?;
+        let requests = followers::follow_requests_of(viewer.id).await?;
+        let request = requests.into_iter().find(|r| r.actor == answer.actor);
+        let Some(request) = request else {
+            return Err(not_found(&format!("follow request from {}", answer.actor)));
+        };
+        let url = request_url(&req)?;
+        let key = required_instance_key()?;
+        let follower = request.follower();
+        let follow: Value =
+            serde_json::from_str(&request.activity).map_err(|e| bad_activity(&e.to_string()))?;
+        let now = Utc::now();
+        followers::remove_follow_request(viewer.id, &follower.actor).await?;
+        if answer.approve {
+            followers::add_follower(viewer.id, follower.clone()).await?;
+            accept_follow(&Fetch, &key, &url, viewer.id, &follower, follow, now).await?;
+            Ok("flash-follow-approved")
+        } else {
+            reject_follow(&Fetch, &key, &url, viewer.id, &follower, follow, now).await?;
+            Ok("flash-follow-denied")
+        }
+    };
+    let flash_id = answer.await.map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("follow request answered", None);
+    flash::set(&resp, &Message::new(flash_id).localize(&lang));
+    Ok(resp)
+}
+
+/// Tells the user's remote followers that their account is being deleted.
+/// Call this before their followers are removed.
+pub async fn announce_account_deletion(base: &Url, user_id: Uuid) -> Fallible<()> {
+    let Some(key) = instance_key()? else {
+        return Ok(());
+    };
+    let followers = followers::followers_of(user_id).await?;
+    deliver_account_deletion(&Fetch, &key, base, user_id, &followers, Utc::now()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::task::Poll;
+
+    const REMOTE_ACTOR: &str = "https://remote.example/users/bob";
+
+    /// Where something was POSTed, with its headers and body.
+    type Delivery = (Url, Vec<(String, String)>, String);
+
+    /// Stands in for the servers quiet federates with: serves fixed JSON
+    /// documents and records everything POSTed to it. POSTs take a moment,
+    /// so tests can see how many are sent at once.
+    #[derive(Default)]
+    struct StubServer {
+        documents: HashMap<Url, Value>,
+        delivered: RefCell<Vec<Delivery>>,
+        in_flight: Cell<usize>,
+        most_in_flight: Cell<usize>,
+    }
+
+    impl Transport for StubServer {
+        fn get_json(&self, url: &Url) -> LocalBoxFuture<'_, Fallible<Value>> {
+            let doc = self
+                .documents
+                .get(url)
+                .cloned()
+                .ok_or_else(|| remote_error(url, "HTTP 404"));
+            async move { doc }.boxed_local()
+        }
+
+        fn post(
+            &self,
+            url: &Url,
+            headers: Vec<(String, String)>,
+            body: String,
+        ) -> LocalBoxFuture<'_, Fallible<()>> {
+            self.delivered
+                .borrow_mut()
+                .push((url.clone(), headers, body));
+            self.in_flight.set(self.in_flight.get() + 1);
+            self.most_in_flight
+                .set(self.most_in_flight.get().max(self.in_flight.get()));
+            let mut waited = false;
+            future::poll_fn(move |cx| {
+                if waited {
+                    self.in_flight.set(self.in_flight.get() - 1);
+                    return Poll::Ready(Ok(()));
+                }
+                waited = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+            .boxed_local()
+        }
+    }
+
+    fn instance_key() -> SigningKey {
+        SigningKey::from_pem(
+            include_str!("activitypub/testdata/instance_key.pem"),
+            include_str!("activitypub/testdata/instance_key.pub.pem"),
+        )
+        .unwrap()
+    }
+
+    fn remote_key() -> SigningKey {
+        SigningKey::from_pem(
+            include_str!("activitypub/testdata/remote_key.pem"),
+            include_str!("activitypub/testdata/remote_key.pub.pem"),
+        )
+        .unwrap()
+    }
+
+    fn stub_with_remote_actor() -> StubServer {
+        let mut stub = StubServer::default();
+        stub.documents.insert(
+            Url::parse(REMOTE_ACTOR).unwrap(),
+            json!({
+                "id": REMOTE_ACTOR,
+                "type": "Person",
+                "inbox": format!("{}/inbox", REMOTE_ACTOR),
+                "endpoints": { "sharedInbox": "https://remote.example/inbox" },
+                "publicKey": {
+                    "id": format!("{}#main-key", REMOTE_ACTOR),
+                    "owner": REMOTE_ACTOR,
+                    "publicKeyPem": remote_key().public_pem,
+                },
+            }),
+        );
+        stub
+    }
+
+    fn header_lookup(headers: Vec<(String, String)>) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        }
+    }
+
+    /// Sends `activity` to a quiet user's inbox, signed by `key` as the remote actor.
+    fn send(
+        stub: &StubServer,
+        key: &SigningKey,
+        user_id: Uuid,
+        activity: Value,
+    ) -> Fallible<Incoming> {
+        let inbox = Url::parse(&format!("https://quiet.example/user/{}/inbox", user_id)).unwrap();
+        let body = activity.to_string();
+        let now = Utc::now();
+        let key_id = format!("{}#main-key", REMOTE_ACTOR);
+        let headers = key
+            .sign_post(&key_id, &inbox, body.as_bytes(), now)
+            .unwrap();
+        block_on(receive(
+            stub,
+            user_id,
+            &inbox,
+            header_lookup(headers),
+            &body,
+            now,
+        ))
+    }
+
+    fn follow(user_id: Uuid) -> Value {
+        json!({
+            "id": format!("{}#follows/1", REMOTE_ACTOR),
+            "type": "Follow",
+            "actor": REMOTE_ACTOR,
+            "object": format!("https://quiet.example/user/{}", user_id),
+        })
+    }
+
+    #[test]
+    fn signed_follow_and_undo_are_accepted() {
+        let stub = stub_with_remote_actor();
+        let user_id = Uuid::new_v4();
+        match send(&stub, &remote_key(), user_id, follow(user_id)) {
+            Ok(Incoming::Follow { follower, .. }) => {
+                assert_eq!(follower.actor.as_str(), REMOTE_ACTOR);
+                assert_eq!(follower.inbox.as_str(), "https://remote.example/inbox");
+            }
+            _ => panic!("expected a follow"),
+        }
+        let undo = json!({
+            "type": "Undo",
+            "actor": REMOTE_ACTOR,
+            "object": follow(user_id),
+        });
+        match send(&stub, &remote_key(), user_id, undo) {
+            Ok(Incoming::Unfollow { actor }) => assert_eq!(actor.as_str(), REMOTE_ACTOR),
+            _ => panic!("expected an unfollow"),
+        }
+    }
+
+    #[test]
+    fn forged_follows_are_rejected() {
+        let stub = stub_with_remote_actor();
+        let user_id = Uuid::new_v4();
+
+        // Signed with a key that isn't the actor's.
+        let forged = send(&stub, &instance_key(), user_id, follow(user_id));
+        assert_eq!(forged.err().unwrap().status, StatusCode::UNAUTHORIZED);
+
+        // Signed by the remote actor, but claiming to be someone else.
+        let mut impersonation = follow(user_id);
+        impersonation["actor"] = json!("https://remote.example/users/carol");
+        let impersonated = send(&stub, &remote_key(), user_id, impersonation);
+        assert_eq!(impersonated.err().unwrap().status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn posts_are_delivered_signed_to_each_inbox_once() {
+        let stub = stub_with_remote_actor();
+        let key = instance_key();
+        let base = Url::parse("https://quiet.example/").unwrap();
+        let shared_inbox = Url::parse("https://remote.example/inbox").unwrap();
+        let followers = vec![
+            Follower {
+                actor: Url::parse(REMOTE_ACTOR).unwrap(),
+                inbox: shared_inbox.clone(),
+            },
+            Follower {
+                actor: Url::parse("https://remote.example/users/carol").unwrap(),
+                inbox: shared_inbox.clone(),
+            },
+        ];
+        let post = posts::Post::fixture("hello fediverse <3");
+        let now = Utc::now();
+        block_on(deliver_post(
+            &stub,
+            &key,
+            &base,
+            &post,
+            Visibility::Public,
+            &followers,
+            now,
+        ))
+        .unwrap();
+
+        let delivered = stub.delivered.borrow();
+        assert_eq!(delivered.len(), 1);
+        let (inbox, headers, body) = &delivered[0];
+        assert_eq!(inbox, &shared_inbox);
+        let activity: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(activity["type"], "Create");
+        assert_eq!(activity["object"]["type"], "Note");
+        assert_eq!(
+            activity["object"]["content"],
+            "<p>hello fediverse &lt;3</p>"
+        );
+
+        let lookup = header_lookup(headers.clone());
+        let sig = Signature::parse(&lookup("signature").unwrap()).unwrap();
+        assert_eq!(
+            sig.key_id,
+            format!("https://quiet.example/user/{}#main-key", post.user_id)
+        );
+        sig.verify_post(inbox, lookup, body.as_bytes(), &key.public_pem, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn posts_are_addressed_by_visibility() {
+        let base = Url::parse("https://quiet.example/").unwrap();
+        let post = posts::Post::fixture("just for friends");
+        let followers = format!("https://quiet.example/user/{}/followers", post.user_id);
+
+        let public = create_activity(&base, &post, Visibility::Public);
+        assert_eq!(public["to"], json!([PUBLIC]));
+        assert_eq!(public["object"]["cc"], json!([followers]));
+
+        let unlisted = create_activity(&base, &post, Visibility::Unlisted);
+        assert_eq!(unlisted["to"], json!([followers]));
+        assert_eq!(unlisted["cc"], json!([PUBLIC]));
+
+        let private = create_activity(&base, &post, Visibility::FollowersOnly);
+        assert_eq!(private["to"], json!([followers]));
+        assert_eq!(private["object"]["to"], json!([followers]));
+        assert_eq!(private["object"]["cc"], json!([]));
+    }
+
+    #[test]
+    fn account_deletion_reaches_every_follower() {
+        let stub = stub_with_remote_actor();
+        let key = instance_key();
+        let base = Url::parse("https://quiet.example/").unwrap();
+        let user_id = Uuid::new_v4();
+        let followers = vec![
+            Follower {
+                actor: Url::parse(REMOTE_ACTOR).unwrap(),
+                inbox: Url::parse("https://remote.example/inbox").unwrap(),
+            },
+            Follower {
+                actor: Url::parse("https://other.example/users/dave").unwrap(),
+                inbox: Url::parse("https://other.example/users/dave/inbox").unwrap(),
+            },
+        ];
+        block_on(deliver_account_deletion(
+            &stub,
+            &key,
+            &base,
+            user_id,
+            &followers,
+            Utc::now(),
+        ))
+        .unwrap();
+
+        let delivered = stub.delivered.borrow();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(stub.most_in_flight.get(), 2, "inboxes are sent to at once");
+        for (_, _, body) in delivered.iter() {
+            let activity: Value = serde_json::from_str(body).unwrap();
+            assert_eq!(activity["type"], "Delete");
+            assert_eq!(
+                activity["object"],
+                format!("https://quiet.example/user/{}", user_id)
+            );
+        }
+    }
+}
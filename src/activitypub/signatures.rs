@@ -0,0 +1,238 @@
+//! HTTP Signatures (draft-cavage-http-signatures), which is how ActivityPub
+//! servers prove who sent a request.
+use crate::utils::http_date;
+use chrono::{offset::Utc, DateTime};
+use rsa::{Hash, PaddingScheme, PublicKey, RSAPrivateKey, RSAPublicKey};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use url::Url;
+
+/// Requests signed further than this from our clock are rejected, so a
+/// captured request can't be replayed forever.
+const MAX_CLOCK_SKEW_HOURS: i64 = 12;
+
+/// Which headers we sign on outgoing requests.
+const SIGNED_HEADERS: &str = "(request-target) host date digest";
+
+/// The key this instance signs its outgoing requests with.
+pub struct SigningKey {
+    key: RSAPrivateKey,
+    /// Public key, PEM-encoded, as it's published in actor documents.
+    pub public_pem: String,
+}
+
+impl SigningKey {
+    pub fn from_pem(private_pem: &str, public_pem: &str) -> Result<Self, String> {
+        let pem = rsa::pem::parse(private_pem).map_err(|e| format!("invalid key PEM: {}", e))?;
+        let key =
+            RSAPrivateKey::try_from(pem).map_err(|e| format!("invalid private key: {}", e))?;
+        parse_public_key(public_pem)?;
+        Ok(Self {
+            key,
+            public_pem: public_pem.to_owned(),
+        })
+    }
+
+    /// Headers that sign a POST of `body` to `url`, to be sent along with it.
+    pub fn sign_post(
+        &self,
+        key_id: &str,
+        url: &Url,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, String)>, String> {
+        let host = url
+            .host_str()
+            .ok_or("can't sign a request to a URL without a host")?;
+        let headers = vec![
+            ("host".to_owned(), host.to_owned()),
+            ("date".to_owned(), http_date(now)),
+            ("digest".to_owned(), digest(body)),
+        ];
+        let target = request_target("post", url);
+        let lookup = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let names: Vec<_> = SIGNED_HEADERS.split(' ').collect();
+        let signing_string = signing_string(&target, &names, lookup)?;
+        let hashed = Sha256::digest(signing_string.as_bytes());
+        let signature = self
+            .key
+            .sign(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                &hashed,
+            )
+            .map_err(|e| format!("couldn't sign request: {}", e))?;
+        let signature_header = format!(
+            "keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+            key_id,
+            SIGNED_HEADERS,
+            base64::encode(&signature)
+        );
+        let mut headers = headers;
+        headers.push(("signature".to_owned(), signature_header));
+        Ok(headers)
+    }
+}
+
+/// A parsed `Signature` header.
+pub struct Signature {
+    /// URL of the key that made the signature. For ActivityPub this is a
+    /// fragment of the signer's actor document.
+    pub key_id: String,
+    headers: Vec<String>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    pub fn parse(header: &str) -> Result<Self, String> {
+        let mut key_id = None;
+        let mut headers = None;
+        let mut signature = None;
+        for param in header.split(',') {
+            let mut kv = param.trim().splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let val = kv.next().unwrap_or("").trim_matches('"');
+            match key {
+                "keyId" => key_id = Some(val.to_owned()),
+                "headers" => headers = Some(val.split(' ').map(str::to_owned).collect()),
+                "signature" => {
+                    signature =
+                        Some(base64::decode(val).map_err(|_| "signature isn't valid base64")?)
+                }
+                "algorithm" if val != "rsa-sha256" && val != "hs2019" => {
+                    return Err(format!("unsupported signature algorithm {}", val))
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            key_id: key_id.ok_or("signature has no keyId")?,
+            // The spec says a missing `headers` means only `date` was signed.
+            headers: headers.unwrap_or_else(|| vec!["date".to_owned()]),
+            signature: signature.ok_or("signature has no signature")?,
+        })
+    }
+
+    /// Checks this signature over a POST request, using the signer's PEM-encoded
+    /// public key. `header` looks up the request's headers by lowercase name.
+    pub fn verify_post<F>(
+        &self,
+        url: &Url,
+        header: F,
+        body: &[u8],
+        public_pem: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        for required in &["(request-target)", "host", "date", "digest"] {
+            if !self.headers.iter().any(|h| h == required) {
+                return Err(format!("signature must cover {}", required));
+            }
+        }
+        if header("digest").as_deref() != Some(&digest(body)) {
+            return Err("digest header doesn't match the body".to_owned());
+        }
+        let date = header("date").ok_or("request has no date header")?;
+        let date = DateTime::parse_from_rfc2822(&date).map_err(|_| "invalid date header")?;
+        if (now - date.with_timezone(&Utc)).num_hours().abs() > MAX_CLOCK_SKEW_HOURS {
+            return Err("request was signed too long ago".to_owned());
+        }
+
+        let names: Vec<_> = self.headers.iter().map(String::as_str).collect();
+        let signing_string = signing_string(&request_target("post", url), &names, header)?;
+        let hashed = Sha256::digest(signing_string.as_bytes());
+        parse_public_key(public_pem)?
+            .verify(
+                PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+                &hashed,
+                &self.signature,
+            )
+            .map_err(|_| "signature doesn't match".to_owned())
+    }
+}
+
+fn parse_public_key(pem: &str) -> Result<RSAPublicKey, String> {
+    let pem = rsa::pem::parse(pem).map_err(|e| format!("invalid key PEM: {}", e))?;
+    RSAPublicKey::try_from(pem).map_err(|e| format!("invalid public key: {}", e))
+}
+
+fn request_target(method: &str, url: &Url) -> String {
+    match url.query() {
+        Some(q) => format!("{} {}?{}", method, url.path(), q),
+        None => format!("{} {}", method, url.path()),
+    }
+}
+
+fn signing_string<F>(target: &str, names: &[&str], header: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let lines: Result<Vec<_>, String> = names
+        .iter()
+        .map(|name| {
+            let val = match *name {
+                "(request-target)" => target.to_owned(),
+                name => header(name).ok_or_else(|| format!("signed header {} is missing", name))?,
+            };
+            Ok(format!("{}: {}", name, val))
+        })
+        .collect();
+    Ok(lines?.join("\n"))
+}
+
+/// Value of the `Digest` header for a request body.
+pub fn digest(body: &[u8]) -> String {
+    format!("SHA-256={}", base64::encode(Sha256::digest(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn instance_key() -> SigningKey {
+        SigningKey::from_pem(
+            include_str!("testdata/instance_key.pem"),
+            include_str!("testdata/instance_key.pub.pem"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn signed_post_verifies() {
+        let key = instance_key();
+        let url = Url::parse("https://remote.example/users/bob/inbox").unwrap();
+        let now = Utc::now();
+        let body = br#"{"type":"Create"}"#;
+        let headers = key
+            .sign_post("https://quiet.example/user/1#main-key", &url, body, now)
+            .unwrap();
+        let lookup = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+        };
+        let sig = Signature::parse(&lookup("signature").unwrap()).unwrap();
+        assert_eq!(sig.key_id, "https://quiet.example/user/1#main-key");
+        sig.verify_post(&url, lookup, body, &key.public_pem, now)
+            .unwrap();
+
+        // Tampering with the body, or replaying much later, must be caught.
+        assert!(sig
+            .verify_post(&url, lookup, b"{}", &key.public_pem, now)
+            .is_err());
+        let later = now + Duration::hours(MAX_CLOCK_SKEW_HOURS + 1);
+        assert!(sig
+            .verify_post(&url, lookup, body, &key.public_pem, later)
+            .is_err());
+        let other_key = include_str!("testdata/remote_key.pub.pem");
+        assert!(sig.verify_post(&url, lookup, body, other_key, now).is_err());
+    }
+}
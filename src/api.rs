@@ -0,0 +1,242 @@
+//! Versioned JSON API under `/api/v1`, for clients that aren't browsers.
+//! It's read-only, apart from signing up: posting and everything else that
+//! changes things needs a browser session and its CSRF token, which other
+//! clients don't have.
+//! Responses use the view types below rather than the stored models, so
+//! changing what's stored can't accidentally change (or leak through) the API.
+use crate::i18n::{self, Message};
+use crate::models::blocks::{self, Blocks};
+use crate::models::{followers, posts, users};
+use crate::twoface::*;
+use chrono::{offset::Utc, DateTime};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use web_sys::{Headers, Request, Response, ResponseInit};
+
+mod openapi;
+
+/// A post, as API clients see it.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct PostView {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub text: String,
+    pub link: Option<Url>,
+    pub created: DateTime<Utc>,
+    pub visibility: posts::Visibility,
+}
+
+impl From<posts::Post> for PostView {
+    fn from(post: posts::Post) -> Self {
+        Self {
+            id: post.id,
+            user_id: post.user_id,
+            text: post.text,
+            link: post.link,
+            created: post.created,
+            visibility: post.visibility,
+        }
+    }
+}
+
+/// A profile, as anyone can see it. Never includes the user's email address.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct ProfileView {
+    pub id: Uuid,
+    pub username: String,
+    pub pic: Url,
+    pub bio: String,
+    pub date_joined: DateTime<Utc>,
+}
+
+impl From<users::Profile> for ProfileView {
+    fn from(profile: users::Profile) -> Self {
+        Self {
+            id: profile.id,
+            username: profile.username,
+            pic: profile.pic,
+            bio: profile.bio,
+            date_joined: profile.date_joined,
+        }
+    }
+}
+
+/// Someone following a user.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct FollowerView {
+    /// Their ActivityPub actor ID.
+    pub actor: Url,
+}
+
+impl From<followers::Follower> for FollowerView {
+    fn from(follower: followers::Follower) -> Self {
+        Self {
+            actor: follower.actor,
+        }
+    }
+}
+
+/// One page of a user's posts, newest first.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct PageView {
+    pub posts: Vec<PostView>,
+    /// Pass this as `?before=` to get the next page of older posts.
+    pub older: Option<String>,
+}
+
+impl From<posts::Page> for PageView {
+    fn from(page: posts::Page) -> Self {
+        Self {
+            posts: page.posts.into_iter().map(PostView::from).collect(),
+            older: page.older.map(posts::Cursor::encode),
+        }
+    }
+}
+
+pub async fn handle(req: Request, path: String, method: String) -> Result<Response, Response> {
+    let lang = i18n::request_language(&req, None);
+    route(req, &path, &method)
+        .await
+        .map_err(|e| e.localized_response(&lang))
+}
+
+async fn route(req: Request, path: &str, method: &str) -> Fallible<Response> {
+    // The path starts with "/api", so skip the empty segment and "api".
+    let segments: Vec<_> = path.trim_end_matches('/').split('/').skip(2).collect();
+    match (method, segments.as_slice()) {
+        ("get", ["openapi.json"]) => json_body_response(StatusCode::OK, openapi::SPEC),
+        ("get", ["v1", "feed"]) => {
+            let viewer = users::signed_in(&req).await?;
+            user_posts(&req, viewer.id, true).await
+        }
+        ("post", ["v1", "users"]) => {
+            let profile = users::create_profile(req).await?;
+            json_response(StatusCode::CREATED, &ProfileView::from(profile))
+        }
+        ("get", ["v1", "users", id]) => {
+            let user_id = parse_id(id)?;
+            check_not_blocked(&req, user_id).await?;
+            let Some(profile) = users::profile_by_id(user_id).await? else {
+                return Err(not_found(path));
+            };
+            json_response(StatusCode::OK, &ProfileView::from(profile))
+        }
+        ("get", ["v1", "users", id, "followers"]) => {
+            let user_id = parse_id(id)?;
+            let (viewer, _) = check_not_blocked(&req, user_id).await?;
+            let Some(profile) = users::profile_by_id(user_id).await? else {
+                return Err(not_found(path));
+            };
+            // Like their posts, who follows a private user is only for them.
+            if profile.is_private && viewer != Some(user_id) {
+                return Err(Error {
+                    internal: format!("{} is private", user_id),
+                    external: Message::new("error-private-account"),
+                    status: StatusCode::FORBIDDEN,
+                });
+            }
+            let followers: Vec<FollowerView> = followers::followers_of(user_id)
+                .await?
+                .into_iter()
+                .map(FollowerView::from)
+                .collect();
+            json_response(StatusCode::OK, &followers)
+        }
+        ("get", ["v1", "users", id, "posts"]) => user_posts(&req, parse_id(id)?, false).await,
+        ("get", ["v1", "users", id, "posts", post_id]) => {
+            let user_id = parse_id(id)?;
+            let (viewer, _) = check_not_blocked(&req, user_id).await?;
+            let post = posts::post_by_user(user_id, viewer, parse_id(post_id)?).await?;
+            let Some(post) = post else {
+                return Err(not_found(path));
+            };
+            json_response(StatusCode::OK, &PostView::from(post))
+        }
+        _ => Err(not_found(path)),
+    }
+}
+
+/// A page of the user's posts, or of their home feed, which also has the
+/// posts of everyone they follow, leaving out anyone they've muted or
+/// blocked.
+async fn user_posts(req: &Request, user_id: Uuid, feed: bool) -> Fallible<Response> {
+    let (viewer, viewer_blocks) = check_not_blocked(req, user_id).await?;
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        status: StatusCode::BAD_REQUEST,
+        external: Message::new("error-invalid-url"),
+    })?;
+    let query = posts::PageQuery::from_url(&url).map_err(|e| Error {
+        internal: e.to_string(),
+        external: e,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    let page = if feed {
+        let following = followers::following_of(user_id).await?;
+        posts::page_of_home_feed(user_id, &following, &viewer_blocks, &query).await?
+    } else {
+        posts::page_of_posts_by_user(user_id, viewer, &query).await?
+    };
+    json_response(StatusCode::OK, &PageView::from(page))
+}
+
+/// Refuses to show the user's profile or posts to a viewer either of them
+/// has blocked. Returns the viewer's ID, if they have a profile, and who
+/// they've blocked and muted.
+async fn check_not_blocked(req: &Request, user_id: Uuid) -> Fallible<(Option<Uuid>, Blocks)> {
+    let Some(viewer) = users::viewer(req).await? else {
+        return Ok((None, Blocks::default()));
+    };
+    if blocks::either_blocks(viewer.id, user_id).await? {
+        return Err(blocks::blocked_error(viewer.id, user_id));
+    }
+    Ok((Some(viewer.id), blocks::blocks_of(viewer.id).await?))
+}
+
+fn parse_id(id: &str) -> Fallible<Uuid> {
+    Uuid::parse_str(id).map_err(|_| Error {
+        internal: format!("{} is not a UUID", id),
+        status: StatusCode::NOT_FOUND,
+        external: Message::new("error-invalid-id").arg("id", id.to_owned()),
+    })
+}
+
+fn not_found(path: &str) -> Error {
+    Error {
+        internal: format!("no API resource at {}", path),
+        status: StatusCode::NOT_FOUND,
+        external: Message::new("error-not-found"),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, val: &T) -> Fallible<Response> {
+    let body = serde_json::to_string(val).map_err(|e| response_error(e.to_string()))?;
+    json_body_response(status, &body)
+}
+
+fn json_body_response(status: StatusCode, body: &str) -> Fallible<Response> {
+    let build = || -> Result<Response, JsValue> {
+        let headers = Headers::new()?;
+        headers.append("content-type", "application/json")?;
+        let init = ResponseInit::new();
+        init.set_status(status.as_u16());
+        init.set_headers(&JsValue::from(headers));
+        Response::new_with_opt_str_and_init(Some(body), &init)
+    };
+    build().map_err(|e| response_error(format!("{:?}", e)))
+}
+
+fn response_error(internal: String) -> Error {
+    Error {
+        internal,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-build-response"),
+    }
+}
@@ -0,0 +1,143 @@
+//! OpenAPI 3 description of the JSON API. The worker serves the checked-in
+//! `openapi.json`; the test below regenerates it from the Rust types the API
+//! actually (de)serializes, and fails if the two have drifted apart.
+
+/// The spec served at `/api/openapi.json`.
+pub const SPEC: &str = include_str!("openapi.json");
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FollowerView, PageView, PostView, ProfileView};
+    use crate::models::users::NewProfile;
+    use crate::twoface::External;
+    use schemars::gen::SchemaSettings;
+    use serde_json::{json, Value};
+
+    fn generate() -> Value {
+        let mut gen = SchemaSettings::openapi3().into_generator();
+        let new_profile = gen.subschema_for::<NewProfile>();
+        let post = gen.subschema_for::<PostView>();
+        let profile = gen.subschema_for::<ProfileView>();
+        let page = gen.subschema_for::<PageView>();
+        let followers = gen.subschema_for::<Vec<FollowerView>>();
+        let error = gen.subschema_for::<External>();
+
+        let body = |schema| json!({ "content": { "application/json": { "schema": schema } } });
+        let response = |description: &str, schema| {
+            let mut resp = body(schema);
+            resp["description"] = json!(description);
+            resp
+        };
+        let id_param = |name: &str| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string", "format": "uuid" }
+            })
+        };
+        let page_params = json!([
+            {
+                "name": "before",
+                "in": "query",
+                "description": "The `older` cursor from the previous page",
+                "schema": { "type": "string" }
+            },
+            {
+                "name": "limit",
+                "in": "query",
+                "description": "How many posts to return",
+                "schema": { "type": "integer", "minimum": 1, "maximum": 100 }
+            }
+        ]);
+        let error_response = response("Error", json!(error));
+
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "quiet",
+                "version": "v1"
+            },
+            "paths": {
+                "/api/v1/feed": {
+                    "get": {
+                        "summary": "The signed-in user's home feed, newest first",
+                        "parameters": page_params,
+                        "responses": {
+                            "200": response("A page of posts", json!(page)),
+                            "default": error_response
+                        }
+                    }
+                },
+                "/api/v1/users": {
+                    "post": {
+                        "summary": "Create a profile, if the server allows sign-ups",
+                        "requestBody": body(json!(new_profile)),
+                        "responses": {
+                            "201": response("The new profile", json!(profile)),
+                            "default": error_response
+                        }
+                    }
+                },
+                "/api/v1/users/{id}": {
+                    "get": {
+                        "summary": "A user's public profile",
+                        "parameters": [id_param("id")],
+                        "responses": {
+                            "200": response("The profile", json!(profile)),
+                            "default": error_response
+                        }
+                    }
+                },
+                "/api/v1/users/{id}/followers": {
+                    "get": {
+                        "summary": "Who follows a user",
+                        "parameters": [id_param("id")],
+                        "responses": {
+                            "200": response("The user's followers", json!(followers)),
+                            "default": error_response
+                        }
+                    }
+                },
+                "/api/v1/users/{id}/posts": {
+                    "get": {
+                        "summary": "A user's posts, newest first",
+                        "parameters": [id_param("id"), page_params[0], page_params[1]],
+                        "responses": {
+                            "200": response("A page of posts", json!(page)),
+                            "default": error_response
+                        }
+                    }
+                },
+                "/api/v1/users/{id}/posts/{post_id}": {
+                    "get": {
+                        "summary": "One post",
+                        "parameters": [id_param("id"), id_param("post_id")],
+                        "responses": {
+                            "200": response("The post", json!(post)),
+                            "default": error_response
+                        }
+                    }
+                }
+            },
+            "components": {
+                "schemas": gen.definitions()
+            }
+        })
+    }
+
+    #[test]
+    fn served_spec_matches_api_types() {
+        let generated = serde_json::to_string_pretty(&generate()).unwrap() + "\n";
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/api/openapi.json");
+            std::fs::write(path, &generated).unwrap();
+            return;
+        }
+        assert!(
+            generated == super::SPEC,
+            "src/api/openapi.json is out of date with the API types. \
+             If the API change is intended, rerun with UPDATE_OPENAPI=1 and commit the result."
+        );
+    }
+}
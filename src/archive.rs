@@ -0,0 +1,186 @@
+//! A download of everything quiet knows about a user, from
+//! `GET /settings/export`. The archive has its own types rather than the
+//! stored models, so it keeps the same shape however storage changes, and
+//! can be read back in later.
+use crate::i18n::{self, Message};
+use crate::models::{followers, posts, users};
+use crate::sessions;
+use crate::twoface::*;
+use chrono::{offset::Utc, DateTime};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use web_sys::{Headers, Request, Response, ResponseInit};
+
+/// Bumped whenever the archive's shape changes, so readers can tell which
+/// one they have.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    pub profile: ArchivedProfile,
+    /// Oldest first.
+    pub posts: Vec<ArchivedPost>,
+    /// ActivityPub actor IDs of the user's followers on other servers.
+    #[serde(default)]
+    pub followers: Vec<Url>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedProfile {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub pic: Url,
+    pub bio: String,
+    pub date_joined: DateTime<Utc>,
+    pub language: Option<String>,
+    pub timezone: String,
+    pub locale: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedPost {
+    pub id: Uuid,
+    pub text: String,
+    pub link: Option<Url>,
+    pub created: DateTime<Utc>,
+    /// Left out for public posts, as archives from before posts had a
+    /// visibility were all public.
+    #[serde(default, skip_serializing_if = "posts::Visibility::is_public")]
+    pub visibility: posts::Visibility,
+}
+
+impl From<users::Profile> for ArchivedProfile {
+    fn from(profile: users::Profile) -> Self {
+        Self {
+            id: profile.id,
+            username: profile.username,
+            email: profile.email,
+            pic: profile.pic,
+            bio: profile.bio,
+            date_joined: profile.date_joined,
+            language: profile.language,
+            timezone: profile.timezone,
+            locale: profile.locale,
+        }
+    }
+}
+
+impl From<posts::Post> for ArchivedPost {
+    fn from(post: posts::Post) -> Self {
+        Self {
+            id: post.id,
+            text: post.text,
+            link: post.link,
+            created: post.created,
+            visibility: post.visibility,
+        }
+    }
+}
+
+impl Archive {
+    pub fn new(
+        profile: users::Profile,
+        posts: Vec<posts::Post>,
+        followers: Vec<followers::Follower>,
+        exported: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            version: ARCHIVE_VERSION,
+            exported,
+            profile: profile.into(),
+            posts: posts.into_iter().map(ArchivedPost::from).collect(),
+            followers: followers.into_iter().map(|f| f.actor).collect(),
+        }
+    }
+}
+
+/// `GET /settings/export`
+pub async fn render_export(req: Request) -> Result<Response, Response> {
+    let lang = i18n::request_language(&req, None);
+    let export = async {
+        let profile = users::signed_in(&req).await?;
+        // It has the user's email address in it, so only their own
+        // settings page can ask for it.
+        sessions::check_csrf(&req)?;
+        let posts = posts::all_posts_by_user(profile.id).await?;
+        let followers = followers::followers_of(profile.id).await?;
+        Ok::<_, Error>(Archive::new(profile, posts, followers, Utc::now()))
+    };
+    let archive = export.await.map_err(|e| e.localized_response(&lang))?;
+    let file_name = format!("quiet-{}.json", archive.exported.format("%Y-%m-%d"));
+    let body = serde_json::to_string_pretty(&archive).unwrap();
+    let build = || -> Result<Response, JsValue> {
+        let headers = Headers::new()?;
+        headers.append("content-type", "application/json; charset=utf-8")?;
+        headers.append(
+            "content-disposition",
+            &format!("attachment; filename=\"{}\"", file_name),
+        )?;
+        // It has the user's email address in it.
+        headers.append("cache-control", "private, no-store")?;
+        let init = ResponseInit::new();
+        init.set_status(StatusCode::OK.as_u16());
+        init.set_headers(&JsValue::from(headers));
+        Response::new_with_opt_str_and_init(Some(&body), &init)
+    };
+    build().map_err(|e| {
+        Error {
+            internal: format!("{:?}", e),
+            external: Message::new("error-build-response"),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        }
+        .into_response()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn archives_have_everything_and_read_back() {
+        let joined = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let profile = users::Profile {
+            username: "ada".to_owned(),
+            date_joined: joined,
+            id: Uuid::new_v4(),
+            pic: Url::parse("https://quiet.example/avatar/1/256").unwrap(),
+            email: "ada@example.com".to_owned(),
+            language: Some("es".to_owned()),
+            timezone: "Europe/Madrid".to_owned(),
+            locale: None,
+            bio: "hi".to_owned(),
+            role: users::Role::User,
+            suspended: false,
+            is_private: false,
+        };
+        let post = posts::Post {
+            user_id: profile.id,
+            created: joined,
+            ..posts::Post::fixture("first!")
+        };
+        let follower = followers::Follower {
+            actor: Url::parse("https://remote.example/users/bob").unwrap(),
+            inbox: Url::parse("https://remote.example/inbox").unwrap(),
+        };
+        let archive = Archive::new(profile, vec![post], vec![follower], joined);
+        let json = serde_json::to_string(&archive).unwrap();
+        let read: Archive = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.version, ARCHIVE_VERSION);
+        assert_eq!(read.profile.email, "ada@example.com");
+        assert_eq!(read.profile.timezone, "Europe/Madrid");
+        assert_eq!(read.posts.len(), 1);
+        assert_eq!(read.posts[0].text, "first!");
+        assert_eq!(
+            read.followers[0].as_str(),
+            "https://remote.example/users/bob"
+        );
+    }
+}
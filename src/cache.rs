@@ -0,0 +1,334 @@
+//! Rendered pages and feeds, kept so they needn't be rendered again until
+//! something on them changes. They're kept in the Workers Cache API, under
+//! a key with the version of every user whose data the page shows. Saving a
+//! user's posts or profile gives them a new version with `invalidate`, so
+//! from then on their pages are looked up under new keys and rendered again.
+//!
+//! Versions are kept in KV, so a change can take a minute to reach other
+//! Cloudflare locations, and until then they might serve the old page.
+use crate::console_logf;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime};
+use futures::future::{FutureExt, LocalBoxFuture};
+use js_sys::Promise;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Request, Response, ResponseInit};
+
+/// How long the cache keeps a page, in seconds. Pages are normally replaced
+/// long before this; it only matters if a version couldn't be changed.
+const MAX_AGE: u32 = 60 * 60;
+
+/// A rendered page, and what's needed to tell whether a reader has it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    pub body: String,
+    pub content_type: String,
+    pub etag: String,
+    /// When what the page shows last changed, if that's known.
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Page {
+    pub fn new(body: String, content_type: &str, last_modified: Option<DateTime<Utc>>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        Self {
+            etag: format!("\"{:x}\"", hasher.finish()),
+            body,
+            content_type: content_type.to_owned(),
+            last_modified,
+        }
+    }
+
+    /// Whether the reader already has this page, going by the
+    /// `If-None-Match` and `If-Modified-Since` headers they sent.
+    pub fn not_modified(
+        &self,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> bool {
+        if let Some(tags) = if_none_match {
+            // If-None-Match takes precedence over If-Modified-Since when both are sent.
+            return tags == "*"
+                || tags
+                    .split(',')
+                    .any(|t| t.trim().trim_start_matches("W/") == self.etag);
+        }
+        if let (Some(since), Some(modified)) = (if_modified_since, self.last_modified) {
+            if let Ok(since) = DateTime::parse_from_rfc2822(since) {
+                // HTTP dates have no sub-second part, so compare whole seconds.
+                return modified.timestamp() <= since.timestamp();
+            }
+        }
+        false
+    }
+
+    /// The page as a response to `req`: 304 Not Modified if they have it.
+    pub fn response(&self, req: &Request, cache_control: &str) -> Result<Response, JsValue> {
+        let headers = self.headers()?;
+        headers.append("cache-control", cache_control)?;
+        let req_headers = req.headers();
+        let not_modified = self.not_modified(
+            req_headers.get("if-none-match")?.as_deref(),
+            req_headers.get("if-modified-since")?.as_deref(),
+        );
+        let init = ResponseInit::new();
+        init.set_headers(&JsValue::from(&headers));
+        if not_modified {
+            init.set_status(http::StatusCode::NOT_MODIFIED.as_u16());
+            Response::new_with_opt_str_and_init(None, &init)
+        } else {
+            init.set_status(http::StatusCode::OK.as_u16());
+            Response::new_with_opt_str_and_init(Some(&self.body), &init)
+        }
+    }
+
+    fn headers(&self) -> Result<Headers, JsValue> {
+        let headers = Headers::new()?;
+        headers.append("content-type", &self.content_type)?;
+        headers.append("etag", &self.etag)?;
+        if let Some(modified) = self.last_modified {
+            headers.append("last-modified", &http_date(modified))?;
+        }
+        Ok(headers)
+    }
+}
+
+/// Somewhere rendered pages are kept.
+pub trait PageCache {
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Option<Page>, String>>;
+
+    fn put<'a>(&'a self, key: &'a str, page: &'a Page) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+/// The cache key for the page at `url`, which also depends on `vary`, like
+/// the reader's language, and shows data with `versions`.
+pub fn key(url: &Url, vary: &[(&str, &str)], versions: &[String]) -> String {
+    let mut key = url.clone();
+    key.set_fragment(None);
+    {
+        let mut query = key.query_pairs_mut();
+        for (name, value) in vary {
+            query.append_pair(name, value);
+        }
+        query.append_pair("versions", &versions.join("."));
+    }
+    key.to_string()
+}
+
+/// Gets the page under `key` from `cache`, or renders it with `render` and
+/// keeps it there. The cache failing doesn't stop the page being shown.
+pub async fn read_through<C, F>(cache: &C, key: &str, render: F) -> Fallible<Page>
+where
+    C: PageCache,
+    F: Future<Output = Fallible<Page>>,
+{
+    match cache.get(key).await {
+        Ok(Some(page)) => return Ok(page),
+        Ok(None) => {}
+        Err(e) => console_logf!("couldn't read {} from the cache: {}", key, e),
+    }
+    let page = render.await?;
+    if let Err(e) = cache.put(key, &page).await {
+        console_logf!("couldn't cache {}: {}", key, e);
+    }
+    Ok(page)
+}
+
+/// The page at `url`, which shows data of `users`, from the cache if it's
+/// there, otherwise rendered with `render`.
+pub async fn page<F>(url: &Url, vary: &[(&str, &str)], users: &[Uuid], render: F) -> Fallible<Page>
+where
+    F: Future<Output = Fallible<Page>>,
+{
+    let mut versions = Vec::with_capacity(users.len());
+    for user_id in users {
+        match version(*user_id).await {
+            Ok(v) => versions.push(v),
+            Err(e) => {
+                // Without every version the key might be stale.
+                console_logf!("couldn't get cache version of {}: {:?}", user_id, e);
+                return render.await;
+            }
+        }
+    }
+    read_through(&WorkersCache, &key(url, vary, &versions), render).await
+}
+
+/// Stops pages showing the user's data being served from the cache.
+/// Failing to is logged rather than returned, since it's done after the
+/// data is already saved.
+pub async fn invalidate(user_id: Uuid) {
+    let version = Uuid::new_v4().to_simple().to_string();
+    let put = CacheNs::put(&user_id.to_string(), &version);
+    if let Err(e) = JsFuture::from(put).await {
+        console_logf!("couldn't invalidate cached pages of {}: {:?}", user_id, e);
+    }
+}
+
+async fn version(user_id: Uuid) -> Result<String, JsValue> {
+    let val = JsFuture::from(CacheNs::get(&user_id.to_string(), "text")).await?;
+    // Users who haven't changed anything since caching began.
+    Ok(val.as_string().unwrap_or_else(|| "0".to_owned()))
+}
+
+/// Cloudflare's cache for the location the worker's running in.
+struct WorkersCache;
+
+impl WorkersCache {
+    fn cache() -> Result<web_sys::Cache, JsValue> {
+        let caches = js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str("caches"))?;
+        let cache = js_sys::Reflect::get(&caches, &JsValue::from_str("default"))?;
+        Ok(cache.unchecked_into())
+    }
+}
+
+impl PageCache for WorkersCache {
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Option<Page>, String>> {
+        async move {
+            let get = async {
+                let val = JsFuture::from(Self::cache()?.match_with_str(key)).await?;
+                if val.is_undefined() {
+                    return Ok(None);
+                }
+                let resp: Response = val.unchecked_into();
+                let headers = resp.headers();
+                let body = JsFuture::from(resp.text()?).await?;
+                let last_modified = headers
+                    .get("last-modified")?
+                    .and_then(|d| DateTime::parse_from_rfc2822(&d).ok())
+                    .map(|d| d.with_timezone(&Utc));
+                Ok(Some(Page {
+                    body: body.as_string().unwrap_or_default(),
+                    content_type: headers.get("content-type")?.unwrap_or_default(),
+                    etag: headers.get("etag")?.unwrap_or_default(),
+                    last_modified,
+                }))
+            };
+            get.await.map_err(|e: JsValue| format!("{:?}", e))
+        }
+        .boxed_local()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, page: &'a Page) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            let put = async {
+                let headers = page.headers()?;
+                headers.append("cache-control", &format!("max-age={}", MAX_AGE))?;
+                let init = ResponseInit::new();
+                init.set_status(http::StatusCode::OK.as_u16());
+                init.set_headers(&JsValue::from(&headers));
+                let resp = Response::new_with_opt_str_and_init(Some(&page.body), &init)?;
+                JsFuture::from(Self::cache()?.put_with_str(key, &resp)).await?;
+                Ok(())
+            };
+            put.await.map_err(|e: JsValue| format!("{:?}", e))
+        }
+        .boxed_local()
+    }
+}
+
+// Each user's cache version, under their ID.
+#[wasm_bindgen]
+extern "C" {
+    type CacheNs;
+
+    #[wasm_bindgen(static_method_of = CacheNs)]
+    fn get(key: &str, data_type: &str) -> Promise;
+
+    #[wasm_bindgen(static_method_of = CacheNs)]
+    fn put(key: &str, val: &str) -> Promise;
+}
+
+/// A cache in memory, standing in for the Workers one in tests.
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use futures::future::ready;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    pub struct MemoryCache {
+        pub pages: RefCell<HashMap<String, Page>>,
+    }
+
+    impl PageCache for MemoryCache {
+        fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Option<Page>, String>> {
+            ready(Ok(self.pages.borrow().get(key).cloned())).boxed_local()
+        }
+
+        fn put<'a>(
+            &'a self,
+            key: &'a str,
+            page: &'a Page,
+        ) -> LocalBoxFuture<'a, Result<(), String>> {
+            self.pages.borrow_mut().insert(key.to_owned(), page.clone());
+            ready(Ok(())).boxed_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryCache;
+    use super::*;
+    use chrono::TimeZone;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    #[test]
+    fn pages_are_only_rendered_until_theyre_cached() {
+        let cache = MemoryCache::default();
+        let renders = Cell::new(0);
+        let render = || async {
+            renders.set(renders.get() + 1);
+            Ok(Page::new("<p>hi</p>".to_owned(), "text/html", None))
+        };
+        block_on(async {
+            let first = read_through(&cache, "home", render()).await.unwrap();
+            let second = read_through(&cache, "home", render()).await.unwrap();
+            assert_eq!(first, second);
+            assert_eq!(renders.get(), 1);
+            read_through(&cache, "elsewhere", render()).await.unwrap();
+            assert_eq!(renders.get(), 2);
+        });
+    }
+
+    #[test]
+    fn new_versions_make_new_keys() {
+        let url = Url::parse("https://quiet.example/user/1?limit=5#top").unwrap();
+        let vary = [("lang", "es")];
+        let before = key(&url, &vary, &["0".to_owned(), "a1".to_owned()]);
+        assert_eq!(
+            before,
+            "https://quiet.example/user/1?limit=5&lang=es&versions=0.a1"
+        );
+        assert_ne!(before, key(&url, &vary, &["0".to_owned(), "b2".to_owned()]));
+        assert_ne!(
+            before,
+            key(&url, &[("lang", "en")], &["0".to_owned(), "a1".to_owned()])
+        );
+    }
+
+    #[test]
+    fn readers_with_the_page_are_told_so() {
+        let modified = Utc.ymd(2020, 9, 1).and_hms_milli(12, 0, 0, 500);
+        let page = Page::new("feed".to_owned(), "application/rss+xml", Some(modified));
+        assert!(page.not_modified(Some(&page.etag), None));
+        assert!(page.not_modified(Some(&format!("\"x\", W/{}", page.etag)), None));
+        assert!(!page.not_modified(Some("\"x\""), Some(&http_date(modified))));
+        assert!(page.not_modified(None, Some(&http_date(modified))));
+        let earlier = modified - chrono::Duration::seconds(1);
+        assert!(!page.not_modified(None, Some(&http_date(earlier))));
+        assert!(!page.not_modified(None, None));
+    }
+}
@@ -0,0 +1,153 @@
+//! Cookies signed with the `SESSION_SECRET` secret, so the browser can hold
+//! on to state for us without being able to change it.
+//!
+//! Everything is signed for a purpose, like "session" or "flash", and only
+//! verifies for that purpose, so a value signed for one thing can't be
+//! passed off as another.
+use crate::utils::*;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use web_sys::Request;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn secret() -> Option<String> {
+    global_var("SESSION_SECRET")
+}
+
+fn mac(secret: &str, purpose: &str) -> Option<HmacSha256> {
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).ok()?;
+    mac.update(purpose.as_bytes());
+    mac.update(b"\0");
+    Some(mac)
+}
+
+/// Signs `value` for `purpose`, so that `verify` can check it hasn't been
+/// changed. Returns None if there's no secret to sign with.
+pub fn sign(purpose: &str, value: &str) -> Option<String> {
+    sign_with(&secret()?, purpose, value)
+}
+
+/// `sign`, with `secret` instead of the worker's.
+pub(crate) fn sign_with(secret: &str, purpose: &str, value: &str) -> Option<String> {
+    let mut mac = mac(secret, purpose)?;
+    mac.update(value.as_bytes());
+    let tag = mac.finalize().into_bytes();
+    Some(format!(
+        "{}.{}",
+        base64::encode_config(value, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// The value `sign` was called with, if `signed` really came from it and
+/// was signed for `purpose`.
+pub fn verify(purpose: &str, signed: &str) -> Option<String> {
+    verify_with(&secret()?, purpose, signed)
+}
+
+/// `verify`, with `secret` instead of the worker's.
+pub(crate) fn verify_with(secret: &str, purpose: &str, signed: &str) -> Option<String> {
+    let mut parts = signed.splitn(2, '.');
+    let value = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let tag = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let mut mac = mac(secret, purpose)?;
+    mac.update(&value);
+    mac.verify(&tag).ok()?;
+    String::from_utf8(value).ok()
+}
+
+/// Reads a cookie from the request, without checking any signature.
+pub fn get(req: &Request, name: &str) -> Option<String> {
+    let header = req.headers().get("cookie").ok()??;
+    header.split(';').find_map(|cookie| {
+        let mut kv = cookie.trim().splitn(2, '=');
+        if kv.next()? == name {
+            kv.next().map(str::to_owned)
+        } else {
+            None
+        }
+    })
+}
+
+/// A `Set-Cookie` header value for a cookie that lasts `max_age_secs`.
+pub fn set_cookie(name: &str, value: &str, max_age_secs: u32) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+        name, value, max_age_secs
+    )
+}
+
+/// A `Set-Cookie` header value that deletes the cookie.
+pub fn clear_cookie(name: &str) -> String {
+    set_cookie(name, "", 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    #[test]
+    fn signed_values_verify() {
+        for (purpose, value) in &[
+            ("quiet_flash", ""),
+            ("quiet_session", "{\"user_id\":1}"),
+            ("csrf", "dots.in.the.value"),
+            ("sign-in", "¿qué tal? 🙂"),
+        ] {
+            let signed = sign_with(SECRET, purpose, value).unwrap();
+            assert_eq!(
+                verify_with(SECRET, purpose, &signed).as_deref(),
+                Some(*value)
+            );
+        }
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let signed = sign_with(SECRET, "quiet_session", "alice").unwrap();
+        let (value, tag) = signed.split_once('.').unwrap();
+        let other = sign_with(SECRET, "quiet_session", "mallory").unwrap();
+        let (other_value, other_tag) = other.split_once('.').unwrap();
+        let flip = |s: &str| {
+            let mut s = s.to_owned();
+            let first = if s.starts_with('A') { "B" } else { "A" };
+            s.replace_range(..1, first);
+            s
+        };
+        for tampered in &[
+            format!("{}.{}", other_value, tag),
+            format!("{}.{}", value, other_tag),
+            format!("{}.{}", flip(value), tag),
+            format!("{}.{}", value, flip(tag)),
+            format!("{}.", value),
+            value.to_owned(),
+            "not base64.at all".to_owned(),
+            String::new(),
+        ] {
+            assert_eq!(
+                verify_with(SECRET, "quiet_session", tampered),
+                None,
+                "{}",
+                tampered
+            );
+        }
+        // It's genuine, but only for what it was signed for, and with the
+        // secret it was signed with.
+        assert_eq!(verify_with(SECRET, "csrf", &signed), None);
+        assert_eq!(
+            verify_with("another secret", "quiet_session", &signed),
+            None
+        );
+    }
+
+    #[test]
+    fn cleared_cookies_expire_at_once() {
+        assert_eq!(
+            clear_cookie("quiet_flash"),
+            "quiet_flash=; Max-Age=0; Path=/; HttpOnly; Secure; SameSite=Lax"
+        );
+    }
+}
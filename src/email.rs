@@ -0,0 +1,139 @@
+//! Email we send people, and the signed links in it. Mail goes out through
+//! MailChannels, from the address in the `MAIL_FROM` variable. Without it,
+//! mail is logged instead of sent, which is handy in development.
+use crate::activitypub::{Fetch, Transport};
+use crate::console_logf;
+use crate::cookies;
+use crate::i18n::Message;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime, Duration};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+const MAILCHANNELS_URL: &str = "https://api.mailchannels.net/tx/v1/send";
+
+/// Links to confirm an email address stop working after this long.
+const VERIFY_FOR_HOURS: i64 = 24;
+
+/// Links to sign in stop working after this long.
+const SIGN_IN_FOR_MINUTES: i64 = 30;
+
+const VERIFY_PURPOSE: &str = "verify-email";
+const SIGN_IN_PURPOSE: &str = "sign-in";
+
+/// What a verification link proves: whoever opened it can read `email`.
+#[derive(Serialize, Deserialize)]
+struct Verification {
+    user_id: Uuid,
+    email: String,
+    expires: i64,
+}
+
+/// A link that confirms `email` belongs to the user. `base` is any URL on
+/// this server.
+pub fn verification_link(base: &Url, user_id: Uuid, email: &str) -> Fallible<Url> {
+    let verification = Verification {
+        user_id,
+        email: email.to_owned(),
+        expires: (Utc::now() + Duration::hours(VERIFY_FOR_HOURS)).timestamp(),
+    };
+    let json = serde_json::to_string(&verification).unwrap();
+    signed_link(base, "/settings/verify-email", VERIFY_PURPOSE, &json).ok_or_else(|| Error {
+        internal: "SESSION_SECRET isn't set, so links can't be signed".to_owned(),
+        external: Message::new("error-no-email-verification"),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+/// The user and email address a verification link's token confirms, if
+/// the token is genuine and hasn't expired.
+pub fn verified_email(token: &str, now: DateTime<Utc>) -> Fallible<(Uuid, String)> {
+    let verification: Option<Verification> = cookies::verify(VERIFY_PURPOSE, token)
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .filter(|v: &Verification| v.expires >= now.timestamp());
+    let Some(verification) = verification else {
+        return Err(Error {
+            internal: "invalid or expired verification token".to_owned(),
+            external: Message::new("error-invalid-verification"),
+            status: StatusCode::BAD_REQUEST,
+        });
+    };
+    Ok((verification.user_id, verification.email))
+}
+
+/// What a sign-in link proves: whoever opened it can read the user's email.
+#[derive(Serialize, Deserialize)]
+struct SignIn {
+    user_id: Uuid,
+    expires: i64,
+}
+
+/// A link that signs in as the user, to send to their email address.
+pub fn sign_in_link(base: &Url, user_id: Uuid) -> Fallible<Url> {
+    let sign_in = SignIn {
+        user_id,
+        expires: (Utc::now() + Duration::minutes(SIGN_IN_FOR_MINUTES)).timestamp(),
+    };
+    let json = serde_json::to_string(&sign_in).unwrap();
+    signed_link(base, "/sign-in/confirm", SIGN_IN_PURPOSE, &json).ok_or_else(|| Error {
+        internal: "SESSION_SECRET isn't set, so links can't be signed".to_owned(),
+        external: Message::new("error-no-sign-in"),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    })
+}
+
+/// The user a sign-in link's token signs in as, if the token is genuine
+/// and hasn't expired.
+pub fn signed_in_user(token: &str, now: DateTime<Utc>) -> Fallible<Uuid> {
+    let sign_in: Option<SignIn> = cookies::verify(SIGN_IN_PURPOSE, token)
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .filter(|s: &SignIn| s.expires >= now.timestamp());
+    let Some(sign_in) = sign_in else {
+        return Err(Error {
+            internal: "invalid or expired sign-in token".to_owned(),
+            external: Message::new("error-invalid-sign-in"),
+            status: StatusCode::BAD_REQUEST,
+        });
+    };
+    Ok(sign_in.user_id)
+}
+
+/// `path` on this server, with `json` signed for `purpose` as its token.
+fn signed_link(base: &Url, path: &str, purpose: &str, json: &str) -> Option<Url> {
+    let token = cookies::sign(purpose, json)?;
+    let mut link = base.join(path).unwrap();
+    link.query_pairs_mut().append_pair("token", &token);
+    Some(link)
+}
+
+pub async fn send(to: &str, subject: &str, body: &str) -> Fallible<()> {
+    let Some(from) = global_var("MAIL_FROM") else {
+        console_logf!(
+            "MAIL_FROM isn't set, so not sending this to {}:\n{}\n\n{}",
+            to,
+            subject,
+            body
+        );
+        return Ok(());
+    };
+    let mail = json!({
+        "personalizations": [{ "to": [{ "email": to }] }],
+        "from": { "email": from, "name": "quiet" },
+        "subject": subject,
+        "content": [{ "type": "text/plain", "value": body }],
+    });
+    let headers = vec![("content-type".to_owned(), "application/json".to_owned())];
+    let url = Url::parse(MAILCHANNELS_URL).unwrap();
+    Fetch
+        .post(&url, headers, mail.to_string())
+        .await
+        .map_err(|e| Error {
+            internal: format!("couldn't send mail to {}: {}", to, e.internal),
+            external: Message::new("error-mail-failed"),
+            status: StatusCode::BAD_GATEWAY,
+        })
+}
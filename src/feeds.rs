@@ -0,0 +1,364 @@
+//! Atom, RSS and JSON Feed versions of a user's posts, for feed readers.
+use crate::cache;
+use crate::i18n;
+use crate::models::{posts, users};
+use crate::utils::*;
+use crate::view::generate_error_response;
+use chrono::{offset::Utc, DateTime, TimeZone};
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use web_sys::Request;
+
+/// How many of a user's most recent posts appear in their feed.
+const FEED_SIZE: usize = 50;
+
+pub enum Format {
+    Atom,
+    Rss,
+    Json,
+}
+
+impl Format {
+    /// The format served at `/user/<id>/<file_name>`, if any.
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        match file_name {
+            "feed.atom" => Some(Self::Atom),
+            "feed.rss" => Some(Self::Rss),
+            "feed.json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Atom => "application/atom+xml; charset=utf-8",
+            Self::Rss => "application/rss+xml; charset=utf-8",
+            Self::Json => "application/feed+json; charset=utf-8",
+        }
+    }
+}
+
+/// Everything a feed needs to know about its user, whatever the format.
+struct Feed {
+    title: String,
+    /// The user's profile page.
+    home_url: Url,
+    /// Where this feed itself is served.
+    feed_url: Url,
+    /// Newest first.
+    posts: Vec<posts::Post>,
+}
+
+impl Feed {
+    fn updated(&self) -> DateTime<Utc> {
+        // Use the newest post rather than the current time, so the feed is
+        // byte-for-byte identical until the user posts again.
+        self.posts
+            .first()
+            .map(|p| p.created)
+            .unwrap_or_else(|| Utc.timestamp(0, 0))
+    }
+
+    fn post_url(&self, post: &posts::Post) -> Url {
+        post.link.clone().unwrap_or_else(|| self.home_url.clone())
+    }
+
+    fn render(&self, format: &Format) -> String {
+        match format {
+            Format::Atom => self.to_atom(),
+            Format::Rss => self.to_rss(),
+            Format::Json => self.to_json_feed(),
+        }
+    }
+
+    fn to_atom(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        out.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        out.push_str(&format!("  <id>{}</id>\n", escape(self.home_url.as_str())));
+        out.push_str(&format!(
+            "  <link rel=\"alternate\" href=\"{}\"/>\n",
+            escape(self.home_url.as_str())
+        ));
+        out.push_str(&format!(
+            "  <link rel=\"self\" href=\"{}\"/>\n",
+            escape(self.feed_url.as_str())
+        ));
+        out.push_str(&format!(
+            "  <updated>{}</updated>\n",
+            self.updated().to_rfc3339()
+        ));
+        out.push_str(&format!(
+            "  <author><name>{}</name></author>\n",
+            escape(&self.title)
+        ));
+        for post in &self.posts {
+            out.push_str("  <entry>\n");
+            out.push_str(&format!("    <id>urn:uuid:{}</id>\n", post.id));
+            out.push_str(&format!(
+                "    <title>{}</title>\n",
+                escape(&summary(&post.text))
+            ));
+            out.push_str(&format!(
+                "    <link rel=\"alternate\" href=\"{}\"/>\n",
+                escape(self.post_url(post).as_str())
+            ));
+            out.push_str(&format!(
+                "    <updated>{}</updated>\n",
+                post.created.to_rfc3339()
+            ));
+            out.push_str(&format!(
+                "    <content type=\"text\">{}</content>\n",
+                escape(&post.text)
+            ));
+            out.push_str("  </entry>\n");
+        }
+        out.push_str("</feed>\n");
+        out
+    }
+
+    fn to_rss(&self) -> String {
+        let mut out = String::new();
+        out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        out.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n");
+        out.push_str("<channel>\n");
+        out.push_str(&format!("  <title>{}</title>\n", escape(&self.title)));
+        out.push_str(&format!(
+            "  <link>{}</link>\n",
+            escape(self.home_url.as_str())
+        ));
+        out.push_str(&format!(
+            "  <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n",
+            escape(self.feed_url.as_str())
+        ));
+        out.push_str(&format!(
+            "  <description>{}</description>\n",
+            escape(&self.title)
+        ));
+        out.push_str(&format!(
+            "  <lastBuildDate>{}</lastBuildDate>\n",
+            self.updated().to_rfc2822()
+        ));
+        for post in &self.posts {
+            out.push_str("  <item>\n");
+            out.push_str(&format!(
+                "    <guid isPermaLink=\"false\">urn:uuid:{}</guid>\n",
+                post.id
+            ));
+            out.push_str(&format!(
+                "    <link>{}</link>\n",
+                escape(self.post_url(post).as_str())
+            ));
+            out.push_str(&format!(
+                "    <description>{}</description>\n",
+                escape(&post.text)
+            ));
+            out.push_str(&format!(
+                "    <pubDate>{}</pubDate>\n",
+                post.created.to_rfc2822()
+            ));
+            out.push_str("  </item>\n");
+        }
+        out.push_str("</channel>\n");
+        out.push_str("</rss>\n");
+        out
+    }
+
+    fn to_json_feed(&self) -> String {
+        let items: Vec<_> = self
+            .posts
+            .iter()
+            .map(|post| {
+                json!({
+                    "id": post.id,
+                    "url": self.post_url(post),
+                    "external_url": post.link,
+                    "content_text": post.text,
+                    "date_published": post.created.to_rfc3339(),
+                })
+            })
+            .collect();
+        json!({
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": self.title,
+            "home_page_url": self.home_url,
+            "feed_url": self.feed_url,
+            "items": items,
+        })
+        .to_string()
+    }
+}
+
+/// Feed readers want a title for each entry, but posts don't have one.
+fn summary(text: &str) -> String {
+    const MAX_CHARS: usize = 80;
+    let line = text.lines().next().unwrap_or("");
+    if line.chars().count() <= MAX_CHARS {
+        return line.to_owned();
+    }
+    let mut summary: String = line.chars().take(MAX_CHARS - 1).collect();
+    summary.push('…');
+    summary
+}
+
+/// Makes text safe to put in XML. XML can't contain most control
+/// characters at all, even escaped, so they're replaced.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => out.push(char::REPLACEMENT_CHARACTER),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub async fn render_feed(req: Request, user_id: Uuid, format: Format) -> JsResult {
+    let feed_url = Url::parse(&req.url()).ok_or_js_err()?;
+    let lang = i18n::request_language(&req, None);
+    let home_url = feed_url
+        .join(&format!("/user/{}", user_id))
+        .ok_or_js_err()?;
+    let render = async {
+        let query = posts::PageQuery {
+            before: None,
+            limit: FEED_SIZE,
+        };
+        let page = posts::page_of_posts_by_user(user_id, None, &query).await?;
+        let title = match users::profile_by_id(user_id).await? {
+            Some(profile) => format!("{} on quiet", profile.username),
+            None => format!("{} on quiet", user_id),
+        };
+        let feed = Feed {
+            title,
+            home_url,
+            feed_url: feed_url.clone(),
+            posts: page.posts,
+        };
+        let body = feed.render(&format);
+        Ok(cache::Page::new(
+            body,
+            format.content_type(),
+            Some(feed.updated()),
+        ))
+    };
+    let page = match cache::page(&feed_url, &[], &[user_id], render).await {
+        Ok(p) => p,
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    // Readers should check for new posts whenever they look.
+    Ok(JsValue::from(page.response(&req, "public, no-cache")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use posts::Post;
+
+    /// Everything that could break out of an XML element or a CDATA section.
+    const HOSTILE: &str =
+        "Tom & Jerry <b>bold</b> ]]> \"quoted\" 'too'\u{0}\u{7}\u{1b}\u{ffff}\ttab\nline";
+    /// What's left of it once it's been through XML.
+    const SURVIVES: &str =
+        "Tom & Jerry <b>bold</b> ]]> \"quoted\" 'too'\u{fffd}\u{fffd}\u{fffd}\u{fffd}\ttab\nline";
+
+    fn feed() -> Feed {
+        let home_url = Url::parse("https://quiet.example/user/1?a=1&b=<2>").unwrap();
+        let post = |text: &str, link| Post {
+            link,
+            ..Post::fixture(text)
+        };
+        Feed {
+            title: "<script>&amp; on quiet".to_owned(),
+            feed_url: home_url.join("feed.atom?x=]]>").unwrap(),
+            home_url,
+            posts: vec![
+                post(
+                    HOSTILE,
+                    Some(Url::parse("https://a.example/?q=1&r=2").unwrap()),
+                ),
+                post("plain", None),
+            ],
+        }
+    }
+
+    const ATOM: Option<&str> = Some("http://www.w3.org/2005/Atom");
+
+    /// The text of each of `doc`'s `tag` elements in namespace `ns`.
+    fn texts(doc: &roxmltree::Document, ns: Option<&str>, tag: &str) -> Vec<String> {
+        doc.descendants()
+            .filter(|n| n.tag_name().namespace() == ns && n.tag_name().name() == tag)
+            .map(|n| n.text().unwrap_or("").to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn atom_is_well_formed() {
+        let feed = feed();
+        let atom = feed.render(&Format::Atom);
+        let doc = roxmltree::Document::parse(&atom).unwrap();
+        assert_eq!(texts(&doc, ATOM, "title")[0], feed.title);
+        assert_eq!(texts(&doc, ATOM, "content"), vec![SURVIVES, "plain"]);
+        assert_eq!(
+            texts(&doc, ATOM, "title")[1],
+            "Tom & Jerry <b>bold</b> ]]> \"quoted\" 'too'\u{fffd}\u{fffd}\u{fffd}\u{fffd}\ttab"
+        );
+        let links: Vec<_> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name((ATOM.unwrap(), "link")))
+            .filter_map(|n| n.attribute("href"))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                feed.home_url.as_str(),
+                feed.feed_url.as_str(),
+                "https://a.example/?q=1&r=2",
+                feed.home_url.as_str(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rss_is_well_formed() {
+        let feed = feed();
+        let rss = feed.render(&Format::Rss);
+        let doc = roxmltree::Document::parse(&rss).unwrap();
+        assert_eq!(
+            texts(&doc, None, "description"),
+            vec![feed.title.as_str(), SURVIVES, "plain"]
+        );
+        assert_eq!(
+            texts(&doc, None, "link"),
+            vec![
+                feed.home_url.as_str(),
+                "https://a.example/?q=1&r=2",
+                feed.home_url.as_str(),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_feed_keeps_text_exactly() {
+        let feed = feed();
+        let json: serde_json::Value = serde_json::from_str(&feed.render(&Format::Json)).unwrap();
+        assert_eq!(json["title"], feed.title.as_str());
+        assert_eq!(json["feed_url"], feed.feed_url.as_str());
+        let items = json["items"].as_array().unwrap();
+        assert_eq!(items[0]["content_text"], HOSTILE);
+        assert_eq!(items[0]["external_url"], "https://a.example/?q=1&r=2");
+        assert_eq!(items[1]["content_text"], "plain");
+        assert_eq!(items[1]["url"], feed.home_url.as_str());
+        assert!(items[1]["external_url"].is_null());
+    }
+}
@@ -0,0 +1,121 @@
+//! Flash messages: one-off notices like "you made a post", set by a handler
+//! and shown on whichever page the user sees next.
+use crate::console_logf;
+use crate::cookies;
+use chrono::{offset::Utc, DateTime, Duration};
+use serde::{Deserialize, Serialize};
+use web_sys::{Request, Response};
+
+const COOKIE: &str = "quiet_flash";
+
+/// Messages that haven't been shown within this long are dropped. The
+/// redirect they're meant to survive takes well under a second.
+const MAX_AGE_SECS: u32 = 60;
+
+#[derive(Serialize, Deserialize)]
+struct Flash {
+    messages: Vec<String>,
+    /// Unix time after which the messages are stale. It's signed along with
+    /// them, so it holds even if the browser keeps the cookie longer.
+    expires: i64,
+}
+
+impl Flash {
+    fn new(message: &str, now: DateTime<Utc>) -> Self {
+        Self {
+            messages: vec![message.to_owned()],
+            expires: (now + Duration::seconds(MAX_AGE_SECS.into())).timestamp(),
+        }
+    }
+
+    /// The messages in a cookie's (already verified) value, unless they're
+    /// stale.
+    fn parse(json: &str, now: DateTime<Utc>) -> Vec<String> {
+        match serde_json::from_str::<Flash>(json) {
+            Ok(flash) if flash.expires >= now.timestamp() => flash.messages,
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Shows `message` on the next page the user loads.
+pub fn set(resp: &Response, message: &str) {
+    let json = serde_json::to_string(&Flash::new(message, Utc::now())).unwrap();
+    let Some(signed) = cookies::sign(COOKIE, &json) else {
+        console_logf!("SESSION_SECRET isn't set, so flash messages are disabled");
+        return;
+    };
+    let cookie = cookies::set_cookie(COOKIE, &signed, MAX_AGE_SECS);
+    if let Err(e) = resp.headers().append("set-cookie", &cookie) {
+        console_logf!("Error setting flash cookie: {:?}", e);
+    }
+}
+
+/// Messages waiting to be shown to this request's user. Whoever shows them
+/// has to send `clear()` back, so they're only shown once.
+pub fn take(req: &Request) -> Vec<String> {
+    cookies::get(req, COOKIE)
+        .and_then(|signed| cookies::verify(COOKIE, &signed))
+        .map(|json| Flash::parse(&json, Utc::now()))
+        .unwrap_or_default()
+}
+
+/// A `Set-Cookie` header value that removes shown messages.
+pub fn clear() -> String {
+    cookies::clear_cookie(COOKIE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    /// What `set` would put in the cookie, and what `take` would read back.
+    fn signed(flash: &Flash) -> String {
+        let json = serde_json::to_string(flash).unwrap();
+        cookies::sign_with(SECRET, COOKIE, &json).unwrap()
+    }
+
+    fn read(signed: &str, now: DateTime<Utc>) -> Vec<String> {
+        cookies::verify_with(SECRET, COOKIE, signed)
+            .map(|json| Flash::parse(&json, now))
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn messages_survive_one_redirect() {
+        let now = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let cookie = signed(&Flash::new("You made a post", now));
+        assert_eq!(read(&cookie, now), vec!["You made a post"]);
+        let later = now + Duration::seconds(MAX_AGE_SECS.into());
+        assert_eq!(read(&cookie, later), vec!["You made a post"]);
+        let stale = later + Duration::seconds(1);
+        assert!(read(&cookie, stale).is_empty());
+    }
+
+    #[test]
+    fn forged_messages_are_ignored() {
+        let now = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let forged = Flash {
+            messages: vec!["Your account is suspended".to_owned()],
+            expires: now.timestamp() + 60,
+        };
+        let json = serde_json::to_string(&forged).unwrap();
+        let unsigned = format!("{}.", base64::encode_config(&json, base64::URL_SAFE_NO_PAD));
+        assert!(read(&unsigned, now).is_empty());
+        // A genuine signature for something else doesn't help.
+        let session = cookies::sign_with(SECRET, "quiet_session", &json).unwrap();
+        assert!(read(&session, now).is_empty());
+    }
+
+    #[test]
+    fn messages_are_cleared_once_shown() {
+        // Whoever shows the messages sends this back, which deletes the
+        // cookie, and an empty one has nothing in it anyway.
+        assert!(clear().starts_with(&format!("{}=;", COOKIE)));
+        assert!(clear().contains("Max-Age=0;"));
+        assert!(read("", Utc::now()).is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+//! Translations of everything quiet says to people. Each language has a
+//! Fluent catalog in `i18n/`, and code refers to messages by ID, so nothing
+//! user-facing is hard-coded in one language.
+use crate::models::users::Profile;
+use chrono::{offset::Utc, DateTime, Locale};
+use chrono_tz::Tz;
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource, FluentValue};
+use fluent_langneg::{accepted_languages, negotiate_languages, NegotiationStrategy};
+use lazy_static::lazy_static;
+use std::convert::TryFrom;
+use std::fmt;
+pub use unic_langid::LanguageIdentifier;
+use web_sys::Request;
+
+/// Used when nothing the user asked for is available, and for messages a
+/// catalog hasn't translated yet.
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Each language's catalog, and the locale its readers get dates in if they
+/// haven't picked one.
+const CATALOGS: [(&str, &str, &str); 2] = [
+    ("en", "en-US", include_str!("i18n/en.ftl")),
+    ("es", "es-ES", include_str!("i18n/es.ftl")),
+];
+
+/// Weekday, the locale's way of writing a date, and a 24-hour time.
+const DATE_FORMAT: &str = "%a %x %H:%M";
+
+lazy_static! {
+    static ref BUNDLES: Vec<(LanguageIdentifier, FluentBundle<FluentResource>)> = CATALOGS
+        .iter()
+        .map(|(lang, _, source)| {
+            let lang: LanguageIdentifier = lang.parse().unwrap();
+            let resource = FluentResource::try_new(source.to_string())
+                .unwrap_or_else(|(_, e)| panic!("invalid catalog {}: {:?}", lang, e));
+            let mut bundle = FluentBundle::new_concurrent(vec![lang.clone()]);
+            // Otherwise arguments are wrapped in invisible bidi isolation
+            // marks, which end up in JSON responses and `alert()`s.
+            bundle.set_use_isolating(false);
+            bundle
+                .add_resource(resource)
+                .unwrap_or_else(|e| panic!("invalid catalog {}: {:?}", lang, e));
+            (lang, bundle)
+        })
+        .collect();
+}
+
+pub fn default_language() -> LanguageIdentifier {
+    DEFAULT_LANGUAGE.parse().unwrap()
+}
+
+/// The available language `lang` names, if there is one.
+pub fn supported(lang: &str) -> Option<LanguageIdentifier> {
+    let lang: LanguageIdentifier = lang.parse().ok()?;
+    BUNDLES
+        .iter()
+        .map(|(l, _)| l)
+        .find(|l| **l == lang)
+        .cloned()
+}
+
+/// Every language quiet is available in.
+pub fn languages() -> impl Iterator<Item = &'static LanguageIdentifier> {
+    BUNDLES.iter().map(|(l, _)| l)
+}
+
+/// Picks the language to talk to someone in: the one their profile asks
+/// for, otherwise the best match for their `Accept-Language` header.
+pub fn negotiate(preferred: Option<&str>, accept_language: Option<&str>) -> LanguageIdentifier {
+    let mut requested: Vec<LanguageIdentifier> =
+        preferred.and_then(|l| l.parse().ok()).into_iter().collect();
+    requested.extend(
+        accept_language
+            .map(accepted_languages::parse)
+            .unwrap_or_default(),
+    );
+    let available: Vec<_> = BUNDLES.iter().map(|(l, _)| l.clone()).collect();
+    let default = default_language();
+    negotiate_languages(
+        &requested,
+        &available,
+        Some(&default),
+        NegotiationStrategy::Lookup,
+    )
+    .first()
+    .map(|l| (*l).clone())
+    .unwrap_or(default)
+}
+
+pub fn accept_language(req: &Request) -> Option<String> {
+    req.headers().get("accept-language").ok().flatten()
+}
+
+/// The language to respond to `req` in. `viewer` is whoever is using the
+/// site, if we know.
+pub fn request_language(req: &Request, viewer: Option<&Profile>) -> LanguageIdentifier {
+    let preferred = viewer.and_then(|p| p.language.as_deref());
+    negotiate(preferred, accept_language(req).as_deref())
+}
+
+/// Formats message `id` in `lang`, falling back to the default language.
+/// None if no catalog has the message.
+pub fn format(lang: &LanguageIdentifier, id: &str, args: Option<&FluentArgs>) -> Option<String> {
+    let default = default_language();
+    [lang, &default].iter().find_map(|lang| {
+        let (_, bundle) = BUNDLES.iter().find(|(l, _)| l == *lang)?;
+        let pattern = bundle.get_message(id)?.value()?;
+        let mut errors = Vec::new();
+        Some(
+            bundle
+                .format_pattern(pattern, args, &mut errors)
+                .into_owned(),
+        )
+    })
+}
+
+/// The locale dates are written in for someone reading `lang`, if they
+/// haven't chosen one.
+pub fn default_locale(lang: &LanguageIdentifier) -> &'static str {
+    CATALOGS
+        .iter()
+        .find(|(l, _, _)| lang.language == *l)
+        .map(|(_, locale, _)| *locale)
+        .unwrap_or("en-US")
+}
+
+/// The IANA time zone called `name`, like "Pacific/Auckland".
+pub fn timezone(name: &str) -> Option<Tz> {
+    name.parse().ok()
+}
+
+/// The date formatting rules for a locale tag like "en-GB".
+pub fn date_locale(tag: &str) -> Option<Locale> {
+    let tag: LanguageIdentifier = tag.parse().ok()?;
+    // chrono names locales the POSIX way, like en_GB.
+    Locale::try_from(tag.to_string().replace('-', "_").as_str()).ok()
+}
+
+/// Writes `date` the way someone in `timezone` who uses `locale` would.
+/// Unknown time zones and locales fall back to UTC and POSIX.
+pub fn format_date(date: DateTime<Utc>, timezone_name: &str, locale: &str) -> String {
+    let tz = timezone(timezone_name).unwrap_or(Tz::UTC);
+    let locale = date_locale(locale).unwrap_or(Locale::POSIX);
+    date.with_timezone(&tz)
+        .format_localized(DATE_FORMAT, locale)
+        .to_string()
+}
+
+/// Something to tell the user, translated once we know what language
+/// they read.
+#[derive(Debug, Clone)]
+pub struct Message {
+    id: &'static str,
+    args: Vec<(&'static str, FluentValue<'static>)>,
+}
+
+impl Message {
+    pub fn new(id: &'static str) -> Self {
+        Self {
+            id,
+            args: Vec::new(),
+        }
+    }
+
+    /// Fills in the `{ $name }` placeable in the message.
+    pub fn arg(mut self, name: &'static str, value: impl Into<FluentValue<'static>>) -> Self {
+        self.args.push((name, value.into()));
+        self
+    }
+
+    /// The number filled in for `{ $name }`, if there is one.
+    pub fn number_arg(&self, name: &str) -> Option<f64> {
+        self.args.iter().find_map(|(n, value)| match value {
+            FluentValue::Number(number) if *n == name => Some(number.value),
+            _ => None,
+        })
+    }
+
+    pub fn localize(&self, lang: &LanguageIdentifier) -> String {
+        let mut args = FluentArgs::new();
+        for (name, value) in &self.args {
+            args.set(*name, value.clone());
+        }
+        format(lang, self.id, Some(&args)).unwrap_or_else(|| self.id.to_owned())
+    }
+}
+
+/// In the default language, for logs.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.localize(&default_language()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lang(s: &str) -> LanguageIdentifier {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn profile_preference_beats_accept_language() {
+        let header = Some("es-MX,es;q=0.9,en;q=0.8");
+        assert_eq!(negotiate(None, header), lang("es"));
+        assert_eq!(negotiate(Some("en"), header), lang("en"));
+        assert_eq!(negotiate(None, Some("fr-FR, de")), lang("en"));
+        assert_eq!(negotiate(None, None), lang("en"));
+    }
+
+    #[test]
+    fn every_message_is_translated() {
+        let (_, _, english) = CATALOGS[0];
+        let ids = english
+            .lines()
+            .filter(|l| !l.starts_with(|c: char| c == '#' || c.is_whitespace()))
+            .filter_map(|l| l.split('=').next())
+            .map(str::trim)
+            .filter(|id| !id.is_empty());
+        for id in ids {
+            for (lang, bundle) in BUNDLES.iter() {
+                assert!(
+                    bundle.has_message(id),
+                    "{} has no translation of {}",
+                    lang,
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn messages_fill_in_arguments() {
+        let msg = Message::new("post-too-long")
+            .arg("max", 1000)
+            .arg("count", 1200);
+        assert_eq!(
+            msg.localize(&lang("en")),
+            "Posts can only have 1000 characters, but yours has 1200"
+        );
+        assert_eq!(
+            msg.localize(&lang("es")),
+            "Las publicaciones solo pueden tener 1000 caracteres, pero la tuya tiene 1200"
+        );
+    }
+
+    #[test]
+    fn dates_are_in_the_viewers_timezone_and_locale() {
+        use chrono::TimeZone;
+        // Late on Tuesday in UTC is already Wednesday in New Zealand.
+        let date = Utc.ymd(2020, 9, 1).and_hms(22, 30, 0);
+        assert_eq!(
+            format_date(date, "Pacific/Auckland", "en-US"),
+            "Wed 09/02/2020 10:30"
+        );
+        assert_eq!(
+            format_date(date, "Europe/Madrid", "es-ES"),
+            "mié 02/09/20 00:30"
+        );
+        assert_eq!(
+            format_date(date, "Mars/Olympus_Mons", "xx"),
+            format_date(date, "UTC", "POSIX")
+        );
+    }
+}
@@ -0,0 +1,239 @@
+# Everything quiet says to people, in English. This is the fallback for
+# messages a translation doesn't have yet, so every message starts here.
+
+# What this language calls itself, for the language picker.
+language-name = English
+
+## Pages
+
+nav-about = about
+nav-new-post = new post
+nav-settings = settings
+nav-moderation = moderation
+nav-sign-in = sign in
+nav-sign-out = sign out
+all-posts = all posts
+older-posts = older posts
+post-report = Report
+post-report-prompt = Why are you reporting this post?
+profile-follow = Follow
+profile-unfollow = Unfollow
+profile-block = Block
+profile-unblock = Unblock
+profile-mute = Mute
+profile-unmute = Unmute
+new-post-title = quiet. new post.
+new-post-heading = new post
+new-post-link-placeholder = Add a link (optional)
+new-post-text-placeholder = What do you want to say?
+new-post-submit = Send your daily post
+new-post-visibility = Who can read it
+visibility-public = Everyone
+visibility-unlisted = Anyone with the link, but not in lists or feeds
+visibility-followers-only = Only your followers
+error-title = Error
+sign-in-title = quiet. sign in.
+sign-in-heading = sign in
+sign-in-username = Username
+sign-in-hint = We'll email you a link to sign in with.
+sign-in-submit = Send me a link
+settings-title = quiet. settings.
+settings-heading = settings
+settings-username = Username
+settings-email = Email
+settings-email-hint = If you change it, we'll send a link to the new address to check it's yours.
+settings-bio = Bio
+settings-language = Language
+settings-language-auto = Same as my browser
+settings-timezone = Time zone
+settings-locale = Date format
+settings-locale-hint = A locale like en-GB. Leave it empty to match your language.
+settings-save = Save
+settings-avatar = Upload a picture
+settings-avatar-hint = A PNG, JPEG or GIF up to 2 MB and 1024 pixels wide and high. It's cropped to a square.
+settings-avatar-submit = Upload
+settings-export = Download your data
+settings-export-hint = Your profile, posts and followers, as a JSON file.
+settings-import = Import posts
+settings-import-hint = A quiet export, a CSV file with text, link and created columns, outbox.json from a Mastodon archive, or data/tweets.js from a Twitter archive. Up to 10 MB.
+settings-import-submit = Import
+settings-delete-heading = delete your account
+settings-private = Private account
+settings-private-hint = Only you and followers you approve can read your posts
+settings-requests-heading = follow requests
+settings-requests-approve = Approve
+settings-requests-deny = Deny
+settings-blocks-heading = blocked and muted
+settings-blocks-account = Someone to block or mute
+settings-blocks-hint = A quiet user's ID, or the address of someone on another server, like https://mastodon.example/users/bob
+settings-delete-hint = This deletes your profile, posts and picture, and tells your followers' servers you've gone. It can't be undone. Type your username to confirm.
+settings-delete-submit = Delete my account
+moderation-title = quiet. moderation.
+moderation-heading = reported posts
+moderation-reason = Reason: { $reason }
+moderation-hide = Hide post
+moderation-suspend = Suspend author
+moderation-dismiss = Dismiss
+moderation-empty = Nothing has been reported.
+moderation-undo-heading = undo
+moderation-undo-user = User ID
+moderation-undo-post = Post ID, to show a hidden post again
+moderation-unhide = Show post again
+moderation-unsuspend = Lift suspension
+moderation-roles-heading = roles
+moderation-roles-user = User ID
+moderation-roles-role = Role
+moderation-roles-submit = Change role
+role-user = User
+role-moderator = Moderator
+role-admin = Admin
+
+## Flash messages
+
+flash-post-created = You made a post
+flash-account-deleted = Your account was deleted. Goodbye!
+flash-avatar-saved = Your new picture was saved
+flash-settings-saved = Your settings were saved
+flash-email-verification-sent = Your settings were saved. To finish changing your email address, follow the link we sent to { $email }
+flash-email-changed = Your email address is now { $email }
+flash-post-reported = Thanks for reporting that post. A moderator will look at it
+flash-post-hidden = The post was hidden
+flash-reports-dismissed = The reports were dismissed
+flash-user-suspended = The user was suspended
+flash-post-unhidden = The post is shown again
+flash-user-unsuspended = The user's suspension was lifted
+flash-role-changed = The user's role was changed
+flash-follow-approved = Follow request approved
+flash-follow-denied = Follow request denied
+flash-followed = Following. Their posts will show in your feed
+flash-unfollowed = Unfollowed
+flash-blocked = Blocked. You won't see each other, and they can't follow you
+flash-unblocked = Unblocked
+flash-muted = Muted. Their posts won't show in your feed
+flash-unmuted = Unmuted
+flash-sign-in-sent = If that account exists, we've emailed it a link to sign in
+flash-signed-in = Signed in as { $username }
+flash-signed-out = Signed out
+
+## Email
+
+email-verify-subject = Confirm your new email address for quiet
+email-verify-body =
+    Hi { $username },
+
+    To use { $email } for your quiet account, open this link within a day:
+
+    { $link }
+
+    If you didn't ask for this, you can ignore this email.
+
+email-sign-in-subject = Sign in to quiet
+email-sign-in-body =
+    Hi { $username },
+
+    To sign in to quiet, open this link within half an hour:
+
+    { $link }
+
+    If you didn't ask for this, you can ignore this email.
+
+## Validation
+
+post-too-long = Posts can only have { $max } characters, but yours has { $count }
+invalid-user-id = { $id } is an invalid user ID
+invalid-link = The URL is invalid
+policy-blocked-domain = Posts can't link to { $domain }
+policy-banned-word = Posts can't contain “{ $word }”
+policy-too-many-links = Posts can only have { $max } links, but yours has { $count }
+policy-repeated = You've already posted that recently
+policy-classifier-rejected = This post looks like spam or abuse: { $reason }
+report-reason-too-long = Reasons can only have { $max } characters, but yours has { $count }
+username-too-long = Usernames can only have { $max } characters, but yours has { $count }
+invalid-pic-url = Your picture URL is invalid
+invalid-email = Your email address is invalid
+bio-too-long = Bios can only have { $max } characters, but yours has { $count }
+invalid-timezone = { $timezone } isn't a time zone quiet knows. Try a name like Europe/London
+invalid-locale = { $locale } isn't a locale quiet knows. Try one like en-GB
+unsupported-language = quiet isn't available in { $language } yet
+delete-confirm-mismatch = Type your username exactly to confirm you want to delete your account
+import-unreadable = quiet couldn't read that file
+import-unknown-format = quiet doesn't recognise that kind of file. Try a quiet export, a CSV file, Mastodon's outbox.json or Twitter's tweets.js
+import-bad-row = This entry is missing its text
+import-bad-date = This entry's date isn't one quiet understands
+import-summary = { $imported ->
+        [one] Imported one post
+       *[other] Imported { $imported } posts
+    }. { $skipped } skipped as reposts or duplicates, { $failed } couldn't be imported.
+upload-missing = Choose a file to upload
+avatar-wrong-type = Pictures have to be PNG, JPEG or GIF images
+avatar-unreadable = That file isn't a picture quiet can read
+upload-too-large = Files can be at most { $max } MB
+avatar-too-many-pixels = Pictures can be at most { $max } pixels wide and high
+invalid-cursor = { $cursor } is an invalid cursor
+invalid-limit = limit must be a number between 1 and { $max }
+
+## Errors
+
+error-page-not-found = Page not found
+error-not-found = Not found
+error-invalid-id = { $id } is an invalid ID
+error-invalid-url = Invalid URL
+error-read-json = couldn't get JSON from request
+error-await-json = Error awaiting JSON
+error-malformed-post = Your post was malformed
+error-malformed-profile = Your profile was malformed
+error-malformed-settings = Your settings were malformed
+error-malformed-report = Your report was malformed
+error-malformed-action = That moderation action was malformed
+error-invalid-post = Invalid post
+error-invalid-profile = Invalid profile
+error-busy = Lots of changes are being saved right now, please try again
+error-rate-limited = { $retry_after ->
+        [one] You're doing that too often, please try again in a second
+       *[other] You're doing that too often, please try again in { $retry_after } seconds
+    }
+error-migrate-database = The database is being upgraded, please try again in a moment
+error-save-post = Post unsuccessful, please try again later
+error-save-profile = Couldn't save your profile, please try again later
+error-signed-out = You need to sign in to do that
+error-not-your-post = You can only post as yourself
+error-signups-closed = This server isn't taking sign-ups
+error-csrf = That didn't come from one of quiet's pages. Reload the page and try again
+error-malformed-sign-in = Say which username to sign in as
+error-no-sign-in = This server can't sign people in
+error-invalid-sign-in = That sign-in link is invalid or has expired
+error-no-email-verification = This server can't check email addresses, so yours can't be changed
+error-invalid-verification = That link is invalid or has expired
+error-mail-failed = Couldn't send email, please try again later
+error-username-taken = That username is taken
+error-suspended = Your account is suspended, so you can't post
+error-not-moderator = Only moderators can do that
+error-not-admin = Only admins can do that
+error-read-form = couldn't read the form
+error-save-avatar = Couldn't save your picture, please try again later
+error-load-avatar = couldn't load picture from database
+error-delete-account = Couldn't delete everything in your account, please try again
+error-load-posts = couldn't load posts from database
+error-load-profile = couldn't load profile from database
+error-load-followers = couldn't load followers from database
+error-save-followers = Couldn't save followers, please try again later
+error-load-reports = couldn't load reports from database
+error-save-report = Couldn't save the report, please try again later
+error-load-blocks = Couldn't load who you've blocked and muted, please try again later
+error-save-blocks = Couldn't save who you've blocked and muted, please try again later
+error-malformed-block = Say who to block or mute, and whether to block, unblock, mute or unmute them
+error-invalid-account = { $account } isn't a quiet user's ID or the address of someone on another server
+error-block-self = You can't block or mute yourself
+error-follow-self = You can't follow yourself
+error-follow-private = Private accounts can only be followed from other servers
+error-private-account = This account is private
+error-malformed-follow-answer = Say whose follow request to answer, and whether to approve it
+error-blocked = This isn't available because of a block
+error-build-response = couldn't build the response
+error-no-federation = This server doesn't federate
+error-federation-misconfigured = Federation is misconfigured
+error-invalid-activity = Invalid activity: { $reason }
+error-bad-signature = Couldn't verify the request's signature
+error-sign-activity = Couldn't sign activity
+error-remote-unreachable = Couldn't reach the other server
+error-delivery-failed = Couldn't deliver your post to some followers
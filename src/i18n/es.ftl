@@ -0,0 +1,237 @@
+# Spanish translation of en.ftl.
+
+language-name = Español
+
+## Pages
+
+nav-about = acerca de
+nav-new-post = nueva publicación
+nav-settings = ajustes
+nav-moderation = moderación
+nav-sign-in = iniciar sesión
+nav-sign-out = cerrar sesión
+all-posts = todas las publicaciones
+older-posts = publicaciones anteriores
+post-report = Denunciar
+post-report-prompt = ¿Por qué denuncias esta publicación?
+profile-follow = Seguir
+profile-unfollow = Dejar de seguir
+profile-block = Bloquear
+profile-unblock = Desbloquear
+profile-mute = Silenciar
+profile-unmute = Dejar de silenciar
+new-post-title = quiet. nueva publicación.
+new-post-heading = nueva publicación
+new-post-link-placeholder = Añade un enlace (opcional)
+new-post-text-placeholder = ¿Qué quieres decir?
+new-post-submit = Envía tu publicación del día
+new-post-visibility = Quién puede leerla
+visibility-public = Todo el mundo
+visibility-unlisted = Quien tenga el enlace, pero no en listas ni feeds
+visibility-followers-only = Solo tus seguidores
+error-title = Error
+sign-in-title = quiet. iniciar sesión.
+sign-in-heading = iniciar sesión
+sign-in-username = Nombre de usuario
+sign-in-hint = Te enviaremos por correo un enlace para iniciar sesión.
+sign-in-submit = Envíame un enlace
+settings-title = quiet. ajustes.
+settings-heading = ajustes
+settings-username = Nombre de usuario
+settings-email = Correo electrónico
+settings-email-hint = Si lo cambias, enviaremos un enlace a la nueva dirección para comprobar que es tuya.
+settings-bio = Biografía
+settings-language = Idioma
+settings-language-auto = El de mi navegador
+settings-timezone = Zona horaria
+settings-locale = Formato de fecha
+settings-locale-hint = Una configuración regional como es-ES. Déjalo vacío para usar la de tu idioma.
+settings-save = Guardar
+settings-avatar = Sube una imagen
+settings-avatar-hint = Un PNG, JPEG o GIF de hasta 2 MB y 1024 píxeles de ancho y de alto. Se recorta en forma de cuadrado.
+settings-avatar-submit = Subir
+settings-export = Descarga tus datos
+settings-export-hint = Tu perfil, tus publicaciones y tus seguidores, en un archivo JSON.
+settings-import = Importar publicaciones
+settings-import-hint = Una exportación de quiet, un archivo CSV con las columnas text, link y created, el outbox.json de un archivo de Mastodon o el data/tweets.js de un archivo de Twitter. Hasta 10 MB.
+settings-import-submit = Importar
+settings-delete-heading = eliminar tu cuenta
+settings-private = Cuenta privada
+settings-private-hint = Solo tú y los seguidores que apruebes podéis leer tus publicaciones
+settings-requests-heading = solicitudes de seguimiento
+settings-requests-approve = Aprobar
+settings-requests-deny = Rechazar
+settings-blocks-heading = bloqueados y silenciados
+settings-blocks-account = Alguien a quien bloquear o silenciar
+settings-blocks-hint = El ID de un usuario de quiet, o la dirección de alguien en otro servidor, como https://mastodon.example/users/bob
+settings-delete-hint = Esto elimina tu perfil, tus publicaciones y tu imagen, y avisa a los servidores de tus seguidores de que te has ido. No se puede deshacer. Escribe tu nombre de usuario para confirmarlo.
+settings-delete-submit = Eliminar mi cuenta
+moderation-title = quiet. moderación.
+moderation-heading = publicaciones denunciadas
+moderation-reason = Motivo: { $reason }
+moderation-hide = Ocultar publicación
+moderation-suspend = Suspender al autor
+moderation-dismiss = Descartar
+moderation-empty = No se ha denunciado nada.
+moderation-undo-heading = deshacer
+moderation-undo-user = ID de usuario
+moderation-undo-post = ID de la publicación, para volver a mostrar una publicación oculta
+moderation-unhide = Volver a mostrar la publicación
+moderation-unsuspend = Levantar la suspensión
+moderation-roles-heading = roles
+moderation-roles-user = ID de usuario
+moderation-roles-role = Rol
+moderation-roles-submit = Cambiar rol
+role-user = Usuario
+role-moderator = Moderador
+role-admin = Administrador
+
+## Flash messages
+
+flash-post-created = Has publicado
+flash-account-deleted = Se ha eliminado tu cuenta. ¡Adiós!
+flash-avatar-saved = Se ha guardado tu nueva imagen
+flash-settings-saved = Se han guardado tus ajustes
+flash-email-verification-sent = Se han guardado tus ajustes. Para terminar de cambiar tu correo electrónico, abre el enlace que enviamos a { $email }
+flash-email-changed = Tu correo electrónico ahora es { $email }
+flash-post-reported = Gracias por denunciar esa publicación. Un moderador la revisará
+flash-post-hidden = Se ha ocultado la publicación
+flash-reports-dismissed = Se han descartado las denuncias
+flash-user-suspended = Se ha suspendido al usuario
+flash-post-unhidden = Se ha vuelto a mostrar la publicación
+flash-user-unsuspended = Se ha levantado la suspensión del usuario
+flash-role-changed = Se ha cambiado el rol del usuario
+flash-follow-approved = Solicitud de seguimiento aprobada
+flash-follow-denied = Solicitud de seguimiento rechazada
+flash-followed = Siguiendo. Sus publicaciones aparecerán en tu feed
+flash-unfollowed = Has dejado de seguir
+flash-blocked = Bloqueado. No os veréis y no podrá seguirte
+flash-unblocked = Desbloqueado
+flash-muted = Silenciado. Sus publicaciones no aparecerán en tu feed
+flash-unmuted = Ya no está silenciado
+flash-sign-in-sent = Si esa cuenta existe, le hemos enviado un enlace para iniciar sesión
+flash-signed-in = Has iniciado sesión como { $username }
+flash-signed-out = Has cerrado la sesión
+
+## Email
+
+email-verify-subject = Confirma tu nueva dirección de correo para quiet
+email-verify-body =
+    Hola, { $username }:
+
+    Para usar { $email } en tu cuenta de quiet, abre este enlace antes de que pase un día:
+
+    { $link }
+
+    Si no lo has pedido tú, puedes ignorar este correo.
+
+email-sign-in-subject = Inicia sesión en quiet
+email-sign-in-body =
+    Hola, { $username }:
+
+    Para iniciar sesión en quiet, abre este enlace antes de que pase media hora:
+
+    { $link }
+
+    Si no lo has pedido tú, puedes ignorar este correo.
+
+## Validation
+
+post-too-long = Las publicaciones solo pueden tener { $max } caracteres, pero la tuya tiene { $count }
+invalid-user-id = { $id } no es un ID de usuario válido
+invalid-link = La URL no es válida
+policy-blocked-domain = Las publicaciones no pueden enlazar a { $domain }
+policy-banned-word = Las publicaciones no pueden contener «{ $word }»
+policy-too-many-links = Las publicaciones solo pueden tener { $max } enlaces, pero la tuya tiene { $count }
+policy-repeated = Ya has publicado eso hace poco
+policy-classifier-rejected = Esta publicación parece spam o abuso: { $reason }
+report-reason-too-long = Los motivos solo pueden tener { $max } caracteres, pero el tuyo tiene { $count }
+username-too-long = Los nombres de usuario solo pueden tener { $max } caracteres, pero el tuyo tiene { $count }
+invalid-pic-url = La URL de tu imagen no es válida
+invalid-email = Tu dirección de correo electrónico no es válida
+bio-too-long = Las biografías solo pueden tener { $max } caracteres, pero la tuya tiene { $count }
+invalid-timezone = quiet no conoce la zona horaria { $timezone }. Prueba con un nombre como Europe/Madrid
+invalid-locale = quiet no conoce la configuración regional { $locale }. Prueba con una como es-ES
+unsupported-language = quiet todavía no está disponible en { $language }
+delete-confirm-mismatch = Escribe tu nombre de usuario exactamente para confirmar que quieres eliminar tu cuenta
+import-unreadable = quiet no ha podido leer ese archivo
+import-unknown-format = quiet no reconoce ese tipo de archivo. Prueba con una exportación de quiet, un CSV, el outbox.json de Mastodon o el tweets.js de Twitter
+import-bad-row = A esta entrada le falta el texto
+import-bad-date = quiet no entiende la fecha de esta entrada
+import-summary = { $imported ->
+        [one] Se ha importado una publicación
+       *[other] Se han importado { $imported } publicaciones
+    }. { $skipped } omitidas por ser republicaciones o duplicados, { $failed } no se pudieron importar.
+upload-missing = Elige un archivo para subir
+avatar-wrong-type = Las imágenes tienen que ser PNG, JPEG o GIF
+avatar-unreadable = quiet no puede leer ese archivo como imagen
+upload-too-large = Los archivos pueden ocupar como máximo { $max } MB
+avatar-too-many-pixels = Las imágenes pueden medir como máximo { $max } píxeles de ancho y de alto
+invalid-cursor = { $cursor } no es un cursor válido
+invalid-limit = limit debe ser un número entre 1 y { $max }
+
+## Errors
+
+error-page-not-found = Página no encontrada
+error-not-found = No encontrado
+error-invalid-id = { $id } no es un ID válido
+error-invalid-url = URL no válida
+error-read-json = no se pudo leer el JSON de la petición
+error-await-json = Error al leer el JSON
+error-malformed-post = Tu publicación tiene un formato incorrecto
+error-malformed-profile = Tu perfil tiene un formato incorrecto
+error-malformed-settings = Tus ajustes tienen un formato incorrecto
+error-malformed-report = Tu denuncia no tenía el formato correcto
+error-malformed-action = Esa acción de moderación no tenía el formato correcto
+error-invalid-post = Publicación no válida
+error-invalid-profile = Perfil no válido
+error-busy = Se están guardando muchos cambios ahora mismo, inténtalo de nuevo
+error-rate-limited = { $retry_after ->
+        [one] Lo estás haciendo demasiado a menudo, inténtalo de nuevo en un segundo
+       *[other] Lo estás haciendo demasiado a menudo, inténtalo de nuevo en { $retry_after } segundos
+    }
+error-migrate-database = Se está actualizando la base de datos, inténtalo de nuevo en un momento
+error-save-post = No se pudo publicar, inténtalo de nuevo más tarde
+error-save-profile = No se pudo guardar tu perfil, inténtalo de nuevo más tarde
+error-signed-out = Tienes que iniciar sesión para hacer eso
+error-not-your-post = Solo puedes publicar en tu propio nombre
+error-signups-closed = Este servidor no admite nuevos registros
+error-csrf = Eso no se envió desde una página de quiet. Vuelve a cargar la página e inténtalo de nuevo
+error-malformed-sign-in = Indica con qué nombre de usuario quieres iniciar sesión
+error-no-sign-in = Este servidor no puede iniciar sesiones
+error-invalid-sign-in = Ese enlace para iniciar sesión no es válido o ha caducado
+error-no-email-verification = Este servidor no puede comprobar direcciones de correo, así que no se puede cambiar la tuya
+error-invalid-verification = Ese enlace no es válido o ha caducado
+error-mail-failed = No se pudo enviar el correo, inténtalo de nuevo más tarde
+error-username-taken = Ese nombre de usuario ya está en uso
+error-suspended = Tu cuenta está suspendida, así que no puedes publicar
+error-not-moderator = Solo los moderadores pueden hacer eso
+error-not-admin = Solo los administradores pueden hacer eso
+error-read-form = no se pudo leer el formulario
+error-save-avatar = No se pudo guardar tu imagen, inténtalo de nuevo más tarde
+error-load-avatar = no se pudo cargar la imagen de la base de datos
+error-delete-account = No se pudo eliminar todo lo de tu cuenta, inténtalo de nuevo
+error-load-posts = no se pudieron cargar las publicaciones de la base de datos
+error-load-profile = no se pudo cargar el perfil de la base de datos
+error-load-followers = no se pudieron cargar los seguidores de la base de datos
+error-save-followers = No se pudieron guardar los seguidores, inténtalo de nuevo más tarde
+error-load-reports = no se pudieron cargar las denuncias de la base de datos
+error-save-report = No se pudo guardar la denuncia, inténtalo de nuevo más tarde
+error-load-blocks = No se pudo cargar a quién has bloqueado y silenciado, inténtalo de nuevo más tarde
+error-save-blocks = No se pudo guardar a quién has bloqueado y silenciado, inténtalo de nuevo más tarde
+error-malformed-block = Indica a quién bloquear o silenciar, y si quieres bloquear, desbloquear, silenciar o dejar de silenciar
+error-invalid-account = { $account } no es el ID de un usuario de quiet ni la dirección de alguien en otro servidor
+error-block-self = No puedes bloquearte ni silenciarte a ti mismo
+error-follow-self = No puedes seguirte a ti mismo
+error-follow-private = Las cuentas privadas solo se pueden seguir desde otros servidores
+error-private-account = Esta cuenta es privada
+error-malformed-follow-answer = Indica qué solicitud de seguimiento responder y si la apruebas
+error-blocked = Esto no está disponible por un bloqueo
+error-build-response = no se pudo construir la respuesta
+error-no-federation = Este servidor no federa
+error-federation-misconfigured = La federación está mal configurada
+error-invalid-activity = Actividad no válida: { $reason }
+error-bad-signature = No se pudo verificar la firma de la petición
+error-sign-activity = No se pudo firmar la actividad
+error-remote-unreachable = No se pudo contactar con el otro servidor
+error-delivery-failed = No se pudo entregar tu publicación a algunos seguidores
@@ -0,0 +1,468 @@
+//! Bringing posts from elsewhere into quiet, from `POST /settings/import`.
+//! People can upload a quiet export, a CSV file with `text`, `link` and
+//! `created` columns, the `outbox.json` from a Mastodon archive or the
+//! `tweets.js` from a Twitter archive. Every entry is validated like a new
+//! post, keeps its original date, and is reported on if it can't be used.
+use crate::archive::Archive;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::posts::{self, NewPost, Post, Visibility};
+use crate::models::users;
+use crate::twoface::*;
+use crate::uploads;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use uuid::Uuid;
+use web_sys::{Request, Response};
+
+/// Archives bigger than this are rejected before they're read.
+const MAX_IMPORT_MB: u32 = 10;
+
+/// What the start of a Twitter archive's `tweets.js` assigns the tweets to.
+const TWITTER_PREFIX: &str = "window.YTD.tweets.part0";
+
+/// One entry in an archive, read as far as it could be.
+enum Row {
+    Post(NewPost, Option<DateTime<Utc>>),
+    /// Something that isn't the user's own post, like a boost or retweet.
+    Skip,
+    Invalid(Message),
+}
+
+pub struct Report {
+    pub imported: usize,
+    /// Reposts of other people, and posts that were already imported.
+    pub skipped: usize,
+    /// Entries that couldn't be imported, numbered from 1.
+    pub failed: Vec<(usize, Message)>,
+}
+
+/// A report, as the settings page sees it.
+#[derive(Serialize)]
+struct ReportView {
+    summary: String,
+    imported: usize,
+    skipped: usize,
+    failed: Vec<FailedEntry>,
+}
+
+#[derive(Serialize)]
+struct FailedEntry {
+    entry: usize,
+    error: String,
+}
+
+impl Report {
+    fn summary(&self) -> Message {
+        Message::new("import-summary")
+            .arg("imported", self.imported)
+            .arg("skipped", self.skipped)
+            .arg("failed", self.failed.len())
+    }
+
+    fn view(&self, lang: &i18n::LanguageIdentifier) -> ReportView {
+        ReportView {
+            summary: self.summary().localize(lang),
+            imported: self.imported,
+            skipped: self.skipped,
+            failed: self
+                .failed
+                .iter()
+                .map(|(entry, error)| FailedEntry {
+                    entry: *entry,
+                    error: error.localize(lang),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `POST /settings/import`
+pub async fn import(req: Request) -> Result<Response, Response> {
+    let lang = i18n::request_language(&req, None);
+    let report = import_upload(&req)
+        .await
+        .map_err(|e| e.localized_response(&lang))?;
+    let body = serde_json::to_string(&report.view(&lang)).unwrap();
+    let resp = success_response(&body, Some("/".to_owned()));
+    flash::set(&resp, &report.summary().localize(&lang));
+    Ok(resp)
+}
+
+async fn import_upload(req: &Request) -> Fallible<Report> {
+    let profile = users::signed_in(req).await?;
+    let upload = uploads::read(req, "archive", MAX_IMPORT_MB).await?;
+    let rows = String::from_utf8(upload.bytes)
+        .map_err(|_| Message::new("import-unreadable"))
+        .and_then(|text| read_archive(&text, profile.id))
+        .map_err(|e| Error {
+            internal: e.to_string(),
+            external: e,
+            status: StatusCode::BAD_REQUEST,
+        })?;
+    let existing = posts::all_posts_by_user(profile.id).await?;
+    let (imported, report) = check_rows(&existing, rows);
+    // Old posts aren't sent to followers, who'd get someone's whole history at once.
+    posts::add_imported(profile.id, imported).await?;
+    Ok(report)
+}
+
+/// Validates each row as a new post, leaving out ones that are already in
+/// `existing` or earlier in the archive.
+fn check_rows(existing: &[Post], rows: Vec<Row>) -> (Vec<Post>, Report) {
+    let mut seen: HashSet<_> = existing
+        .iter()
+        .map(|p| (p.created, p.text.clone()))
+        .collect();
+    let mut imported = Vec::new();
+    let mut report = Report {
+        imported: 0,
+        skipped: 0,
+        failed: Vec::new(),
+    };
+    for (i, row) in rows.into_iter().enumerate() {
+        let entry = i + 1;
+        let (new_post, created) = match row {
+            Row::Post(new_post, created) => (new_post, created),
+            Row::Skip => {
+                report.skipped += 1;
+                continue;
+            }
+            Row::Invalid(e) => {
+                report.failed.push((entry, e));
+                continue;
+            }
+        };
+        let mut post = match Post::try_from(new_post) {
+            Ok(post) => post,
+            Err(e) => {
+                report.failed.push((entry, e));
+                continue;
+            }
+        };
+        if let Some(created) = created {
+            post.created = created;
+        }
+        if !seen.insert((post.created, post.text.clone())) {
+            report.skipped += 1;
+            continue;
+        }
+        imported.push(post);
+    }
+    report.imported = imported.len();
+    (imported, report)
+}
+
+/// Works out what kind of archive `text` is, and reads its entries.
+fn read_archive(text: &str, user_id: Uuid) -> Result<Vec<Row>, Message> {
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    if let Some(tweets) = text.strip_prefix(TWITTER_PREFIX) {
+        let json = tweets.trim_start().trim_start_matches('=');
+        let tweets: Vec<Value> =
+            serde_json::from_str(json).map_err(|_| Message::new("import-unreadable"))?;
+        return Ok(tweets.iter().map(|t| twitter_row(t, user_id)).collect());
+    }
+    if !text.starts_with('{') {
+        return read_csv(text, user_id);
+    }
+    let json: Value = serde_json::from_str(text).map_err(|_| Message::new("import-unreadable"))?;
+    if let Some(items) = json["orderedItems"].as_array() {
+        return Ok(items.iter().map(|i| mastodon_row(i, user_id)).collect());
+    }
+    let archive: Archive =
+        serde_json::from_value(json).map_err(|_| Message::new("import-unknown-format"))?;
+    Ok(archive
+        .posts
+        .into_iter()
+        .map(|post| {
+            let new_post = NewPost {
+                text: post.text,
+                link: post.link.map(|l| l.to_string()),
+                user_id: user_id.to_string(),
+                visibility: post.visibility,
+            };
+            Row::Post(new_post, Some(post.created))
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct CsvRow {
+    text: String,
+    #[serde(default)]
+    link: Option<String>,
+    /// RFC 3339, like 2020-09-01T12:00:00Z.
+    #[serde(default)]
+    created: Option<String>,
+}
+
+fn read_csv(text: &str, user_id: Uuid) -> Result<Vec<Row>, Message> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|_| Message::new("import-unreadable"))?;
+    if !headers.iter().any(|h| h == "text") {
+        return Err(Message::new("import-unknown-format"));
+    }
+    Ok(reader
+        .deserialize()
+        .map(|row: Result<CsvRow, _>| {
+            let Ok(row) = row else {
+                return Row::Invalid(Message::new("import-bad-row"));
+            };
+            let created = match row.created.as_deref().map(DateTime::parse_from_rfc3339) {
+                Some(Ok(created)) => Some(created.with_timezone(&Utc)),
+                Some(Err(_)) => return Row::Invalid(Message::new("import-bad-date")),
+                None => None,
+            };
+            let new_post = NewPost {
+                text: row.text,
+                link: row.link.filter(|l| !l.is_empty()),
+                user_id: user_id.to_string(),
+                visibility: Visibility::Public,
+            };
+            Row::Post(new_post, created)
+        })
+        .collect())
+}
+
+/// An item from a Mastodon outbox. Boosts are `Announce`s of someone else's
+/// post, so only `Create`s are the user's own.
+fn mastodon_row(item: &Value, user_id: Uuid) -> Row {
+    if item["type"] != "Create" {
+        return Row::Skip;
+    }
+    let note = &item["object"];
+    let Some(content) = note["content"].as_str() else {
+        return Row::Invalid(Message::new("import-bad-row"));
+    };
+    let published = note["published"]
+        .as_str()
+        .or_else(|| item["published"].as_str());
+    let created = match published.map(DateTime::parse_from_rfc3339) {
+        Some(Ok(created)) => created.with_timezone(&Utc),
+        _ => return Row::Invalid(Message::new("import-bad-date")),
+    };
+    let new_post = NewPost {
+        text: html_to_text(content),
+        link: None,
+        user_id: user_id.to_string(),
+        visibility: mastodon_visibility(note),
+    };
+    Row::Post(new_post, Some(created))
+}
+
+/// Mastodon addresses public posts to everyone, unlisted ones to followers
+/// with everyone copied in, and the rest to followers or mentions only.
+/// Notes without addressing are taken to be public.
+fn mastodon_visibility(note: &Value) -> Visibility {
+    const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+    let addressed = |field: &str| {
+        note[field]
+            .as_array()
+            .map(|a| a.iter().any(|v| v == PUBLIC))
+    };
+    match (addressed("to"), addressed("cc")) {
+        (None, None) | (Some(true), _) => Visibility::Public,
+        (_, Some(true)) => Visibility::Unlisted,
+        _ => Visibility::FollowersOnly,
+    }
+}
+
+/// A tweet from a Twitter archive. Links in tweets are shortened to t.co
+/// addresses, so they're swapped back for where they really go.
+fn twitter_row(tweet: &Value, user_id: Uuid) -> Row {
+    let tweet = &tweet["tweet"];
+    let Some(mut text) = tweet["full_text"].as_str().map(str::to_owned) else {
+        return Row::Invalid(Message::new("import-bad-row"));
+    };
+    if text.starts_with("RT @") {
+        return Row::Skip;
+    }
+    let created_at = tweet["created_at"].as_str().unwrap_or_default();
+    let created = match DateTime::parse_from_str(created_at, "%a %b %d %H:%M:%S %z %Y") {
+        Ok(created) => created.with_timezone(&Utc),
+        Err(_) => return Row::Invalid(Message::new("import-bad-date")),
+    };
+    let urls = tweet["entities"]["urls"].as_array();
+    for url in urls.into_iter().flatten() {
+        if let (Some(short), Some(expanded)) = (url["url"].as_str(), url["expanded_url"].as_str()) {
+            text = text.replace(short, expanded);
+        }
+    }
+    let new_post = NewPost {
+        text: html_escape_decode(&text),
+        link: None,
+        user_id: user_id.to_string(),
+        visibility: Visibility::Public,
+    };
+    Row::Post(new_post, Some(created))
+}
+
+/// Mastodon stores posts as HTML. quiet posts are plain text, so keep the
+/// words and line breaks and drop the markup.
+fn html_to_text(html: &str) -> String {
+    let html = html
+        .replace("</p><p>", "\n\n")
+        .replace("<br>", "\n")
+        .replace("<br/>", "\n")
+        .replace("<br />", "\n");
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    html_escape_decode(text.trim())
+}
+
+/// Undoes the escaping HTML (and Twitter) applies to text.
+fn html_escape_decode(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn user_id() -> Uuid {
+        Uuid::parse_str("fc53b101-1756-4b8f-b5fe-b71d103e9f20").unwrap()
+    }
+
+    fn import(text: &str, existing: &[Post]) -> (Vec<Post>, Report) {
+        check_rows(existing, read_archive(text, user_id()).unwrap())
+    }
+
+    #[test]
+    fn csv_keeps_dates_and_reports_bad_rows() {
+        let csv = format!(
+            "text,link,created\n\
+             hello,,2019-05-01T10:00:00Z\n\
+             \"with, a link\",https://example.com/,2019-05-02T10:00:00+02:00\n\
+             bad date,,yesterday\n\
+             {},,\n\
+             no date,,\n",
+            "x".repeat(2000)
+        );
+        let (posts, report) = import(&csv, &[]);
+        assert_eq!(report.imported, 3);
+        let failed: Vec<_> = report.failed.iter().map(|(entry, _)| *entry).collect();
+        assert_eq!(failed, vec![3, 4]);
+        assert_eq!(posts[0].created, Utc.ymd(2019, 5, 1).and_hms(10, 0, 0));
+        assert_eq!(posts[1].text, "with, a link");
+        assert_eq!(
+            posts[1].link.as_ref().unwrap().as_str(),
+            "https://example.com/"
+        );
+        assert_eq!(posts[1].created, Utc.ymd(2019, 5, 2).and_hms(8, 0, 0));
+    }
+
+    #[test]
+    fn importing_twice_skips_what_is_already_there() {
+        let csv = "text,created\nonce,2019-05-01T10:00:00Z\n";
+        let (first, _) = import(csv, &[]);
+        let (second, report) = import(csv, &first);
+        assert!(second.is_empty());
+        assert_eq!(report.skipped, 1);
+    }
+
+    #[test]
+    fn mastodon_outboxes_keep_own_posts_as_text() {
+        let outbox = r#"{
+            "type": "OrderedCollection",
+            "orderedItems": [
+                {
+                    "type": "Create",
+                    "published": "2020-01-01T00:00:00Z",
+                    "object": {
+                        "type": "Note",
+                        "published": "2020-01-01T00:00:00Z",
+                        "content": "<p>fish &amp; chips</p><p>see <a href=\"https://example.com/\"><span class=\"invisible\">https://</span>example.com/</a></p>"
+                    }
+                },
+                { "type": "Announce", "object": "https://remote.example/notes/1" },
+                {
+                    "type": "Create",
+                    "object": {
+                        "type": "Note",
+                        "published": "2020-01-02T00:00:00Z",
+                        "content": "<p>quietly</p>",
+                        "to": ["https://mastodon.example/users/ada/followers"],
+                        "cc": ["https://www.w3.org/ns/activitystreams#Public"]
+                    }
+                }
+            ]
+        }"#;
+        let (posts, report) = import(outbox, &[]);
+        assert_eq!((report.imported, report.skipped), (2, 1));
+        assert_eq!(posts[0].text, "fish & chips\n\nsee https://example.com/");
+        assert_eq!(posts[0].created, Utc.ymd(2020, 1, 1).and_hms(0, 0, 0));
+        assert_eq!(posts[0].visibility, Visibility::Public);
+        assert_eq!(posts[1].visibility, Visibility::Unlisted);
+    }
+
+    #[test]
+    fn twitter_archives_expand_links_and_skip_retweets() {
+        let tweets = r#"window.YTD.tweets.part0 = [
+            { "tweet": {
+                "full_text": "reading https://t.co/abc &amp; thinking",
+                "created_at": "Wed Oct 10 20:19:24 +0000 2018",
+                "entities": { "urls": [
+                    { "url": "https://t.co/abc", "expanded_url": "https://example.com/essay" }
+                ] }
+            } },
+            { "tweet": {
+                "full_text": "RT @someone: not mine",
+                "created_at": "Wed Oct 10 21:00:00 +0000 2018"
+            } }
+        ]"#;
+        let (posts, report) = import(tweets, &[]);
+        assert_eq!((report.imported, report.skipped), (1, 1));
+        assert_eq!(
+            posts[0].text,
+            "reading https://example.com/essay & thinking"
+        );
+        assert_eq!(posts[0].created, Utc.ymd(2018, 10, 10).and_hms(20, 19, 24));
+    }
+
+    #[test]
+    fn quiet_exports_read_back_in() {
+        let export = r#"{
+            "version": 1,
+            "exported": "2020-09-01T12:00:00Z",
+            "profile": {
+                "id": "fc53b101-1756-4b8f-b5fe-b71d103e9f20",
+                "username": "ada",
+                "email": "ada@example.com",
+                "pic": "https://quiet.example/avatar/1/256",
+                "bio": "",
+                "date_joined": "2020-08-01T12:00:00Z",
+                "language": null,
+                "timezone": "UTC",
+                "locale": null
+            },
+            "posts": [
+                { "id": "6f4c8bd3-58a1-4d6c-9a0e-d4a4b1d1c2a1", "text": "hi", "link": null, "created": "2020-08-02T12:00:00Z" }
+            ]
+        }"#;
+        let (posts, report) = import(export, &[]);
+        assert_eq!(report.imported, 1);
+        assert_eq!(posts[0].created, Utc.ymd(2020, 8, 2).and_hms(12, 0, 0));
+        assert!(read_archive("{\"something\": \"else\"}", user_id()).is_err());
+    }
+}
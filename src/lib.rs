@@ -1,14 +1,28 @@
-#[macro_use]
-extern crate guard;
 extern crate cfg_if;
 extern crate wasm_bindgen;
 
+mod activitypub;
+mod api;
+mod archive;
+mod cache;
+mod cookies;
+mod email;
+mod feeds;
+mod flash;
+mod i18n;
+mod import;
 mod models;
+mod moderation;
+mod policy;
+mod ratelimit;
+mod sessions;
 mod templates;
 mod twoface;
+mod uploads;
 mod utils;
 mod view;
 
+use crate::i18n::Message;
 use crate::utils::*;
 use cfg_if::cfg_if;
 use futures::FutureExt;
@@ -16,6 +30,7 @@ use http::StatusCode;
 use js_sys::Promise;
 use std::future::Future;
 use url::Url;
+use uuid::Uuid;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::future_to_promise as ftp;
 use web_sys::{FetchEvent, Response};
@@ -32,6 +47,7 @@ cfg_if! {
 
 #[wasm_bindgen]
 pub fn main(event: FetchEvent) -> Promise {
+    set_panic_hook();
     let req = event.request();
     let url = match Url::parse(&req.url()).ok_or_js_err() {
         Ok(v) => v,
@@ -39,15 +55,14 @@ pub fn main(event: FetchEvent) -> Promise {
     };
     let path = url.path().to_lowercase();
     let method = req.method().to_lowercase();
+    let lang = i18n::request_language(&req, None);
     let render_404 = || {
         let err = twoface::Error {
             internal: format!("method {} not allowed for {}", method, url),
             status: StatusCode::NOT_FOUND,
-            external: twoface::External {
-                msg: "Page not found".to_owned(),
-            },
+            external: Message::new("error-page-not-found"),
         };
-        view::render_error(err)
+        view::render_error(err, &lang)
     };
 
     // Route the request to a handler function
@@ -56,11 +71,109 @@ pub fn main(event: FetchEvent) -> Promise {
             "get" => ftp(view::render_home(req)),
             _ => render_404(),
         },
-        Some("post") => match method.as_ref() {
-            "post" => api_result_to_promise(models::posts::new_post(req)),
-            "get" => ftp(view::render_new_post(req)),
+        Some("post") => match (
+            method.as_ref(),
+            path.split("/").nth(2).map(Uuid::parse_str),
+            path.split("/").nth(3),
+        ) {
+            ("post", None, None) => api_result_to_promise(models::posts::new_post(req)),
+            ("get", None, None) => ftp(view::render_new_post(req)),
+            ("post", Some(Ok(post_id)), Some("report")) => {
+                api_result_to_promise(moderation::report_post(req, post_id))
+            }
             _ => render_404(),
         },
+        Some("moderation") => match (method.as_ref(), path.split("/").nth(2)) {
+            ("get", None) => ftp(view::render_moderation(req)),
+            ("post", Some(action)) => {
+                api_result_to_promise(moderation::act(req, action.to_owned()))
+            }
+            _ => render_404(),
+        },
+        Some("sign-in") => match (method.as_ref(), path.split("/").nth(2)) {
+            ("get", None) => ftp(view::render_sign_in(req)),
+            ("post", None) => api_result_to_promise(sessions::send_sign_in_link(req)),
+            ("get", Some("confirm")) => ftp(view::confirm_sign_in(req)),
+            _ => render_404(),
+        },
+        Some("sign-out") => match method.as_ref() {
+            "post" => ftp(view::sign_out(req)),
+            _ => render_404(),
+        },
+        Some("settings") => match (method.as_ref(), path.split("/").nth(2)) {
+            ("post", None) => api_result_to_promise(models::users::save_settings(req)),
+            ("get", None) => ftp(view::render_settings(req)),
+            ("get", Some("verify-email")) => ftp(view::confirm_email(req)),
+            ("post", Some("avatar")) => api_result_to_promise(models::users::save_avatar(req)),
+            ("post", Some("delete")) => api_result_to_promise(models::users::delete_account(req)),
+            ("get", Some("export")) => api_result_to_promise(archive::render_export(req)),
+            ("post", Some("import")) => api_result_to_promise(import::import(req)),
+            ("post", Some("blocks")) => api_result_to_promise(models::blocks::save_change(req)),
+            ("post", Some("follow-requests")) => {
+                api_result_to_promise(activitypub::answer_follow_request(req))
+            }
+            _ => render_404(),
+        },
+        Some("avatar") => {
+            let mut segments = path.split("/").skip(2);
+            match (
+                method.as_ref(),
+                segments.next().map(Uuid::parse_str),
+                segments.next(),
+            ) {
+                ("get", Some(Ok(user_id)), Some(size)) => ftp(models::avatars::render_avatar(
+                    req,
+                    user_id,
+                    size.to_owned(),
+                )),
+                _ => render_404(),
+            }
+        }
+        Some("api") => api_result_to_promise(api::handle(req, path, method)),
+        Some(".well-known") => match (method.as_ref(), path.split("/").nth(2)) {
+            ("get", Some("webfinger")) => api_result_to_promise(activitypub::render_webfinger(req)),
+            _ => render_404(),
+        },
+        Some("user") => {
+            let mut segments = path.split("/").skip(2);
+            let user_id = segments.next().map(Uuid::parse_str);
+            let rest: Vec<_> = segments.collect();
+            match (method.as_ref(), user_id, rest.as_slice()) {
+                ("get", Some(Ok(user_id)), []) => {
+                    if activitypub::wants_activity_json(&req) {
+                        api_result_to_promise(activitypub::render_actor(req, user_id))
+                    } else {
+                        ftp(view::render_profile(req, user_id))
+                    }
+                }
+                ("get", Some(Ok(user_id)), ["outbox"]) => {
+                    api_result_to_promise(activitypub::render_outbox(req, user_id))
+                }
+                ("get", Some(Ok(user_id)), ["followers"]) => {
+                    api_result_to_promise(activitypub::render_followers(req, user_id))
+                }
+                ("post", Some(Ok(user_id)), ["inbox"]) => {
+                    api_result_to_promise(activitypub::receive_inbox(req, user_id))
+                }
+                ("post", Some(Ok(user_id)), ["follow"]) => {
+                    api_result_to_promise(models::followers::save_follow(req, user_id, true))
+                }
+                ("post", Some(Ok(user_id)), ["unfollow"]) => {
+                    api_result_to_promise(models::followers::save_follow(req, user_id, false))
+                }
+                ("get", Some(Ok(user_id)), ["posts", post_id]) => match Uuid::parse_str(post_id) {
+                    Ok(post_id) => {
+                        api_result_to_promise(activitypub::render_note(req, user_id, post_id))
+                    }
+                    Err(_) => render_404(),
+                },
+                ("get", Some(Ok(user_id)), [file]) => match feeds::Format::from_file_name(file) {
+                    Some(format) => ftp(feeds::render_feed(req, user_id, format)),
+                    None => render_404(),
+                },
+                _ => render_404(),
+            }
+        }
         _ => render_404(),
     }
 }
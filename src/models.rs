@@ -1,2 +1,11 @@
+pub mod avatars;
+pub mod blocks;
+pub mod followers;
 pub mod posts;
+pub mod reports;
+#[cfg(any(test, feature = "sql"))]
+pub mod sql;
+pub mod storage;
+#[cfg(any(test, feature = "durable-timelines"))]
+pub mod timelines;
 pub mod users;
@@ -0,0 +1,225 @@
+//! Profile pictures people upload. They're resized to a few fixed sizes and
+//! served from `/avatar/<user_id>/<size>`, so pages don't load pictures
+//! from other sites.
+use crate::i18n::{self, Message};
+use crate::twoface::*;
+use crate::uploads;
+use crate::utils::*;
+use crate::view::generate_error_response;
+use http::StatusCode;
+use image::imageops::FilterType;
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, Rgb, RgbImage};
+use js_sys::Promise;
+use std::io::Cursor;
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Request, Response, ResponseInit};
+
+/// Uploads bigger than this are rejected before they're read.
+const MAX_UPLOAD_MB: u32 = 2;
+
+/// Pictures wider or taller than this are rejected before they're decoded,
+/// so a small file can't expand into an enormous image. Decoded, the
+/// biggest allowed picture takes 4 MB, and it's still four times the
+/// largest size stored.
+const MAX_DIMENSION: u32 = 1024;
+
+/// The sizes avatars are stored in, in pixels square, largest first.
+pub const SIZES: [u32; 3] = [256, 128, 48];
+
+const ACCEPTED_TYPES: [(&str, ImageFormat); 3] = [
+    ("image/png", ImageFormat::Png),
+    ("image/jpeg", ImageFormat::Jpeg),
+    ("image/gif", ImageFormat::Gif),
+];
+
+/// Checks `bytes` really are an image of `content_type`, and makes a
+/// square PNG of it in each of `SIZES`. Re-encoding also drops metadata
+/// like where a photo was taken.
+pub fn resize(content_type: &str, bytes: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Message> {
+    let claimed = ACCEPTED_TYPES
+        .iter()
+        .find(|(t, _)| *t == content_type)
+        .map(|(_, f)| *f);
+    let Some(format) = claimed else {
+        return Err(Message::new("avatar-wrong-type"));
+    };
+    let unreadable = |_| Message::new("avatar-unreadable");
+    if image::guess_format(bytes).ok() != Some(format) {
+        return Err(Message::new("avatar-unreadable"));
+    }
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .map_err(unreadable)?;
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(Message::new("avatar-too-many-pixels").arg("max", MAX_DIMENSION));
+    }
+    let mut image = image::load_from_memory_with_format(bytes, format).map_err(unreadable)?;
+    let mut resized = Vec::new();
+    for &size in SIZES.iter() {
+        // Each size is made from the one before, which is much less work
+        // than starting from the original every time.
+        image = image.resize_to_fill(size, size, FilterType::Triangle);
+        let mut png = Vec::new();
+        image
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(unreadable)?;
+        resized.push((size, png));
+    }
+    Ok(resized)
+}
+
+/// Saves the picture uploaded in the `avatar` field of the multipart form
+/// in `req` as the user's avatar. Returns the URL of its largest size.
+pub async fn save_upload(req: &Request, user_id: Uuid) -> Fallible<Url> {
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        external: Message::new("error-invalid-url"),
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    let upload = uploads::read(req, "avatar", MAX_UPLOAD_MB).await?;
+    let resized = resize(&upload.content_type, &upload.bytes).map_err(|e| Error {
+        internal: e.to_string(),
+        external: e,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    for (size, png) in &resized {
+        JsFuture::from(AvatarsNs::put(&key(user_id, *size), png))
+            .await
+            .map_err(|e| Error {
+                internal: format!("{:?}", e),
+                external: Message::new("error-save-avatar"),
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+    }
+    Ok(url
+        .join(&format!("/avatar/{}/{}", user_id, SIZES[0]))
+        .unwrap())
+}
+
+/// A plain grey square, for users who haven't uploaded a picture.
+fn placeholder(size: u32) -> Vec<u8> {
+    let image = RgbImage::from_pixel(size, size, Rgb([0xcc, 0xcc, 0xcc]));
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(image)
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .expect("PNGs can always be encoded");
+    png
+}
+
+fn key(user_id: Uuid, size: u32) -> String {
+    format!("{}/{}", user_id, size)
+}
+
+pub async fn avatar(user_id: Uuid, size: u32) -> Fallible<Option<Vec<u8>>> {
+    let promise = AvatarsNs::get(&key(user_id, size), "arrayBuffer");
+    let val = JsFuture::from(promise).await.map_err(|e| Error {
+        internal: format!("{:?}", e),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-avatar"),
+    })?;
+    if val.is_null() || val.is_undefined() {
+        return Ok(None);
+    }
+    Ok(Some(js_sys::Uint8Array::new(&val).to_vec()))
+}
+
+/// Deletes every size of the user's avatar, if they uploaded one.
+pub async fn delete_avatar(user_id: Uuid) -> Fallible<()> {
+    for &size in SIZES.iter() {
+        JsFuture::from(AvatarsNs::delete(&key(user_id, size)))
+            .await
+            .map_err(|e| Error {
+                internal: format!("{:?}", e),
+                external: Message::new("error-delete-account"),
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+    }
+    Ok(())
+}
+
+/// `GET /avatar/<user_id>/<size>`
+pub async fn render_avatar(req: Request, user_id: Uuid, size: String) -> JsResult {
+    let lang = i18n::request_language(&req, None);
+    let not_found = || Error {
+        internal: format!("no {} avatar for {}", size, user_id),
+        external: Message::new("error-not-found"),
+        status: StatusCode::NOT_FOUND,
+    };
+    let size = size.parse().ok().filter(|s| SIZES.contains(s));
+    let Some(size) = size else {
+        return generate_error_response(not_found(), &lang);
+    };
+    let mut png = match avatar(user_id, size).await {
+        Ok(Some(png)) => png,
+        Ok(None) => placeholder(size),
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    let headers = Headers::new()?;
+    headers.append("content-type", "image/png")?;
+    // The URL stays the same when the picture changes, so don't cache for long.
+    headers.append("cache-control", "public, max-age=3600")?;
+    let init = ResponseInit::new();
+    init.set_status(StatusCode::OK.as_u16());
+    init.set_headers(&JsValue::from(headers));
+    let resp = Response::new_with_opt_u8_array_and_init(Some(&mut png), &init)?;
+    Ok(JsValue::from(resp))
+}
+
+#[wasm_bindgen]
+extern "C" {
+    type AvatarsNs;
+
+    #[wasm_bindgen(static_method_of = AvatarsNs)]
+    fn get(key: &str, data_type: &str) -> Promise;
+
+    #[wasm_bindgen(static_method_of = AvatarsNs)]
+    fn put(key: &str, val: &[u8]) -> Promise;
+
+    #[wasm_bindgen(static_method_of = AvatarsNs)]
+    fn delete(key: &str) -> Promise;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        let mut bytes = Vec::new();
+        image.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn uploads_are_cropped_square_in_every_size() {
+        let resized = resize("image/png", &png(600, 300)).unwrap();
+        let sizes: Vec<_> = resized.iter().map(|(s, _)| *s).collect();
+        assert_eq!(sizes, SIZES);
+        for (size, bytes) in resized {
+            let image = image::load_from_memory_with_format(&bytes, ImageFormat::Png).unwrap();
+            assert_eq!(image.dimensions(), (size, size));
+        }
+    }
+
+    #[test]
+    fn uploads_must_be_what_they_claim() {
+        assert!(resize("image/jpeg", &png(10, 10)).is_err());
+        assert!(resize("image/svg+xml", b"<svg/>").is_err());
+        assert!(resize("image/png", b"not a png").is_err());
+        assert!(resize("image/png", &png(MAX_DIMENSION + 1, 1)).is_err());
+    }
+
+    #[test]
+    fn users_without_a_picture_get_a_placeholder() {
+        for &size in SIZES.iter() {
+            let image =
+                image::load_from_memory_with_format(&placeholder(size), ImageFormat::Png).unwrap();
+            assert_eq!(image.dimensions(), (size, size));
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Who each user has blocked or muted. Blocking someone stops them following
+//! the user, and keeps each of them from seeing the other. Muting someone just
+//! keeps their posts out of the user's feed, without telling them.
+//!
+//! Lists are kept in KV under the user's ID, whichever backend posts use.
+use crate::cache;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::followers;
+use crate::models::storage::{self, Schema, Store, UpdateError, Versioned};
+use crate::models::users;
+use crate::twoface::*;
+use crate::utils::*;
+use futures::future::{FutureExt, LocalBoxFuture};
+use http::StatusCode;
+use js_sys::Promise;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use url::Url;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Request, Response};
+
+/// Someone a user can block or mute.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Account {
+    /// A user on this server.
+    Local(Uuid),
+    /// Someone on another server, by their ActivityPub actor ID.
+    Remote(Url),
+}
+
+impl Account {
+    /// Reads a quiet user's ID, or a remote actor's URL.
+    pub fn parse(s: &str) -> Result<Self, Message> {
+        let s = s.trim();
+        if let Ok(id) = Uuid::parse_str(s) {
+            return Ok(Account::Local(id));
+        }
+        match Url::parse(s) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {
+                Ok(Account::Remote(url))
+            }
+            _ => Err(Message::new("error-invalid-account").arg("account", s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Account::Local(id) => write!(f, "{}", id),
+            Account::Remote(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Blocks {
+    pub blocked: Vec<Account>,
+    pub muted: Vec<Account>,
+}
+
+/// Blocks were first stored with versions.
+impl Schema for Blocks {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("no migration from blocks version {}", version))
+    }
+}
+
+/// What a user can do about someone.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Block,
+    Unblock,
+    Mute,
+    Unmute,
+}
+
+impl Change {
+    fn flash(self) -> &'static str {
+        match self {
+            Change::Block => "flash-blocked",
+            Change::Unblock => "flash-unblocked",
+            Change::Mute => "flash-muted",
+            Change::Unmute => "flash-unmuted",
+        }
+    }
+}
+
+impl Blocks {
+    pub fn blocks(&self, account: &Account) -> bool {
+        self.blocked.contains(account)
+    }
+
+    /// Whether `account`'s posts are kept out of the user's feed. Blocking
+    /// someone mutes them too.
+    pub fn mutes(&self, account: &Account) -> bool {
+        self.blocks(account) || self.muted.contains(account)
+    }
+
+    /// Doing something twice is the same as doing it once.
+    pub fn apply(&mut self, change: Change, account: Account) {
+        let list = match change {
+            Change::Block | Change::Unblock => &mut self.blocked,
+            Change::Mute | Change::Unmute => &mut self.muted,
+        };
+        list.retain(|a| a != &account);
+        if let Change::Block | Change::Mute = change {
+            list.push(account);
+        }
+    }
+}
+
+/// What the settings and profile pages send to block or mute someone.
+#[derive(Deserialize)]
+struct BlockRequest {
+    account: String,
+    change: Change,
+}
+
+/// `POST /settings/blocks`
+pub async fn save_change(req: Request) -> Result<Response, Response> {
+    let viewer = users::viewer(&req).await.ok().flatten();
+    let lang = i18n::request_language(&req, viewer.as_ref());
+    let change = async {
+        let viewer = users::signed_in(&req).await?;
+        let body: BlockRequest = users::read_json(&req, "error-malformed-block").await?;
+        let account = Account::parse(&body.account).map_err(|e| Error {
+            internal: e.to_string(),
+            external: e,
+            status: StatusCode::BAD_REQUEST,
+        })?;
+        if account == Account::Local(viewer.id) {
+            return Err(Error {
+                internal: format!("{} tried to block or mute themself", viewer.id),
+                external: Message::new("error-block-self"),
+                status: StatusCode::BAD_REQUEST,
+            });
+        }
+        change_blocks(viewer.id, body.change, account).await?;
+        Ok(body.change)
+    };
+    let change = change.await.map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("blocks saved", None);
+    flash::set(&resp, &Message::new(change.flash()).localize(&lang));
+    Ok(resp)
+}
+
+/// Blocks, unblocks, mutes or unmutes someone for the user. Blocking
+/// someone stops them following the user, and stops a quiet user being
+/// followed by them.
+pub async fn change_blocks(user_id: Uuid, change: Change, account: Account) -> Fallible<()> {
+    let apply = |blocks: &mut Blocks| blocks.apply(change, account.clone());
+    storage::update(&BlocksKv, &user_id.to_string(), apply)
+        .await
+        .map_err(|e| match e {
+            UpdateError::Conflict => Error {
+                internal: format!("gave up saving {}'s blocks after conflicts", user_id),
+                status: StatusCode::CONFLICT,
+                external: Message::new("error-busy"),
+            },
+            UpdateError::Failed(e) => Error {
+                internal: e,
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-save-blocks"),
+            },
+        })?;
+    // Pages the user sees may look different now.
+    cache::invalidate(user_id).await;
+    match (change, account) {
+        (Change::Block, Account::Remote(actor)) => {
+            followers::remove_follower(user_id, &actor).await?;
+            followers::remove_follow_request(user_id, &actor).await?;
+        }
+        (Change::Block, Account::Local(other)) => {
+            followers::unfollow(user_id, other).await?;
+            followers::unfollow(other, user_id).await?;
+            cache::invalidate(other).await;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether either of two quiet users has blocked the other, so neither
+/// should see the other's profile or posts.
+pub async fn either_blocks(a: Uuid, b: Uuid) -> Fallible<bool> {
+    if a == b {
+        return Ok(false);
+    }
+    Ok(blocks_of(a).await?.blocks(&Account::Local(b))
+        || blocks_of(b).await?.blocks(&Account::Local(a)))
+}
+
+/// The error for a profile or post hidden by a block. It reads the same to
+/// whoever did the blocking and whoever was blocked.
+pub fn blocked_error(viewer_id: Uuid, user_id: Uuid) -> Error {
+    Error {
+        internal: format!("{} and {} are blocked", viewer_id, user_id),
+        external: Message::new("error-blocked"),
+        status: StatusCode::FORBIDDEN,
+    }
+}
+
+pub async fn blocks_of(user_id: Uuid) -> Fallible<Blocks> {
+    let blocks = match BlocksKv.get(&user_id.to_string()).await {
+        Ok(Some(stored)) => storage::decode(&stored.bytes),
+        Ok(None) => Ok(Blocks::default()),
+        Err(e) => Err(e),
+    };
+    blocks.map_err(|e| Error {
+        internal: e,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-blocks"),
+    })
+}
+
+/// Forgets who the user has blocked and muted.
+pub async fn delete_blocks(user_id: Uuid) -> Fallible<()> {
+    BlocksKv
+        .delete(&user_id.to_string())
+        .await
+        .map_err(|e| Error {
+            internal: e,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            external: Message::new("error-delete-account"),
+        })
+}
+
+/// Blocks are kept in Workers KV, which like `PostsKv` can only check a
+/// value hasn't changed just before saving over it.
+struct BlocksKv;
+
+impl Store for BlocksKv {
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+        async move {
+            let val = JsFuture::from(BlocksNs::get(key, "arrayBuffer"))
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            if val.is_null() || val.is_undefined() {
+                return Ok(None);
+            }
+            let bytes = js_sys::Uint8Array::new(&val).to_vec();
+            Ok(Some(Versioned::new(bytes)))
+        }
+        .boxed_local()
+    }
+
+    fn put_if_match<'a>(
+        &'a self,
+        key: &'a str,
+        bytes: Vec<u8>,
+        etag: Option<&'a str>,
+    ) -> LocalBoxFuture<'a, Result<bool, String>> {
+        async move {
+            let current = self.get(key).await?;
+            if current.as_ref().map(|v| v.etag.as_str()) != etag {
+                return Ok(false);
+            }
+            JsFuture::from(BlocksNs::put(key, &bytes))
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            Ok(true)
+        }
+        .boxed_local()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            JsFuture::from(BlocksNs::delete(key))
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    type BlocksNs;
+
+    #[wasm_bindgen(static_method_of = BlocksNs)]
+    fn get(key: &str, data_type: &str) -> Promise;
+
+    #[wasm_bindgen(static_method_of = BlocksNs)]
+    fn put(key: &str, val: &[u8]) -> Promise;
+
+    #[wasm_bindgen(static_method_of = BlocksNs)]
+    fn delete(key: &str) -> Promise;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future;
+
+    const REMOTE: &str = "https://remote.example/users/bob";
+
+    #[test]
+    fn accounts_are_ids_or_actor_urls() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            Account::parse(&format!(" {} ", id)).unwrap(),
+            Account::Local(id)
+        );
+        let remote = Account::parse(REMOTE).unwrap();
+        assert_eq!(remote, Account::Remote(Url::parse(REMOTE).unwrap()));
+        assert_eq!(remote.to_string(), REMOTE);
+        assert!(Account::parse("bob").is_err());
+        assert!(Account::parse("mailto:bob@remote.example").is_err());
+    }
+
+    #[test]
+    fn blocking_mutes_and_changes_are_idempotent() {
+        let bob = Account::parse(REMOTE).unwrap();
+        let carol = Account::Local(Uuid::new_v4());
+        let mut blocks = Blocks::default();
+        blocks.apply(Change::Block, bob.clone());
+        blocks.apply(Change::Block, bob.clone());
+        blocks.apply(Change::Mute, carol.clone());
+        assert_eq!(blocks.blocked, vec![bob.clone()]);
+        assert!(blocks.mutes(&bob));
+        assert!(blocks.mutes(&carol));
+        assert!(!blocks.blocks(&carol));
+
+        blocks.apply(Change::Unblock, bob.clone());
+        blocks.apply(Change::Unmute, carol.clone());
+        assert_eq!(blocks, Blocks::default());
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let blocks = Blocks {
+            blocked: vec![Account::parse(REMOTE).unwrap()],
+            muted: vec![Account::Local(Uuid::new_v4())],
+        };
+        let bytes = storage::encode(&blocks).unwrap();
+        assert_eq!(storage::decode::<Blocks>(&bytes).unwrap(), blocks);
+    }
+
+    #[test]
+    fn blocking_two_people_at_once_blocks_both() {
+        let store = storage::memory::MemoryStore::default();
+        let bob = Account::parse(REMOTE).unwrap();
+        let carol = Account::Local(Uuid::new_v4());
+        let block = |account: &Account| {
+            let account = account.clone();
+            storage::update(&store, "ada", move |blocks: &mut Blocks| {
+                blocks.apply(Change::Block, account.clone())
+            })
+        };
+        let (a, b) = block_on(future::join(block(&bob), block(&carol)));
+        a.unwrap();
+        b.unwrap();
+        let stored = block_on(store.get("ada")).unwrap().unwrap();
+        let blocks: Blocks = storage::decode(&stored.bytes).unwrap();
+        assert!(blocks.blocks(&bob) && blocks.blocks(&carol));
+        assert!(store.conflicts.get() > 0);
+    }
+}
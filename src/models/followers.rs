@@ -0,0 +1,404 @@
+use crate::cache;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::blocks;
+#[cfg(feature = "sql")]
+use crate::models::sql;
+use crate::models::storage::{self, Schema};
+use crate::models::users;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+use web_sys::{Request, Response};
+#[cfg(not(feature = "sql"))]
+use {
+    crate::models::storage::{Store, UpdateError},
+    kv::FollowersKv,
+};
+
+/// Someone on another server who follows a quiet user, e.g. from Mastodon.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Follower {
+    /// The follower's ActivityPub actor ID.
+    pub actor: Url,
+    /// Where to deliver the user's new posts to this follower. Often a shared
+    /// inbox for the follower's whole server.
+    pub inbox: Url,
+}
+
+/// Someone asking to follow a private user, waiting for them to decide.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FollowRequest {
+    pub actor: Url,
+    pub inbox: Url,
+    /// The Follow activity, as JSON. Accepting or rejecting it quotes it.
+    pub activity: String,
+    pub created: DateTime<Utc>,
+}
+
+impl FollowRequest {
+    pub fn follower(&self) -> Follower {
+        Follower {
+            actor: self.actor.clone(),
+            inbox: self.inbox.clone(),
+        }
+    }
+}
+
+/// Follow requests were first stored with versions.
+impl Schema for Vec<FollowRequest> {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!(
+            "no migration from follow requests version {}",
+            version
+        ))
+    }
+}
+
+/// Who a quiet user follows on quiet. Following lists were first stored
+/// with versions.
+impl Schema for Vec<Uuid> {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("no migration from following version {}", version))
+    }
+}
+
+/// Followers haven't changed shape since they were first stored.
+impl Schema for Vec<Follower> {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => storage::decode_payload(payload),
+            _ => Err(format!("no migration from followers version {}", version)),
+        }
+    }
+}
+
+/// `POST /user/<id>/follow` and `/user/<id>/unfollow`, from the buttons on
+/// profiles. Only public users can be followed on quiet; private users
+/// approve their followers, who are all on other servers.
+pub async fn save_follow(req: Request, user_id: Uuid, follow: bool) -> Result<Response, Response> {
+    let viewer = users::viewer(&req).await.ok().flatten();
+    let lang = i18n::request_language(&req, viewer.as_ref());
+    let change = async {
+        let viewer = users::signed_in(&req).await?;
+        if !follow {
+            return unfollow(viewer.id, user_id).await;
+        }
+        if viewer.id == user_id {
+            return Err(Error {
+                internal: format!("{} tried to follow themself", viewer.id),
+                external: Message::new("error-follow-self"),
+                status: StatusCode::BAD_REQUEST,
+            });
+        }
+        let Some(profile) = users::profile_by_id(user_id).await? else {
+            return Err(Error {
+                internal: format!("no profile {} to follow", user_id),
+                external: Message::new("error-not-found"),
+                status: StatusCode::NOT_FOUND,
+            });
+        };
+        if blocks::either_blocks(viewer.id, user_id).await? {
+            return Err(blocks::blocked_error(viewer.id, user_id));
+        }
+        if profile.is_private {
+            return Err(Error {
+                internal: format!("{} tried to follow private user {}", viewer.id, user_id),
+                external: Message::new("error-follow-private"),
+                status: StatusCode::FORBIDDEN,
+            });
+        }
+        self::follow(viewer.id, user_id).await
+    };
+    change.await.map_err(|e| e.localized_response(&lang))?;
+    // Their feed looks different now.
+    if let Some(viewer) = &viewer {
+        cache::invalidate(viewer.id).await;
+    }
+    let resp = success_response("follows saved", None);
+    let flash_id = if follow {
+        "flash-followed"
+    } else {
+        "flash-unfollowed"
+    };
+    flash::set(&resp, &Message::new(flash_id).localize(&lang));
+    Ok(resp)
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn followers_of(user_id: Uuid) -> Fallible<Vec<Follower>> {
+    load(&user_id.to_string()).await
+}
+
+/// Records that `follower` follows the user. Following twice is the same as following once.
+#[cfg(not(feature = "sql"))]
+pub async fn add_follower(user_id: Uuid, follower: Follower) -> Fallible<()> {
+    update(&user_id.to_string(), |followers: &mut Vec<Follower>| {
+        followers.retain(|f| f.actor != follower.actor);
+        followers.push(follower.clone());
+    })
+    .await
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn remove_follower(user_id: Uuid, actor: &Url) -> Fallible<()> {
+    update(&user_id.to_string(), |followers: &mut Vec<Follower>| {
+        followers.retain(|f| &f.actor != actor)
+    })
+    .await
+}
+
+/// Forgets everyone who follows the user, everyone asking to, and who
+/// they follow.
+#[cfg(not(feature = "sql"))]
+pub async fn delete_followers(user_id: Uuid) -> Fallible<()> {
+    let keys = [
+        user_id.to_string(),
+        requests_key(user_id),
+        following_key(user_id),
+    ];
+    for key in &keys {
+        FollowersKv.delete(key).await.map_err(|e| Error {
+            internal: e,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            external: Message::new("error-delete-account"),
+        })?;
+    }
+    Ok(())
+}
+
+/// Follow requests are kept beside the followers, under their own key.
+#[cfg(not(feature = "sql"))]
+fn requests_key(user_id: Uuid) -> String {
+    format!("requests:{}", user_id)
+}
+
+/// The follow requests waiting for the user to decide, oldest first.
+#[cfg(not(feature = "sql"))]
+pub async fn follow_requests_of(user_id: Uuid) -> Fallible<Vec<FollowRequest>> {
+    load(&requests_key(user_id)).await
+}
+
+/// Asking twice replaces the first request.
+#[cfg(not(feature = "sql"))]
+pub async fn add_follow_request(user_id: Uuid, request: FollowRequest) -> Fallible<()> {
+    update(
+        &requests_key(user_id),
+        |requests: &mut Vec<FollowRequest>| {
+            requests.retain(|r| r.actor != request.actor);
+            requests.push(request.clone());
+        },
+    )
+    .await
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn remove_follow_request(user_id: Uuid, actor: &Url) -> Fallible<()> {
+    update(
+        &requests_key(user_id),
+        |requests: &mut Vec<FollowRequest>| requests.retain(|r| &r.actor != actor),
+    )
+    .await
+}
+
+/// Who a user follows on quiet is kept beside their followers too. It's
+/// only looked up by who's following, so nothing lists a user's followers
+/// on quiet.
+#[cfg(not(feature = "sql"))]
+fn following_key(user_id: Uuid) -> String {
+    format!("following:{}", user_id)
+}
+
+/// The quiet users `user_id` follows, in the order they followed them.
+#[cfg(not(feature = "sql"))]
+pub async fn following_of(user_id: Uuid) -> Fallible<Vec<Uuid>> {
+    load(&following_key(user_id)).await
+}
+
+/// Records that `user_id` follows `followed`, another quiet user.
+/// Following twice is the same as following once.
+#[cfg(not(feature = "sql"))]
+pub async fn follow(user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    update(&following_key(user_id), |following: &mut Vec<Uuid>| {
+        following.retain(|id| *id != followed);
+        following.push(followed);
+    })
+    .await
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn unfollow(user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    update(&following_key(user_id), |following: &mut Vec<Uuid>| {
+        following.retain(|id| *id != followed)
+    })
+    .await
+}
+
+#[cfg(not(feature = "sql"))]
+async fn load<T: Schema + Default>(key: &str) -> Fallible<T> {
+    let stored = match FollowersKv.get(key).await {
+        Ok(Some(stored)) => storage::decode(&stored.bytes),
+        Ok(None) => Ok(T::default()),
+        Err(e) => Err(e),
+    };
+    stored.map_err(|e| Error {
+        internal: e,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-followers"),
+    })
+}
+
+#[cfg(not(feature = "sql"))]
+async fn update<T: Schema + Default>(key: &str, change: impl FnMut(&mut T)) -> Fallible<()> {
+    storage::update(&FollowersKv, key, change)
+        .await
+        .map_err(|e| match e {
+            UpdateError::Conflict => Error {
+                internal: format!("gave up saving followers {} after conflicts", key),
+                status: StatusCode::CONFLICT,
+                external: Message::new("error-busy"),
+            },
+            UpdateError::Failed(e) => Error {
+                internal: e,
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-save-followers"),
+            },
+        })?;
+    Ok(())
+}
+
+#[cfg(feature = "sql")]
+pub async fn followers_of(user_id: Uuid) -> Fallible<Vec<Follower>> {
+    sql::followers_of(&sql::database().await?, user_id).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn add_follower(user_id: Uuid, follower: Follower) -> Fallible<()> {
+    sql::add_follower(&sql::database().await?, user_id, &follower).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn remove_follower(user_id: Uuid, actor: &Url) -> Fallible<()> {
+    sql::remove_follower(&sql::database().await?, user_id, actor).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn delete_followers(user_id: Uuid) -> Fallible<()> {
+    sql::delete_followers(&sql::database().await?, user_id).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn follow_requests_of(user_id: Uuid) -> Fallible<Vec<FollowRequest>> {
+    sql::follow_requests_of(&sql::database().await?, user_id).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn add_follow_request(user_id: Uuid, request: FollowRequest) -> Fallible<()> {
+    sql::add_follow_request(&sql::database().await?, user_id, &request).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn remove_follow_request(user_id: Uuid, actor: &Url) -> Fallible<()> {
+    sql::remove_follow_request(&sql::database().await?, user_id, actor).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn following_of(user_id: Uuid) -> Fallible<Vec<Uuid>> {
+    sql::following_of(&sql::database().await?, user_id).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn follow(user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    sql::follow(&sql::database().await?, user_id, followed).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn unfollow(user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    sql::unfollow(&sql::database().await?, user_id, followed).await
+}
+
+#[cfg(not(feature = "sql"))]
+mod kv {
+    use crate::models::storage::{Store, Versioned};
+    use futures::future::{FutureExt, LocalBoxFuture};
+    use js_sys::Promise;
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_futures::JsFuture;
+
+    /// Followers and follow requests are kept in Workers KV, which like
+    /// `PostsKv` can only check a value hasn't changed just before saving
+    /// over it.
+    pub struct FollowersKv;
+
+    impl Store for FollowersKv {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            async move {
+                let val = JsFuture::from(FollowersNs::get(key, "arrayBuffer"))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                if val.is_null() || val.is_undefined() {
+                    return Ok(None);
+                }
+                let bytes = js_sys::Uint8Array::new(&val).to_vec();
+                Ok(Some(Versioned::new(bytes)))
+            }
+            .boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            async move {
+                let current = self.get(key).await?;
+                if current.as_ref().map(|v| v.etag.as_str()) != etag {
+                    return Ok(false);
+                }
+                JsFuture::from(FollowersNs::put(key, &bytes))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(true)
+            }
+            .boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            async move {
+                JsFuture::from(FollowersNs::delete(key))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(())
+            }
+            .boxed_local()
+        }
+    }
+
+    #[wasm_bindgen]
+    extern "C" {
+        type FollowersNs;
+
+        #[wasm_bindgen(static_method_of = FollowersNs)]
+        fn get(key: &str, data_type: &str) -> Promise;
+
+        #[wasm_bindgen(static_method_of = FollowersNs)]
+        fn put(key: &str, val: &[u8]) -> Promise;
+
+        #[wasm_bindgen(static_method_of = FollowersNs)]
+        fn delete(key: &str) -> Promise;
+    }
+}
@@ -1,10 +1,23 @@
+use crate::activitypub;
+use crate::cache;
 use crate::console_logf;
-use crate::twoface;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::blocks::{Account, Blocks};
+#[cfg(feature = "sql")]
+use crate::models::sql;
+use crate::models::storage::{self, Schema};
+#[cfg(not(feature = "sql"))]
+use crate::models::storage::{Store, UpdateError};
+use crate::models::users;
+use crate::policy::Policy;
+use crate::ratelimit::{self, Action};
 use crate::twoface::*;
 use crate::utils::*;
+use cfg_if::cfg_if;
+use chrono::{offset::Utc, DateTime, SecondsFormat, TimeZone};
 use http::StatusCode;
 use js_sys::Promise;
-use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use url::Url;
@@ -14,52 +27,78 @@ use wasm_bindgen_futures::JsFuture;
 use web_sys::{Request, Response};
 
 const MAX_POST_CHARS: usize = 1000;
+/// How many posts are shown on one page if the request doesn't say.
+const DEFAULT_PAGE_SIZE: usize = 20;
+/// Upper bound on `?limit=`, so one response can't contain a user's whole history.
+const MAX_PAGE_SIZE: usize = 100;
 
 pub async fn new_post(req: Request) -> Result<Response, Response> {
-    let json_f = req.json().map_err(|e| {
-        Error {
-            internal: format!("error getting json future: {:?}", e),
-            external: twoface::External {
-                msg: "couldn't get JSON from request".to_owned(),
-            },
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-        }
-        .into_response()
+    let lang = i18n::request_language(&req, None);
+    create_post(req)
+        .await
+        .map_err(|e| e.localized_response(&lang))?;
+    console_logf!("Successfully made new post");
+    let resp = success_response("you made a post", Some("/".to_owned()));
+    flash::set(&resp, &Message::new("flash-post-created").localize(&lang));
+    Ok(resp)
+}
+
+/// Validates the post in the request body and saves it. People can only
+/// post as themselves.
+pub async fn create_post(req: Request) -> Fallible<Post> {
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        external: Message::new("error-invalid-url"),
+        status: StatusCode::BAD_REQUEST,
     })?;
-    let json = JsFuture::from(json_f).await.map_err(|e| {
-        Error {
-            internal: format!("error awaiting json: {:?}", e),
-            external: twoface::External {
-                msg: "Error awaiting JSON".to_owned(),
-            },
-            status: StatusCode::BAD_REQUEST,
-        }
-        .into_response()
+    let author = users::signed_in(&req).await?;
+    let json_f = req.json().map_err(|e| Error {
+        internal: format!("error getting json future: {:?}", e),
+        external: Message::new("error-read-json"),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
     })?;
-    let new_post: NewPost = json.into_serde().map_err(|e| {
-        Error {
-            internal: format!("error parsing post: {:?}", e),
-            external: twoface::External {
-                msg: "Your post was malformed".to_owned(),
-            },
-            status: StatusCode::BAD_REQUEST,
-        }
-        .into_response()
+    let json = JsFuture::from(json_f).await.map_err(|e| Error {
+        internal: format!("error awaiting json: {:?}", e),
+        external: Message::new("error-await-json"),
+        status: StatusCode::BAD_REQUEST,
     })?;
-    let post = Post::try_from(new_post).map_err(|e| {
-        Error {
-            internal: e.clone(),
-            external: twoface::External { msg: e },
-            status: StatusCode::BAD_REQUEST,
-        }
-        .into_response()
+    let new_post: NewPost = from_js(&json).map_err(|e| Error {
+        internal: format!("error parsing post: {:?}", e),
+        external: Message::new("error-malformed-post"),
+        status: StatusCode::BAD_REQUEST,
     })?;
-    post.put().await.map_err(|e| e.into_response())?;
-    console_logf!("Successfully made new post");
-    Ok(success_response("you made a post", Some("/".to_owned())))
+    let post = Post::try_from(new_post).map_err(|e| Error {
+        internal: e.to_string(),
+        external: e,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    if post.user_id != author.id {
+        return Err(Error {
+            internal: format!("{} tried to post as {}", author.id, post.user_id),
+            external: Message::new("error-not-your-post"),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+    if author.suspended {
+        return Err(Error {
+            internal: format!("{} is suspended", post.user_id),
+            external: Message::new("error-suspended"),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+    ratelimit::check(&req, Action::Post, Some(post.user_id)).await?;
+    let recent = all_posts_by_user(post.user_id).await?;
+    Policy::configured().check(&post, &recent).await?;
+    post.clone().put().await?;
+    // The post is saved either way, so don't fail the request if some
+    // followers' servers can't be reached.
+    if let Err(e) = activitypub::announce_post(&url, &post).await {
+        console_logf!("Couldn't deliver post {}: {}", post.id, e.internal);
+    }
+    Ok(post)
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Post {
     /// All posts contain some text the user wrote.
     pub text: String,
@@ -67,6 +106,118 @@ pub struct Post {
     pub link: Option<Url>,
     /// User that created this post
     pub user_id: Uuid,
+    /// Identifies this post. Posts stored before posts had IDs are given
+    /// one when they're migrated; see `PostV0::upgrade`.
+    pub id: Uuid,
+    /// When the post was made. Posts stored before this was recorded are
+    /// dated around the Unix epoch when they're migrated.
+    pub created: DateTime<Utc>,
+    /// Hidden by a moderator. Hidden posts are kept, but not shown anywhere.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub hidden: bool,
+    /// Who the author wants to read the post.
+    #[serde(default, skip_serializing_if = "Visibility::is_public")]
+    pub visibility: Visibility,
+}
+
+/// Who can read a post.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    /// Anyone, anywhere the author's posts are shown.
+    #[default]
+    Public,
+    /// Anyone with the link, but it's left out of post lists, feeds and the
+    /// outbox.
+    Unlisted,
+    /// Only the author's followers, who get it over ActivityPub. On quiet,
+    /// only the author sees it.
+    FollowersOnly,
+}
+
+impl Visibility {
+    pub fn is_public(&self) -> bool {
+        *self == Visibility::Public
+    }
+}
+
+fn unknown_time() -> DateTime<Utc> {
+    Utc.timestamp(0, 0)
+}
+
+/// A user's posts are stored together, oldest first.
+impl Schema for Vec<Post> {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => {
+                let posts: Vec<PostV0> = storage::decode_payload(payload)?;
+                Ok(posts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, old)| old.upgrade(i))
+                    .collect())
+            }
+            _ => Err(format!("no migration from posts version {}", version)),
+        }
+    }
+}
+
+/// Posts as they were stored before versioning, with positional fields.
+/// The oldest have no ID or date.
+#[derive(Deserialize)]
+struct PostV0 {
+    text: String,
+    link: Option<Url>,
+    user_id: Uuid,
+    #[serde(default)]
+    id: Uuid,
+    #[serde(default = "unknown_time")]
+    created: DateTime<Utc>,
+}
+
+/// What IDs given to posts from before IDs are derived in.
+const LEGACY_POST_IDS: Uuid = Uuid::from_bytes([
+    0x3b, 0x9e, 0x5c, 0x0a, 0x7d, 0x41, 0x4f, 0x2e, 0x9a, 0x63, 0x18, 0xc4, 0x52, 0xe7, 0x0b, 0xd9,
+]);
+
+impl PostV0 {
+    /// The post, as the `index`th of its author's stored posts. One without
+    /// a date is dated `index` milliseconds after the Unix epoch, so undated
+    /// posts keep their order and don't share a time. One without an ID
+    /// gets one derived from what's in it, so it's the same every time the
+    /// post is read, until `all_posts_by_user` writes it back. The index
+    /// keeps two posts saying the same thing from sharing an ID.
+    fn upgrade(self, index: usize) -> Post {
+        let created = if self.created == unknown_time() {
+            self.created + chrono::Duration::milliseconds(index as i64)
+        } else {
+            self.created
+        };
+        let id = if self.id.is_nil() {
+            let name = format!(
+                "{}/{}/{}/{}",
+                self.user_id,
+                created.to_rfc3339(),
+                index,
+                self.text
+            );
+            Uuid::new_v5(&LEGACY_POST_IDS, name.as_bytes())
+        } else {
+            self.id
+        };
+        Post {
+            text: self.text,
+            link: self.link,
+            user_id: self.user_id,
+            id,
+            created,
+            hidden: false,
+            visibility: Visibility::Public,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -77,23 +228,24 @@ pub struct NewPost {
     pub link: Option<String>,
     /// User that created this post
     pub user_id: String,
+    /// Who can read the post. Defaults to everyone.
+    #[serde(default)]
+    pub visibility: Visibility,
 }
 
 impl TryFrom<NewPost> for Post {
-    type Error = String;
+    type Error = Message;
 
     fn try_from(new_post: NewPost) -> Result<Self, Self::Error> {
         if new_post.text.len() > MAX_POST_CHARS {
-            return Err(format!(
-                "Posts can only have {} characters, but yours has {}",
-                MAX_POST_CHARS,
-                new_post.text.len()
-            ));
+            return Err(Message::new("post-too-long")
+                .arg("max", MAX_POST_CHARS)
+                .arg("count", new_post.text.len()));
         }
         let user_id = Uuid::parse_str(&new_post.user_id)
-            .map_err(|_| format!("{} is an invalid user ID", new_post.user_id))?;
+            .map_err(|_| Message::new("invalid-user-id").arg("id", new_post.user_id.clone()))?;
         let link = match new_post.link.map(|s| Url::parse(&s)) {
-            Some(Err(_)) => return Err("The URL is invalid".to_owned()),
+            Some(Err(_)) => return Err(Message::new("invalid-link")),
             None => None,
             Some(Ok(u)) => Some(u),
         };
@@ -101,64 +253,497 @@ impl TryFrom<NewPost> for Post {
             text: new_post.text,
             link,
             user_id,
+            id: Uuid::new_v4(),
+            created: Utc::now(),
+            hidden: false,
+            visibility: new_post.visibility,
         })
     }
 }
 
 impl Post {
+    /// Whether `viewer` can read the post, given a link to it. Authors can
+    /// read all their posts, except ones a moderator hid.
+    pub fn readable_by(&self, viewer: Option<Uuid>) -> bool {
+        !self.hidden
+            && (viewer == Some(self.user_id) || self.visibility != Visibility::FollowersOnly)
+    }
+
+    /// Whether the post shows up in the lists of posts `viewer` sees, like
+    /// profile pages, feeds and the outbox.
+    pub fn listed_for(&self, viewer: Option<Uuid>) -> bool {
+        self.readable_by(viewer) && (viewer == Some(self.user_id) || self.visibility.is_public())
+    }
+
+    #[cfg(not(feature = "sql"))]
+    pub async fn put(self) -> Fallible<()> {
+        let user_id = self.user_id;
+        update_posts(user_id, |posts| posts.push(self.clone())).await
+    }
+
+    #[cfg(feature = "sql")]
     pub async fn put(self) -> Fallible<()> {
-        let key = self.user_id.to_string();
-        let mut val = all_posts_by_user(self.user_id).await?;
-        val.push(self);
-        let mut val_bytes = Vec::new();
-        val.serialize(&mut Serializer::new(&mut val_bytes))
-            .map_err(|e| Error {
-                internal: e.to_string(),
-                status: http::StatusCode::BAD_REQUEST,
-                external: twoface::External {
-                    msg: "Invalid post".into(),
-                },
-            })?;
-        JsFuture::from(PostsNs::put(&key, &val_bytes))
-            .await
-            .map_err(|e| Error {
-                internal: format!("{:?}", e),
-                status: http::StatusCode::INTERNAL_SERVER_ERROR,
-                external: twoface::External {
-                    msg: "Post unsuccessful, please try again later".to_owned(),
-                },
-            })?;
+        let user_id = self.user_id;
+        sql::add_posts(&sql::database().await?, &[self]).await?;
+        cache::invalidate(user_id).await;
         Ok(())
     }
+
+    /// A public post saying `text`, by a new user, for tests. Set whatever
+    /// else a test cares about with `Post { ..Post::fixture(text) }`.
+    #[cfg(test)]
+    pub fn fixture(text: &str) -> Self {
+        Self {
+            text: text.to_owned(),
+            link: None,
+            user_id: Uuid::new_v4(),
+            id: Uuid::new_v4(),
+            created: Utc.ymd(2020, 9, 1).and_hms(12, 0, 0),
+            hidden: false,
+            visibility: Visibility::Public,
+        }
+    }
 }
 
-pub async fn all_posts_by_user(user_id: Uuid) -> Fallible<Vec<Post>> {
-    let promise = PostsNs::get(&user_id.to_string(), "arrayBuffer");
-    let val = JsFuture::from(promise).await.map_err(|e| Error {
-        internal: format!("{:?}", e),
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        external: twoface::External {
-            msg: "couldn't load posts from database".to_owned(),
-        },
-    })?;
-    if val.is_null() || val.is_undefined() {
-        return Ok(Vec::new());
+/// Adds posts imported from elsewhere to the user's history. They keep
+/// their original dates, so unlike new posts they're slotted in among the
+/// user's posts by date rather than appended.
+#[cfg(not(feature = "sql"))]
+pub async fn add_imported(user_id: Uuid, imported: Vec<Post>) -> Fallible<()> {
+    if imported.is_empty() {
+        return Ok(());
+    }
+    update_posts(user_id, |posts| {
+        posts.extend(imported.iter().cloned());
+        // Stable, so posts made at the same moment stay in the order they were.
+        posts.sort_by_key(|p| p.created);
+    })
+    .await
+}
+
+/// Imported posts keep their original dates, which is all the database
+/// orders posts by.
+#[cfg(feature = "sql")]
+pub async fn add_imported(user_id: Uuid, imported: Vec<Post>) -> Fallible<()> {
+    if imported.is_empty() {
+        return Ok(());
+    }
+    sql::add_posts(&sql::database().await?, &imported).await?;
+    cache::invalidate(user_id).await;
+    Ok(())
+}
+
+/// Hides one of a user's posts from everyone, or shows it again.
+#[cfg(not(feature = "sql"))]
+pub async fn set_hidden(user_id: Uuid, id: Uuid, hidden: bool) -> Fallible<()> {
+    update_posts(user_id, |posts| set_hidden_in(posts, id, hidden)).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn set_hidden(user_id: Uuid, id: Uuid, hidden: bool) -> Fallible<()> {
+    sql::set_hidden(&sql::database().await?, user_id, id, hidden).await?;
+    cache::invalidate(user_id).await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "sql", allow(dead_code))]
+fn set_hidden_in(posts: &mut [Post], id: Uuid, hidden: bool) {
+    for post in posts.iter_mut().filter(|p| p.id == id) {
+        post.hidden = hidden;
+    }
+}
+
+#[cfg(not(feature = "sql"))]
+async fn update_posts(user_id: Uuid, change: impl FnMut(&mut Vec<Post>)) -> Fallible<()> {
+    let key = user_id.to_string();
+    storage::update(&Timelines, &key, change)
+        .await
+        .map_err(|e| match e {
+            UpdateError::Conflict => Error {
+                internal: format!("gave up saving posts of {} after conflicts", user_id),
+                status: StatusCode::CONFLICT,
+                external: Message::new("error-busy"),
+            },
+            UpdateError::Failed(e) => Error {
+                internal: e,
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-save-post"),
+            },
+        })?;
+    cache::invalidate(user_id).await;
+    Ok(())
+}
+
+/// A position in a user's post history: the post a page starts at, which
+/// the next page of older posts ends just before. Pages are in order of
+/// when posts were made, then their IDs, and a cursor compares the same way,
+/// so it stays put when posts are added anywhere, including old ones
+/// slotted in by `add_imported`, and when its own post is deleted.
+/// Clients only ever see the encoded form, which they should treat as opaque.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    created: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    /// Versions the encoding, so the format can change without old links
+    /// silently pointing at the wrong posts. "p1:" cursors counted posts.
+    const PREFIX: &'static str = "p2:";
+
+    fn at(post: &Post) -> Self {
+        Self {
+            created: post.created,
+            id: post.id,
+        }
+    }
+
+    pub fn encode(self) -> String {
+        let created = self.created.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        base64::encode_config(
+            format!("{}{}:{}", Self::PREFIX, self.id, created),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    pub fn decode(s: &str) -> Result<Self, Message> {
+        let invalid = || Message::new("invalid-cursor").arg("cursor", s.to_owned());
+        let bytes = base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(bytes).map_err(|_| invalid())?;
+        let Some((id, created)) = decoded
+            .strip_prefix(Self::PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+        else {
+            return Err(invalid());
+        };
+        Ok(Self {
+            created: DateTime::parse_from_rfc3339(created)
+                .map_err(|_| invalid())?
+                .with_timezone(&Utc),
+            id: Uuid::parse_str(id).map_err(|_| invalid())?,
+        })
+    }
+
+    /// How many of `posts`, in page order, come before the cursor.
+    fn position(&self, posts: &[Post]) -> usize {
+        posts.partition_point(|p| Self::at(p) < *self)
+    }
+}
+
+/// Which page of posts a request wants, parsed from `?before=<cursor>&limit=N`.
+pub struct PageQuery {
+    pub before: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl PageQuery {
+    pub fn from_url(url: &Url) -> Result<Self, Message> {
+        let mut query = Self {
+            before: None,
+            limit: DEFAULT_PAGE_SIZE,
+        };
+        for (key, val) in url.query_pairs() {
+            match key.as_ref() {
+                "before" => query.before = Some(Cursor::decode(&val)?),
+                "limit" => {
+                    query.limit = match val.parse() {
+                        Ok(n) if n > 0 && n <= MAX_PAGE_SIZE => n,
+                        _ => return Err(Message::new("invalid-limit").arg("max", MAX_PAGE_SIZE)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(query)
+    }
+
+    /// Link to the page after `page`, keeping the same limit.
+    pub fn older_url(&self, path: &str, page: &Page) -> Option<String> {
+        let older = page.older?;
+        let mut url = path.to_owned();
+        url.push_str("?before=");
+        url.push_str(&older.encode());
+        if self.limit != DEFAULT_PAGE_SIZE {
+            url.push_str(&format!("&limit={}", self.limit));
+        }
+        Some(url)
+    }
+}
+
+/// Some of a user's posts, newest first.
+pub struct Page {
+    pub posts: Vec<Post>,
+    /// Where the next page of older posts starts, if there are any.
+    pub older: Option<Cursor>,
+}
+
+impl Page {
+    /// Picks the requested page out of a user's posts.
+    pub fn of(mut posts: Vec<Post>, query: &PageQuery) -> Self {
+        // They're stored oldest first, but posts made at the same moment
+        // can be in any order.
+        posts.sort_by_key(Cursor::at);
+        let end = match query.before {
+            Some(cursor) => cursor.position(&posts),
+            None => posts.len(),
+        };
+        let start = end.saturating_sub(query.limit);
+        let older = if start > 0 {
+            Some(Cursor::at(&posts[start]))
+        } else {
+            None
+        };
+        posts.truncate(end);
+        let mut page: Vec<_> = posts.drain(start..).collect();
+        page.reverse();
+        Self { posts: page, older }
+    }
+
+    /// Leaves out the posts that aren't listed for `viewer`. This happens
+    /// after paging, so hiding a post doesn't change which posts are on which
+    /// page; the page just comes out shorter.
+    fn listed_for(mut self, viewer: Option<Uuid>) -> Self {
+        self.posts.retain(|p| p.listed_for(viewer));
+        self
     }
-    let typebuf: js_sys::Uint8Array = js_sys::Uint8Array::new(&val);
-    if typebuf.length() == 0 {
+}
+
+/// Whether `viewer` can read the user's posts. Suspended users' posts
+/// aren't shown to anyone, so there are none to read. On quiet, only a
+/// private user can read their own posts; everyone else is refused. The
+/// followers they approve are all on other servers, and only get new posts
+/// delivered over ActivityPub. `viewer` is None for public outputs like
+/// feeds.
+async fn readable(user_id: Uuid, viewer: Option<Uuid>) -> Fallible<bool> {
+    let Some(author) = users::profile_by_id(user_id).await? else {
+        return Ok(true);
+    };
+    if author.suspended {
+        return Ok(false);
+    }
+    if author.is_private && viewer != Some(user_id) {
+        return Err(Error {
+            internal: format!("{:?} can't read private user {}", viewer, user_id),
+            external: Message::new("error-private-account"),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+    Ok(true)
+}
+
+/// One page of the posts listed for `viewer` of a user's.
+pub async fn page_of_posts_by_user(
+    user_id: Uuid,
+    viewer: Option<Uuid>,
+    query: &PageQuery,
+) -> Fallible<Page> {
+    if !readable(user_id, viewer).await? {
+        return Ok(Page {
+            posts: Vec::new(),
+            older: None,
+        });
+    }
+    let posts = all_posts_by_user(user_id).await?;
+    Ok(Page::of(posts, query).listed_for(viewer))
+}
+
+/// One page of `viewer`'s home feed: their own posts, and those listed for
+/// them of the quiet users in `following`, who they follow. Private and
+/// suspended users' posts are left out, even if they're followed, and so
+/// is anyone `blocks`, the viewer's, mutes.
+pub async fn page_of_home_feed(
+    viewer: Uuid,
+    following: &[Uuid],
+    blocks: &Blocks,
+    query: &PageQuery,
+) -> Fallible<Page> {
+    let mut authors = Vec::new();
+    for &user_id in std::iter::once(&viewer).chain(following) {
+        match readable(user_id, Some(viewer)).await {
+            Ok(true) => authors.push(user_id),
+            Ok(false) => {}
+            Err(e) if e.status == StatusCode::FORBIDDEN => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let mut posts = posts_followed_by(viewer, following).await?;
+    posts.extend(all_posts_by_user(viewer).await?);
+    posts.retain(|p| authors.contains(&p.user_id));
+    Ok(Page::of(unmuted(posts, blocks), query).listed_for(Some(viewer)))
+}
+
+/// Leaves out the posts by anyone `blocks` mutes. Unlike hidden posts,
+/// they're left out before paging, so muting someone who posts a lot
+/// doesn't leave pages of the feed empty.
+fn unmuted(mut posts: Vec<Post>, blocks: &Blocks) -> Vec<Post> {
+    posts.retain(|p| !blocks.mutes(&Account::Local(p.user_id)));
+    posts
+}
+
+/// Posts by the quiet users in `following`. KV can't join, so each of
+/// their timelines is loaded in turn.
+#[cfg(not(feature = "sql"))]
+async fn posts_followed_by(_viewer: Uuid, following: &[Uuid]) -> Fallible<Vec<Post>> {
+    let mut posts = Vec::new();
+    for &user_id in following {
+        posts.extend(all_posts_by_user(user_id).await?);
+    }
+    Ok(posts)
+}
+
+/// Posts by the quiet users `viewer` follows, in one query.
+#[cfg(feature = "sql")]
+async fn posts_followed_by(viewer: Uuid, _following: &[Uuid]) -> Fallible<Vec<Post>> {
+    sql::posts_followed_by(&sql::database().await?, viewer).await
+}
+
+/// Every post of a user's that `viewer` can read: none if they're
+/// suspended, none a moderator hid, and none only for followers unless
+/// `viewer` wrote them. Unlisted posts are included.
+pub async fn visible_posts_by_user(user_id: Uuid, viewer: Option<Uuid>) -> Fallible<Vec<Post>> {
+    if !readable(user_id, viewer).await? {
         return Ok(Vec::new());
     }
-    let mut body = vec![0; typebuf.length() as usize];
-    typebuf.copy_to(&mut body[..]);
+    let mut posts = all_posts_by_user(user_id).await?;
+    posts.retain(|p| p.readable_by(viewer));
+    Ok(posts)
+}
 
-    let posts: Vec<Post> = rmp_serde::from_read_ref(&body).map_err(|e| Error {
-        internal: format!("{:?}", e),
+/// Finds one of a user's posts by its ID, if `viewer` can see it.
+pub async fn post_by_user(user_id: Uuid, viewer: Option<Uuid>, id: Uuid) -> Fallible<Option<Post>> {
+    let posts = visible_posts_by_user(user_id, viewer).await?;
+    Ok(posts.into_iter().find(|p| p.id == id))
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn all_posts_by_user(user_id: Uuid) -> Fallible<Vec<Post>> {
+    let key = user_id.to_string();
+    let stored = Timelines.get(&key).await;
+    let posts = match stored {
+        Ok(Some(stored)) => {
+            // Migrating posts gives old ones IDs, which have to be saved
+            // before anything links to them. Reading works either way.
+            let upgraded = storage::upgrade::<_, Vec<Post>>(&Timelines, &key, &stored).await;
+            if let Err(e) = upgraded {
+                console_logf!("couldn't upgrade the posts of {}: {:?}", user_id, e);
+            }
+            storage::decode(&stored.bytes)
+        }
+        Ok(None) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    };
+    posts.map_err(|e| Error {
+        internal: e,
         status: StatusCode::INTERNAL_SERVER_ERROR,
-        external: twoface::External {
-            msg: "couldn't load posts from database".to_owned(),
-        },
-    })?;
-    Ok(posts)
+        external: Message::new("error-load-posts"),
+    })
+}
+
+#[cfg(feature = "sql")]
+pub async fn all_posts_by_user(user_id: Uuid) -> Fallible<Vec<Post>> {
+    sql::posts_by_user(&sql::database().await?, user_id).await
+}
+
+cfg_if! {
+    // Durable Objects read back what was just written, wherever the reader
+    // is. KV can take a minute to catch up, so someone redirected to their
+    // feed after posting might not see their post yet.
+    if #[cfg(all(feature = "sql", feature = "durable-timelines"))] {
+        compile_error!("posts can be kept in D1 or Durable Objects, not both");
+    } else if #[cfg(feature = "sql")] {
+        // Posts are rows in the database; see `models::sql`.
+    } else if #[cfg(feature = "durable-timelines")] {
+        use crate::models::timelines::DurableTimelines as Timelines;
+    } else {
+        use kv::PostsKv as Timelines;
+    }
+}
+
+#[cfg(not(any(feature = "sql", feature = "durable-timelines")))]
+mod kv {
+    use super::*;
+    use crate::models::storage::Versioned;
+    use futures::future::{FutureExt, LocalBoxFuture};
+
+    /// Posts are kept in Workers KV, all of a user's under their ID. KV can't
+    /// compare and set in one step, so `put_if_match` reads the value again just
+    /// before saving. That catches requests racing on one server, but leaves a
+    /// short window, and KV only becomes consistent between locations after a
+    /// minute or so.
+    pub struct PostsKv;
+
+    impl Store for PostsKv {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            async move {
+                let val = JsFuture::from(PostsNs::get(key, "arrayBuffer"))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                if val.is_null() || val.is_undefined() {
+                    return Ok(None);
+                }
+                let bytes = js_sys::Uint8Array::new(&val).to_vec();
+                if bytes.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Versioned::new(bytes)))
+            }
+            .boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            async move {
+                let current = self.get(key).await?;
+                if current.as_ref().map(|v| v.etag.as_str()) != etag {
+                    return Ok(false);
+                }
+                JsFuture::from(PostsNs::put(key, &bytes))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(true)
+            }
+            .boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            async move {
+                JsFuture::from(PostsNs::delete(key))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(())
+            }
+            .boxed_local()
+        }
+    }
+}
+
+/// Deletes every post the user has made.
+#[cfg(not(feature = "sql"))]
+pub async fn delete_all_posts_by_user(user_id: Uuid) -> Fallible<()> {
+    Timelines
+        .delete(&user_id.to_string())
+        .await
+        .map_err(|e| Error {
+            internal: e,
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            external: Message::new("error-delete-account"),
+        })?;
+    cache::invalidate(user_id).await;
+    Ok(())
+}
+
+#[cfg(feature = "sql")]
+pub async fn delete_all_posts_by_user(user_id: Uuid) -> Fallible<()> {
+    sql::delete_posts_by_user(&sql::database().await?, user_id).await?;
+    cache::invalidate(user_id).await;
+    Ok(())
 }
 
 // The Cloudflare Workers environment will bind your Workers KV namespaces to
@@ -179,3 +764,287 @@ extern "C" {
     #[wasm_bindgen(static_method_of = PostsNs)]
     fn delete(key: &str) -> Promise;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(visibility: Visibility) -> Post {
+        Post {
+            visibility,
+            ..Post::fixture("hi")
+        }
+    }
+
+    #[test]
+    fn visibility_decides_who_reads_and_who_sees_it_listed() {
+        let stranger = Some(Uuid::new_v4());
+        for (visibility, readable, listed) in &[
+            (Visibility::Public, true, true),
+            (Visibility::Unlisted, true, false),
+            (Visibility::FollowersOnly, false, false),
+        ] {
+            let post = post(*visibility);
+            assert_eq!(post.readable_by(stranger), *readable, "{:?}", visibility);
+            assert_eq!(post.readable_by(None), *readable, "{:?}", visibility);
+            assert_eq!(post.listed_for(stranger), *listed, "{:?}", visibility);
+            assert!(post.listed_for(Some(post.user_id)));
+        }
+        let mut hidden = post(Visibility::Public);
+        hidden.hidden = true;
+        assert!(!hidden.readable_by(Some(hidden.user_id)));
+    }
+
+    /// One user's posts made `secs` seconds into 2020, oldest first.
+    fn dated(secs: &[i64]) -> Vec<Post> {
+        let user_id = Uuid::new_v4();
+        secs.iter()
+            .map(|s| Post {
+                user_id,
+                created: Utc.timestamp(1_577_836_800 + s, 0),
+                ..Post::fixture("hi")
+            })
+            .collect()
+    }
+
+    fn ids(posts: &[Post]) -> Vec<Uuid> {
+        posts.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn cursors_stay_put_when_old_posts_are_imported() {
+        let mut posts = dated(&[10, 30, 50, 70]);
+        let first = Page::of(
+            posts.clone(),
+            &PageQuery {
+                before: None,
+                limit: 2,
+            },
+        );
+        let older = first.older.unwrap();
+        let expected = [posts[1].id, posts[0].id];
+
+        // One import lands before the cursor and one after it.
+        let imported = dated(&[20, 60]);
+        posts.extend(imported.iter().cloned());
+        posts.sort_by_key(|p| p.created);
+        let query = PageQuery {
+            before: Some(older),
+            limit: 2,
+        };
+        let second = Page::of(posts.clone(), &query);
+        assert_eq!(ids(&second.posts), vec![expected[0], imported[0].id]);
+
+        // If the cursor's post is gone, the page still ends where it was.
+        posts.retain(|p| p.id != first.posts[1].id);
+        let third = Page::of(
+            posts,
+            &PageQuery {
+                before: Some(older),
+                limit: 3,
+            },
+        );
+        assert_eq!(
+            ids(&third.posts),
+            vec![expected[0], imported[0].id, expected[1]]
+        );
+    }
+
+    fn english(msg: Message) -> String {
+        msg.localize(&i18n::default_language())
+    }
+
+    fn encoded(s: &str) -> String {
+        base64::encode_config(s, base64::URL_SAFE_NO_PAD)
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for post in &dated(&[0, 1, 86_400 * 365]) {
+            let cursor = Cursor::at(post);
+            assert_eq!(Cursor::decode(&cursor.encode()).ok(), Some(cursor));
+        }
+        let precise = Post {
+            created: Utc.timestamp(1_600_000_000, 123_456_789),
+            ..Post::fixture("hi")
+        };
+        let cursor = Cursor::at(&precise);
+        assert_eq!(Cursor::decode(&cursor.encode()).ok(), Some(cursor));
+    }
+
+    #[test]
+    fn malformed_cursors_are_refused() {
+        let id = Uuid::new_v4();
+        for bad in &[
+            String::new(),
+            "not base64!".to_owned(),
+            base64::encode_config([0xff, 0xfe], base64::URL_SAFE_NO_PAD),
+            encoded("p1:3"),
+            encoded(&format!("p3:{}:2020-01-01T00:00:00Z", id)),
+            encoded("p2:"),
+            encoded("p2:not-a-uuid:2020-01-01T00:00:00Z"),
+            encoded(&format!("p2:{}:yesterday", id)),
+            encoded(&format!("p2:{}", id)),
+        ] {
+            let err = Cursor::decode(bad).unwrap_err();
+            assert_eq!(
+                english(err),
+                format!("{} is an invalid cursor", bad),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn limits_are_checked() {
+        let parse = |query: &str| {
+            let url = Url::parse(&format!("https://quiet.example/user/1{}", query)).unwrap();
+            PageQuery::from_url(&url).map(|q| q.limit).map_err(english)
+        };
+        let out_of_range = Err(format!(
+            "limit must be a number between 1 and {}",
+            MAX_PAGE_SIZE
+        ));
+        for (query, limit) in &[
+            ("", Ok(DEFAULT_PAGE_SIZE)),
+            ("?limit=1", Ok(1)),
+            ("?limit=100", Ok(MAX_PAGE_SIZE)),
+            ("?limit=0", out_of_range.clone()),
+            ("?limit=101", out_of_range.clone()),
+            ("?limit=-1", out_of_range.clone()),
+            ("?limit=ten", out_of_range.clone()),
+            ("?limit=", out_of_range.clone()),
+        ] {
+            assert_eq!(&parse(query), limit, "{}", query);
+        }
+    }
+
+    #[test]
+    fn pages_end_at_the_cursor() {
+        let posts = dated(&[10, 20, 30, 40, 50]);
+        let len = posts.len();
+        let newest = ids(&posts).into_iter().rev().collect::<Vec<_>>();
+        let gone = Cursor::at(&dated(&[60])[0]);
+        // (cursor, limit, posts on the page, where the next page starts)
+        for (before, limit, page, older) in &[
+            (None, 2, &newest[..2], Some(3)),
+            (None, len, &newest[..], None),
+            (None, len + 1, &newest[..], None),
+            (Some(Cursor::at(&posts[0])), 2, &newest[..0], None),
+            (Some(Cursor::at(&posts[3])), 2, &newest[2..4], Some(1)),
+            (Some(Cursor::at(&posts[len - 1])), len, &newest[1..], None),
+            (Some(gone), 2, &newest[..2], Some(3)),
+        ] {
+            let query = PageQuery {
+                before: *before,
+                limit: *limit,
+            };
+            let got = Page::of(posts.clone(), &query);
+            assert_eq!(&ids(&got.posts)[..], *page, "{:?} {}", before, limit);
+            assert_eq!(got.older, older.map(|i| Cursor::at(&posts[i])));
+        }
+    }
+
+    #[test]
+    fn unlisted_posts_shorten_pages_without_moving_them() {
+        let mut posts = dated(&[10, 20, 30, 40]);
+        posts[3].hidden = true;
+        posts[2].visibility = Visibility::Unlisted;
+        let author = Some(posts[0].user_id);
+        let query = PageQuery {
+            before: None,
+            limit: 2,
+        };
+        let page = Page::of(posts.clone(), &query).listed_for(None);
+        assert!(page.posts.is_empty());
+        assert_eq!(page.older, Some(Cursor::at(&posts[2])));
+        // The author still sees their unlisted post, but nobody sees hidden ones.
+        let page = Page::of(posts.clone(), &query).listed_for(author);
+        assert_eq!(ids(&page.posts), vec![posts[2].id]);
+        let query = PageQuery {
+            before: page.older,
+            limit: 2,
+        };
+        let page = Page::of(posts.clone(), &query).listed_for(None);
+        assert_eq!(ids(&page.posts), vec![posts[1].id, posts[0].id]);
+        assert_eq!(page.older, None);
+    }
+
+    #[test]
+    fn legacy_posts_saying_the_same_thing_get_different_ids() {
+        let user_id = Uuid::new_v4();
+        // Version 0 posts were positional, and the oldest had no ID or date.
+        let same = ("hi".to_owned(), None::<Url>, user_id);
+        let payload = rmp_serde::to_vec(&vec![same.clone(), same]).unwrap();
+        let posts = <Vec<Post> as Schema>::migrate(0, &payload).unwrap();
+        assert!(posts.iter().all(|p| !p.id.is_nil()));
+        assert_ne!(posts[0].id, posts[1].id);
+        let again = <Vec<Post> as Schema>::migrate(0, &payload).unwrap();
+        assert_eq!(ids(&again), ids(&posts));
+    }
+
+    #[test]
+    fn feeds_leave_out_muted_and_blocked_people_before_paging() {
+        let (ada, bob, cy) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let start = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let posts: Vec<_> = [ada, bob, bob, cy, bob, ada]
+            .iter()
+            .enumerate()
+            .map(|(i, &user_id)| Post {
+                user_id,
+                created: start + chrono::Duration::minutes(i as i64),
+                ..Post::fixture(&format!("post {}", i))
+            })
+            .collect();
+        let blocks = Blocks {
+            blocked: vec![Account::Local(cy)],
+            muted: vec![Account::Local(bob)],
+        };
+        let query = PageQuery {
+            before: None,
+            limit: 2,
+        };
+        let page = Page::of(unmuted(posts, &blocks), &query);
+        let texts: Vec<_> = page.posts.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(texts, ["post 5", "post 0"]);
+        assert!(page.older.is_none());
+    }
+
+    #[test]
+    fn paging_reaches_posts_sharing_a_time_or_an_id() {
+        let mut posts = dated(&[10, 10, 10, 20, 30, 30]);
+        // No two share both, which migration makes sure of for old posts.
+        posts[3].id = posts[0].id;
+        posts[5].id = posts[0].id;
+        for limit in 1..=posts.len() {
+            let mut seen = Vec::new();
+            let mut before = None;
+            loop {
+                let page = Page::of(posts.clone(), &PageQuery { before, limit });
+                seen.extend(page.posts.iter().map(|p| (p.created, p.id)));
+                before = match page.older {
+                    Some(older) => Some(older),
+                    None => break,
+                };
+            }
+            let mut all: Vec<_> = posts.iter().map(|p| (p.created, p.id)).collect();
+            all.sort();
+            all.reverse();
+            assert_eq!(seen, all, "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn moderators_hide_one_post_and_can_show_it_again() {
+        let same = ("hi".to_owned(), None::<Url>, Uuid::new_v4());
+        let payload = rmp_serde::to_vec(&vec![same.clone(), same]).unwrap();
+        let mut posts = <Vec<Post> as Schema>::migrate(0, &payload).unwrap();
+        let id = posts[1].id;
+        set_hidden_in(&mut posts, id, true);
+        let hidden: Vec<_> = posts.iter().map(|p| p.hidden).collect();
+        assert_eq!(hidden, [false, true]);
+        set_hidden_in(&mut posts, id, false);
+        assert!(posts.iter().all(|p| !p.hidden));
+    }
+}
@@ -0,0 +1,222 @@
+//! Reports of posts that break the rules, waiting for a moderator. Once a
+//! moderator hides the post, suspends its author or decides it's fine, its
+//! reports are resolved, which forgets them.
+use crate::i18n::Message;
+#[cfg(feature = "sql")]
+use crate::models::sql;
+use crate::models::storage::Schema;
+use crate::twoface::*;
+use chrono::{offset::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+#[cfg(not(feature = "sql"))]
+use {
+    crate::models::storage::{self, Store, UpdateError},
+    http::StatusCode,
+    kv::ReportsKv,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub id: Uuid,
+    pub post_id: Uuid,
+    /// Who made the post.
+    pub author_id: Uuid,
+    /// Who reported it, if they have a profile.
+    pub reporter_id: Option<Uuid>,
+    /// Why they reported it, in their own words.
+    pub reason: String,
+    /// The post's text when it was reported, so moderators can read what
+    /// was reported even if the post changes or is hidden.
+    pub text: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Reports were first stored with versions.
+impl Schema for Vec<Report> {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, _payload: &[u8]) -> Result<Self, String> {
+        Err(format!("no migration from reports version {}", version))
+    }
+}
+
+/// The most someone can write about why they're reporting a post.
+pub const MAX_REASON_CHARS: usize = 500;
+
+impl Report {
+    pub fn new(
+        post_id: Uuid,
+        author_id: Uuid,
+        reporter_id: Option<Uuid>,
+        reason: String,
+        text: String,
+    ) -> Result<Self, Message> {
+        let count = reason.chars().count();
+        if count > MAX_REASON_CHARS {
+            return Err(Message::new("report-reason-too-long")
+                .arg("max", MAX_REASON_CHARS)
+                .arg("count", count));
+        }
+        Ok(Self {
+            id: Uuid::new_v4(),
+            post_id,
+            author_id,
+            reporter_id,
+            reason,
+            text,
+            created: Utc::now(),
+        })
+    }
+}
+
+/// Every report is kept in one list under this key, oldest first.
+#[cfg(not(feature = "sql"))]
+const QUEUE_KEY: &str = "queue";
+
+#[cfg(not(feature = "sql"))]
+pub async fn add_report(report: Report) -> Fallible<()> {
+    update_queue(|reports| reports.push(report.clone())).await
+}
+
+/// Every report waiting for a moderator, oldest first.
+#[cfg(not(feature = "sql"))]
+pub async fn open_reports() -> Fallible<Vec<Report>> {
+    let stored = ReportsKv.get(QUEUE_KEY).await;
+    let reports = match stored {
+        Ok(Some(stored)) => storage::decode(&stored.bytes),
+        Ok(None) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    };
+    reports.map_err(|e| Error {
+        internal: e,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-reports"),
+    })
+}
+
+/// Forgets the reports of a post a moderator has dealt with.
+#[cfg(not(feature = "sql"))]
+pub async fn resolve_post(post_id: Uuid) -> Fallible<()> {
+    update_queue(|reports| reports.retain(|r| r.post_id != post_id)).await
+}
+
+/// Forgets the reports of a user's posts, when they're suspended or gone.
+#[cfg(not(feature = "sql"))]
+pub async fn resolve_author(author_id: Uuid) -> Fallible<()> {
+    update_queue(|reports| reports.retain(|r| r.author_id != author_id)).await
+}
+
+#[cfg(not(feature = "sql"))]
+async fn update_queue(change: impl FnMut(&mut Vec<Report>)) -> Fallible<()> {
+    storage::update(&ReportsKv, QUEUE_KEY, change)
+        .await
+        .map_err(|e| match e {
+            UpdateError::Conflict => Error {
+                internal: "gave up saving reports after conflicts".to_owned(),
+                status: StatusCode::CONFLICT,
+                external: Message::new("error-busy"),
+            },
+            UpdateError::Failed(e) => Error {
+                internal: e,
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-save-report"),
+            },
+        })?;
+    Ok(())
+}
+
+#[cfg(feature = "sql")]
+pub async fn add_report(report: Report) -> Fallible<()> {
+    sql::add_report(&sql::database().await?, &report).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn open_reports() -> Fallible<Vec<Report>> {
+    sql::reports(&sql::database().await?).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn resolve_post(post_id: Uuid) -> Fallible<()> {
+    sql::delete_reports_of_post(&sql::database().await?, post_id).await
+}
+
+#[cfg(feature = "sql")]
+pub async fn resolve_author(author_id: Uuid) -> Fallible<()> {
+    sql::delete_reports_of_author(&sql::database().await?, author_id).await
+}
+
+#[cfg(not(feature = "sql"))]
+mod kv {
+    use crate::models::storage::{Store, Versioned};
+    use futures::future::{FutureExt, LocalBoxFuture};
+    use js_sys::Promise;
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_futures::JsFuture;
+
+    /// Reports are kept in Workers KV, which like `PostsKv` can only check
+    /// a value hasn't changed just before saving over it.
+    pub struct ReportsKv;
+
+    impl Store for ReportsKv {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            async move {
+                let val = JsFuture::from(ReportsNs::get(key, "arrayBuffer"))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                if val.is_null() || val.is_undefined() {
+                    return Ok(None);
+                }
+                let bytes = js_sys::Uint8Array::new(&val).to_vec();
+                Ok(Some(Versioned::new(bytes)))
+            }
+            .boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            async move {
+                let current = self.get(key).await?;
+                if current.as_ref().map(|v| v.etag.as_str()) != etag {
+                    return Ok(false);
+                }
+                JsFuture::from(ReportsNs::put(key, &bytes))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(true)
+            }
+            .boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            async move {
+                JsFuture::from(ReportsNs::delete(key))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                Ok(())
+            }
+            .boxed_local()
+        }
+    }
+
+    #[wasm_bindgen]
+    extern "C" {
+        type ReportsNs;
+
+        #[wasm_bindgen(static_method_of = ReportsNs)]
+        fn get(key: &str, data_type: &str) -> Promise;
+
+        #[wasm_bindgen(static_method_of = ReportsNs)]
+        fn put(key: &str, val: &[u8]) -> Promise;
+
+        #[wasm_bindgen(static_method_of = ReportsNs)]
+        fn delete(key: &str) -> Promise;
+    }
+}
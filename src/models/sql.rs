@@ -0,0 +1,1011 @@
+//! Posts, profiles and followers as rows in a relational database, when
+//! quiet is built with the `sql` feature. On Cloudflare that's D1, bound as
+//! `QuietDb`; in tests it's SQLite in memory. Either way the same SQL runs,
+//! so queries can join across tables, like `posts_followed_by`.
+//!
+//! The tables are made by `MIGRATIONS`, which `database` applies the first
+//! time each worker uses the database. Avatars stay in KV.
+use crate::i18n::Message;
+use crate::models::{
+    followers::{FollowRequest, Follower},
+    posts::{Post, Visibility},
+    reports::Report,
+    users::{Profile, Role},
+};
+use crate::twoface::*;
+use chrono::{offset::Utc, DateTime, SecondsFormat};
+use futures::future::LocalBoxFuture;
+use http::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+use uuid::Uuid;
+
+/// The statements that build the schema, in order. Once a migration has
+/// been released, don't change it: add another.
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: what used to be in KV.
+    &[
+        "CREATE TABLE users (
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            email TEXT NOT NULL,
+            pic TEXT NOT NULL,
+            bio TEXT NOT NULL DEFAULT '',
+            date_joined TEXT NOT NULL,
+            language TEXT,
+            timezone TEXT NOT NULL DEFAULT 'UTC',
+            locale TEXT
+        )",
+        // `seq` keeps posts made at the same moment in the order they were saved.
+        "CREATE TABLE posts (
+            seq INTEGER PRIMARY KEY,
+            id TEXT NOT NULL UNIQUE,
+            user_id TEXT NOT NULL,
+            text TEXT NOT NULL,
+            link TEXT,
+            created TEXT NOT NULL
+        )",
+        "CREATE INDEX posts_by_user ON posts (user_id, created, seq)",
+        "CREATE TABLE followers (
+            user_id TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            inbox TEXT NOT NULL,
+            PRIMARY KEY (user_id, actor)
+        )",
+        "CREATE INDEX followers_by_actor ON followers (actor)",
+    ],
+    // 2: moderation.
+    &[
+        "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user'",
+        "ALTER TABLE users ADD COLUMN suspended INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE posts ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0",
+        "CREATE TABLE reports (
+            id TEXT PRIMARY KEY,
+            post_id TEXT NOT NULL,
+            author_id TEXT NOT NULL,
+            reporter_id TEXT,
+            reason TEXT NOT NULL,
+            text TEXT NOT NULL,
+            created TEXT NOT NULL
+        )",
+        "CREATE INDEX reports_by_post ON reports (post_id)",
+        "CREATE INDEX reports_by_author ON reports (author_id)",
+    ],
+    // 3: private accounts.
+    &[
+        "ALTER TABLE users ADD COLUMN is_private INTEGER NOT NULL DEFAULT 0",
+        "CREATE TABLE follow_requests (
+            user_id TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            inbox TEXT NOT NULL,
+            activity TEXT NOT NULL,
+            created TEXT NOT NULL,
+            PRIMARY KEY (user_id, actor)
+        )",
+    ],
+    // 4: who can read each post.
+    &["ALTER TABLE posts ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public'"],
+    // 5: quiet users following each other.
+    &["CREATE TABLE follows (
+            follower_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY (follower_id, user_id)
+        )"],
+];
+
+/// SQLite has no booleans, so these columns hold 0 or 1, which are made
+/// into `false` and `true` when rows are read.
+const BOOLEAN_COLUMNS: &[&str] = &["hidden", "suspended", "is_private"];
+
+/// What a parameter of a statement can be.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Text(String),
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Integer(b as i64)
+    }
+}
+
+impl From<Role> for Value {
+    fn from(role: Role) -> Self {
+        Value::Text(
+            match role {
+                Role::User => "user",
+                Role::Moderator => "moderator",
+                Role::Admin => "admin",
+            }
+            .to_owned(),
+        )
+    }
+}
+
+impl From<Visibility> for Value {
+    fn from(visibility: Visibility) -> Self {
+        Value::Text(
+            match visibility {
+                Visibility::Public => "public",
+                Visibility::Unlisted => "unlisted",
+                Visibility::FollowersOnly => "followers-only",
+            }
+            .to_owned(),
+        )
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_owned())
+    }
+}
+
+impl From<Uuid> for Value {
+    fn from(id: Uuid) -> Self {
+        Value::Text(id.to_string())
+    }
+}
+
+impl From<&Url> for Value {
+    fn from(url: &Url) -> Self {
+        Value::Text(url.to_string())
+    }
+}
+
+/// Dates are written so they sort as text, which is how SQLite compares them.
+impl From<DateTime<Utc>> for Value {
+    fn from(date: DateTime<Utc>) -> Self {
+        Value::Text(date.to_rfc3339_opts(SecondsFormat::Nanos, true))
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(val: Option<T>) -> Self {
+        val.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+
+/// A row of results, by column name.
+pub type Row = serde_json::Map<String, serde_json::Value>;
+
+/// One statement and its parameters, which it refers to as `?1`, `?2`...
+pub type Statement<'a> = (&'a str, Vec<Value>);
+
+/// Somewhere that runs SQL.
+pub trait Database {
+    fn query<'a>(
+        &'a self,
+        sql: &'a str,
+        params: Vec<Value>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Row>, String>>;
+
+    /// Runs `statements` in one transaction, so either they all happen or
+    /// none do. Returns how many rows each one changed.
+    fn batch<'a>(
+        &'a self,
+        statements: Vec<Statement<'a>>,
+    ) -> LocalBoxFuture<'a, Result<Vec<usize>, String>>;
+}
+
+/// Brings the database's tables up to date. Returns how many migrations
+/// were applied.
+pub async fn migrate(db: &impl Database) -> Result<usize, String> {
+    let create = "CREATE TABLE IF NOT EXISTS migrations (
+        version INTEGER PRIMARY KEY,
+        applied TEXT NOT NULL
+    )";
+    db.batch(vec![(create, vec![])]).await?;
+    let rows = db
+        .query(
+            "SELECT COALESCE(MAX(version), 0) AS version FROM migrations",
+            vec![],
+        )
+        .await?;
+    let current = rows
+        .first()
+        .and_then(|row| row.get("version"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    if current > MIGRATIONS.len() {
+        return Err(format!(
+            "database is at version {}, newer than this code knows",
+            current
+        ));
+    }
+    for (i, statements) in MIGRATIONS.iter().enumerate().skip(current) {
+        let mut batch: Vec<Statement> = statements.iter().map(|sql| (*sql, vec![])).collect();
+        // If another worker is migrating too, one of them fails to insert
+        // this and its whole batch is rolled back.
+        batch.push((
+            "INSERT INTO migrations (version, applied) VALUES (?1, ?2)",
+            vec![Value::Integer(i as i64 + 1), Utc::now().into()],
+        ));
+        db.batch(batch).await?;
+    }
+    Ok(MIGRATIONS.len() - current)
+}
+
+fn read_rows<T: DeserializeOwned>(rows: Vec<Row>) -> Result<Vec<T>, String> {
+    rows.into_iter()
+        .map(|mut row| {
+            for column in BOOLEAN_COLUMNS {
+                if let Some(val) = row.get_mut(*column) {
+                    if let Some(i) = val.as_i64() {
+                        *val = (i != 0).into();
+                    }
+                }
+            }
+            serde_json::from_value(serde_json::Value::Object(row)).map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+fn failed(external: &'static str) -> impl Fn(String) -> Error {
+    move |internal| Error {
+        internal,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new(external),
+    }
+}
+
+const POST_COLUMNS: &str = "posts.id, posts.user_id, posts.text, posts.link, posts.created, \
+    posts.hidden, posts.visibility";
+
+/// Saves new posts.
+pub async fn add_posts(db: &impl Database, posts: &[Post]) -> Fallible<()> {
+    let statements = posts
+        .iter()
+        .map(|post| {
+            let sql = "INSERT INTO posts (id, user_id, text, link, created, hidden, visibility)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+            let params = vec![
+                post.id.into(),
+                post.user_id.into(),
+                post.text.as_str().into(),
+                post.link.as_ref().into(),
+                post.created.into(),
+                post.hidden.into(),
+                post.visibility.into(),
+            ];
+            (sql, params)
+        })
+        .collect();
+    db.batch(statements)
+        .await
+        .map_err(failed("error-save-post"))?;
+    Ok(())
+}
+
+/// All the user's posts, oldest first.
+pub async fn posts_by_user(db: &impl Database, user_id: Uuid) -> Fallible<Vec<Post>> {
+    let sql = format!(
+        "SELECT {} FROM posts WHERE user_id = ?1 ORDER BY created, seq",
+        POST_COLUMNS
+    );
+    let rows = db.query(&sql, vec![user_id.into()]).await;
+    rows.and_then(read_rows).map_err(failed("error-load-posts"))
+}
+
+/// Posts by the quiet users `user_id` follows, oldest first.
+pub async fn posts_followed_by(db: &impl Database, user_id: Uuid) -> Fallible<Vec<Post>> {
+    let sql = format!(
+        "SELECT {} FROM posts JOIN follows ON follows.user_id = posts.user_id
+        WHERE follows.follower_id = ?1
+        ORDER BY posts.created, posts.seq",
+        POST_COLUMNS
+    );
+    let rows = db.query(&sql, vec![user_id.into()]).await;
+    rows.and_then(read_rows).map_err(failed("error-load-posts"))
+}
+
+pub async fn set_hidden(db: &impl Database, user_id: Uuid, id: Uuid, hidden: bool) -> Fallible<()> {
+    let sql = "UPDATE posts SET hidden = ?3 WHERE user_id = ?1 AND id = ?2";
+    db.batch(vec![(sql, vec![user_id.into(), id.into(), hidden.into()])])
+        .await
+        .map_err(failed("error-save-post"))?;
+    Ok(())
+}
+
+pub async fn delete_posts_by_user(db: &impl Database, user_id: Uuid) -> Fallible<()> {
+    let sql = "DELETE FROM posts WHERE user_id = ?1";
+    db.batch(vec![(sql, vec![user_id.into()])])
+        .await
+        .map_err(failed("error-delete-account"))?;
+    Ok(())
+}
+
+/// Saves a new or changed profile, unless someone else has its username.
+pub async fn put_profile(db: &impl Database, profile: &Profile) -> Fallible<()> {
+    // Checking here, rather than only relying on the UNIQUE constraint,
+    // tells a taken username apart from other failures.
+    let sql = "INSERT INTO users
+            (id, username, email, pic, bio, date_joined, language, timezone, locale,
+            role, suspended, is_private)
+        SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12
+        WHERE NOT EXISTS (SELECT 1 FROM users WHERE username = ?2 AND id != ?1)
+        ON CONFLICT (id) DO UPDATE SET
+            username = excluded.username,
+            email = excluded.email,
+            pic = excluded.pic,
+            bio = excluded.bio,
+            language = excluded.language,
+            timezone = excluded.timezone,
+            locale = excluded.locale,
+            role = excluded.role,
+            suspended = excluded.suspended,
+            is_private = excluded.is_private";
+    let params = vec![
+        profile.id.into(),
+        profile.username.as_str().into(),
+        profile.email.as_str().into(),
+        (&profile.pic).into(),
+        profile.bio.as_str().into(),
+        profile.date_joined.into(),
+        profile.language.as_deref().into(),
+        profile.timezone.as_str().into(),
+        profile.locale.as_deref().into(),
+        profile.role.into(),
+        profile.suspended.into(),
+        profile.is_private.into(),
+    ];
+    let changed = db
+        .batch(vec![(sql, params)])
+        .await
+        .map_err(failed("error-save-profile"))?;
+    if changed.first() == Some(&0) {
+        return Err(Error {
+            internal: format!("username {} is taken", profile.username),
+            external: Message::new("error-username-taken"),
+            status: StatusCode::CONFLICT,
+        });
+    }
+    Ok(())
+}
+
+const PROFILE_COLUMNS: &str = "id, username, email, pic, bio, date_joined, language, timezone, \
+    locale, role, suspended, is_private";
+
+pub async fn profile_by_id(db: &impl Database, id: Uuid) -> Fallible<Option<Profile>> {
+    let sql = format!("SELECT {} FROM users WHERE id = ?1", PROFILE_COLUMNS);
+    let rows = db.query(&sql, vec![id.into()]).await;
+    let profiles = rows
+        .and_then(read_rows)
+        .map_err(failed("error-load-profile"))?;
+    Ok(profiles.into_iter().next())
+}
+
+/// Usernames are matched case-insensitively.
+pub async fn profile_by_username(db: &impl Database, username: &str) -> Fallible<Option<Profile>> {
+    let sql = format!("SELECT {} FROM users WHERE username = ?1", PROFILE_COLUMNS);
+    let rows = db.query(&sql, vec![username.into()]).await;
+    let profiles = rows
+        .and_then(read_rows)
+        .map_err(failed("error-load-profile"))?;
+    Ok(profiles.into_iter().next())
+}
+
+pub async fn delete_profile(db: &impl Database, id: Uuid) -> Fallible<()> {
+    let sql = "DELETE FROM users WHERE id = ?1";
+    db.batch(vec![(sql, vec![id.into()])])
+        .await
+        .map_err(failed("error-delete-account"))?;
+    Ok(())
+}
+
+pub async fn followers_of(db: &impl Database, user_id: Uuid) -> Fallible<Vec<Follower>> {
+    let sql = "SELECT actor, inbox FROM followers WHERE user_id = ?1 ORDER BY rowid";
+    let rows = db.query(sql, vec![user_id.into()]).await;
+    rows.and_then(read_rows)
+        .map_err(failed("error-load-followers"))
+}
+
+/// Following twice is the same as following once, but the inbox is updated.
+pub async fn add_follower(db: &impl Database, user_id: Uuid, follower: &Follower) -> Fallible<()> {
+    let sql = "INSERT INTO followers (user_id, actor, inbox) VALUES (?1, ?2, ?3)
+        ON CONFLICT (user_id, actor) DO UPDATE SET inbox = excluded.inbox";
+    let params = vec![
+        user_id.into(),
+        (&follower.actor).into(),
+        (&follower.inbox).into(),
+    ];
+    db.batch(vec![(sql, params)])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+pub async fn remove_follower(db: &impl Database, user_id: Uuid, actor: &Url) -> Fallible<()> {
+    let sql = "DELETE FROM followers WHERE user_id = ?1 AND actor = ?2";
+    db.batch(vec![(sql, vec![user_id.into(), actor.into()])])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+/// Forgets the user's followers, the follow requests waiting for them, and
+/// who they follow and who follows them on quiet.
+pub async fn delete_followers(db: &impl Database, user_id: Uuid) -> Fallible<()> {
+    db.batch(vec![
+        (
+            "DELETE FROM followers WHERE user_id = ?1",
+            vec![user_id.into()],
+        ),
+        (
+            "DELETE FROM follow_requests WHERE user_id = ?1",
+            vec![user_id.into()],
+        ),
+        (
+            "DELETE FROM follows WHERE follower_id = ?1 OR user_id = ?1",
+            vec![user_id.into()],
+        ),
+    ])
+    .await
+    .map_err(failed("error-delete-account"))?;
+    Ok(())
+}
+
+/// Oldest first.
+pub async fn follow_requests_of(db: &impl Database, user_id: Uuid) -> Fallible<Vec<FollowRequest>> {
+    let sql = "SELECT actor, inbox, activity, created FROM follow_requests
+        WHERE user_id = ?1 ORDER BY created, rowid";
+    let rows = db.query(sql, vec![user_id.into()]).await;
+    rows.and_then(read_rows)
+        .map_err(failed("error-load-followers"))
+}
+
+/// Asking twice replaces the first request.
+pub async fn add_follow_request(
+    db: &impl Database,
+    user_id: Uuid,
+    request: &FollowRequest,
+) -> Fallible<()> {
+    let sql = "INSERT INTO follow_requests (user_id, actor, inbox, activity, created)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (user_id, actor) DO UPDATE SET
+            inbox = excluded.inbox,
+            activity = excluded.activity,
+            created = excluded.created";
+    let params = vec![
+        user_id.into(),
+        (&request.actor).into(),
+        (&request.inbox).into(),
+        request.activity.as_str().into(),
+        request.created.into(),
+    ];
+    db.batch(vec![(sql, params)])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+pub async fn remove_follow_request(db: &impl Database, user_id: Uuid, actor: &Url) -> Fallible<()> {
+    let sql = "DELETE FROM follow_requests WHERE user_id = ?1 AND actor = ?2";
+    db.batch(vec![(sql, vec![user_id.into(), actor.into()])])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+/// The quiet users `user_id` follows, in the order they followed them.
+pub async fn following_of(db: &impl Database, user_id: Uuid) -> Fallible<Vec<Uuid>> {
+    #[derive(Deserialize)]
+    struct Followed {
+        user_id: Uuid,
+    }
+    let sql = "SELECT user_id FROM follows WHERE follower_id = ?1 ORDER BY rowid";
+    let rows = db.query(sql, vec![user_id.into()]).await;
+    let followed: Vec<Followed> = rows
+        .and_then(read_rows)
+        .map_err(failed("error-load-followers"))?;
+    Ok(followed.into_iter().map(|f| f.user_id).collect())
+}
+
+/// Following twice is the same as following once.
+pub async fn follow(db: &impl Database, user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    let sql = "INSERT INTO follows (follower_id, user_id) VALUES (?1, ?2)
+        ON CONFLICT (follower_id, user_id) DO NOTHING";
+    db.batch(vec![(sql, vec![user_id.into(), followed.into()])])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+pub async fn unfollow(db: &impl Database, user_id: Uuid, followed: Uuid) -> Fallible<()> {
+    let sql = "DELETE FROM follows WHERE follower_id = ?1 AND user_id = ?2";
+    db.batch(vec![(sql, vec![user_id.into(), followed.into()])])
+        .await
+        .map_err(failed("error-save-followers"))?;
+    Ok(())
+}
+
+pub async fn add_report(db: &impl Database, report: &Report) -> Fallible<()> {
+    let sql = "INSERT INTO reports (id, post_id, author_id, reporter_id, reason, text, created)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+    let params = vec![
+        report.id.into(),
+        report.post_id.into(),
+        report.author_id.into(),
+        report.reporter_id.into(),
+        report.reason.as_str().into(),
+        report.text.as_str().into(),
+        report.created.into(),
+    ];
+    db.batch(vec![(sql, params)])
+        .await
+        .map_err(failed("error-save-report"))?;
+    Ok(())
+}
+
+/// Every report waiting for a moderator, oldest first.
+pub async fn reports(db: &impl Database) -> Fallible<Vec<Report>> {
+    let sql = "SELECT id, post_id, author_id, reporter_id, reason, text, created
+        FROM reports ORDER BY created, rowid";
+    let rows = db.query(sql, vec![]).await;
+    rows.and_then(read_rows)
+        .map_err(failed("error-load-reports"))
+}
+
+pub async fn delete_reports_of_post(db: &impl Database, post_id: Uuid) -> Fallible<()> {
+    let sql = "DELETE FROM reports WHERE post_id = ?1";
+    db.batch(vec![(sql, vec![post_id.into()])])
+        .await
+        .map_err(failed("error-save-report"))?;
+    Ok(())
+}
+
+pub async fn delete_reports_of_author(db: &impl Database, author_id: Uuid) -> Fallible<()> {
+    let sql = "DELETE FROM reports WHERE author_id = ?1";
+    db.batch(vec![(sql, vec![author_id.into()])])
+        .await
+        .map_err(failed("error-save-report"))?;
+    Ok(())
+}
+
+#[cfg(feature = "sql")]
+pub use d1::database;
+
+#[cfg(feature = "sql")]
+mod d1 {
+    use super::*;
+    use futures::future::FutureExt;
+    use js_sys::{Array, Promise};
+    use serde::Deserialize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen_futures::JsFuture;
+
+    /// The D1 database bound as `QuietDb` in `wrangler.toml`.
+    pub struct D1;
+
+    static MIGRATED: AtomicBool = AtomicBool::new(false);
+
+    /// The database, with its tables up to date.
+    pub async fn database() -> Fallible<D1> {
+        if !MIGRATED.load(Ordering::Relaxed) {
+            migrate(&D1).await.map_err(|e| Error {
+                internal: format!("couldn't migrate database: {}", e),
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                external: Message::new("error-migrate-database"),
+            })?;
+            MIGRATED.store(true, Ordering::Relaxed);
+        }
+        Ok(D1)
+    }
+
+    fn prepare(sql: &str, params: Vec<Value>) -> D1Statement {
+        let params: Vec<JsValue> = params
+            .into_iter()
+            .map(|param| match param {
+                Value::Null => JsValue::NULL,
+                Value::Integer(i) => JsValue::from_f64(i as f64),
+                Value::Text(s) => JsValue::from_str(&s),
+            })
+            .collect();
+        QuietDb::prepare(sql).bind(params.into_boxed_slice())
+    }
+
+    #[derive(Deserialize)]
+    struct Results {
+        results: Vec<Row>,
+    }
+
+    #[derive(Deserialize)]
+    struct Changes {
+        meta: Meta,
+    }
+
+    #[derive(Deserialize)]
+    struct Meta {
+        changes: usize,
+    }
+
+    impl Database for D1 {
+        fn query<'a>(
+            &'a self,
+            sql: &'a str,
+            params: Vec<Value>,
+        ) -> LocalBoxFuture<'a, Result<Vec<Row>, String>> {
+            async move {
+                let val = JsFuture::from(prepare(sql, params).all())
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                let results: Results = crate::utils::from_js(&val).map_err(|e| e.to_string())?;
+                Ok(results.results)
+            }
+            .boxed_local()
+        }
+
+        fn batch<'a>(
+            &'a self,
+            statements: Vec<Statement<'a>>,
+        ) -> LocalBoxFuture<'a, Result<Vec<usize>, String>> {
+            async move {
+                let prepared: Array = statements
+                    .into_iter()
+                    .map(|(sql, params)| JsValue::from(prepare(sql, params)))
+                    .collect();
+                let val = JsFuture::from(QuietDb::batch(&prepared))
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                let results: Vec<Changes> =
+                    crate::utils::from_js(&val).map_err(|e| e.to_string())?;
+                Ok(results.into_iter().map(|r| r.meta.changes).collect())
+            }
+            .boxed_local()
+        }
+    }
+
+    #[wasm_bindgen]
+    extern "C" {
+        type QuietDb;
+        type D1Statement;
+
+        #[wasm_bindgen(static_method_of = QuietDb)]
+        fn prepare(sql: &str) -> D1Statement;
+
+        #[wasm_bindgen(static_method_of = QuietDb)]
+        fn batch(statements: &Array) -> Promise;
+
+        #[wasm_bindgen(method, variadic)]
+        fn bind(this: &D1Statement, params: Box<[JsValue]>) -> D1Statement;
+
+        #[wasm_bindgen(method)]
+        fn all(this: &D1Statement) -> Promise;
+    }
+}
+
+/// SQLite in memory, standing in for D1.
+#[cfg(test)]
+pub mod sqlite {
+    use super::*;
+    use futures::future::{ready, FutureExt};
+    use rusqlite::types::{ToSql, ToSqlOutput, ValueRef};
+    use rusqlite::Connection;
+    use std::cell::RefCell;
+
+    pub struct Sqlite(RefCell<Connection>);
+
+    impl Sqlite {
+        /// A new, empty database, with its tables made.
+        pub fn new() -> Self {
+            let db = Sqlite(RefCell::new(Connection::open_in_memory().unwrap()));
+            futures::executor::block_on(migrate(&db)).unwrap();
+            db
+        }
+    }
+
+    impl ToSql for Value {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            Ok(match self {
+                Value::Null => ToSqlOutput::Owned(rusqlite::types::Value::Null),
+                Value::Integer(i) => ToSqlOutput::Owned(rusqlite::types::Value::Integer(*i)),
+                Value::Text(s) => ToSqlOutput::Borrowed(ValueRef::Text(s.as_bytes())),
+            })
+        }
+    }
+
+    fn json(val: ValueRef) -> serde_json::Value {
+        match val {
+            ValueRef::Null => serde_json::Value::Null,
+            ValueRef::Integer(i) => i.into(),
+            ValueRef::Real(f) => f.into(),
+            ValueRef::Text(s) => String::from_utf8_lossy(s).into(),
+            ValueRef::Blob(b) => b.to_vec().into(),
+        }
+    }
+
+    fn query(conn: &Connection, sql: &str, params: Vec<Value>) -> rusqlite::Result<Vec<Row>> {
+        let mut stmt = conn.prepare(sql)?;
+        let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+        let mut rows = stmt.query(params)?;
+        let mut found = Vec::new();
+        while let Some(row) = rows.next()? {
+            let mut read = Row::new();
+            for (i, column) in columns.iter().enumerate() {
+                read.insert(column.clone(), json(row.get_raw(i)));
+            }
+            found.push(read);
+        }
+        Ok(found)
+    }
+
+    fn batch(conn: &mut Connection, statements: Vec<Statement>) -> rusqlite::Result<Vec<usize>> {
+        let tx = conn.transaction()?;
+        let changed = statements
+            .into_iter()
+            .map(|(sql, params)| tx.execute(sql, params))
+            .collect::<rusqlite::Result<_>>()?;
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    impl Database for Sqlite {
+        fn query<'a>(
+            &'a self,
+            sql: &'a str,
+            params: Vec<Value>,
+        ) -> LocalBoxFuture<'a, Result<Vec<Row>, String>> {
+            let rows = query(&self.0.borrow(), sql, params).map_err(|e| e.to_string());
+            ready(rows).boxed_local()
+        }
+
+        fn batch<'a>(
+            &'a self,
+            statements: Vec<Statement<'a>>,
+        ) -> LocalBoxFuture<'a, Result<Vec<usize>, String>> {
+            let changed = batch(&mut self.0.borrow_mut(), statements).map_err(|e| e.to_string());
+            ready(changed).boxed_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sqlite::Sqlite;
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use futures::executor::block_on;
+
+    fn profile(username: &str) -> Profile {
+        Profile {
+            locale: Some("en-GB".to_owned()),
+            ..Profile::fixture(username)
+        }
+    }
+
+    fn post(user_id: Uuid, text: &str, created: DateTime<Utc>) -> Post {
+        Post {
+            user_id,
+            created,
+            ..Post::fixture(text)
+        }
+    }
+
+    #[test]
+    fn migrations_only_run_once() {
+        let db = Sqlite::new();
+        assert_eq!(block_on(migrate(&db)).unwrap(), 0);
+    }
+
+    #[test]
+    fn posts_read_back_in_order() {
+        let db = Sqlite::new();
+        let ada = Uuid::new_v4();
+        let now = Utc::now();
+        block_on(async {
+            let second = post(ada, "second", now);
+            let mut first = post(ada, "first", now - Duration::days(1));
+            first.link = Some(Url::parse("https://example.com/").unwrap());
+            let mut same_moment = post(ada, "third", now);
+            same_moment.visibility = Visibility::FollowersOnly;
+            add_posts(&db, &[second, same_moment]).await.unwrap();
+            add_posts(&db, &[first]).await.unwrap();
+            add_posts(&db, &[post(Uuid::new_v4(), "not ada's", now)])
+                .await
+                .unwrap();
+
+            let posts = posts_by_user(&db, ada).await.unwrap();
+            let texts: Vec<_> = posts.iter().map(|p| p.text.as_str()).collect();
+            assert_eq!(texts, ["first", "second", "third"]);
+            assert_eq!(
+                posts[0].link.as_ref().unwrap().as_str(),
+                "https://example.com/"
+            );
+            assert_eq!(posts[1].created, now);
+            let visibilities: Vec<_> = posts.iter().map(|p| p.visibility).collect();
+            assert_eq!(
+                visibilities,
+                [
+                    Visibility::Public,
+                    Visibility::Public,
+                    Visibility::FollowersOnly
+                ]
+            );
+
+            delete_posts_by_user(&db, ada).await.unwrap();
+            assert!(posts_by_user(&db, ada).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn usernames_are_unique_whatever_their_case() {
+        let db = Sqlite::new();
+        block_on(async {
+            let mut ada = profile("ada");
+            put_profile(&db, &ada).await.unwrap();
+            let err = put_profile(&db, &profile("ADA")).await.unwrap_err();
+            assert_eq!(err.status, StatusCode::CONFLICT);
+
+            ada.bio = "hi".to_owned();
+            put_profile(&db, &ada).await.unwrap();
+            let found = profile_by_username(&db, "Ada").await.unwrap().unwrap();
+            assert_eq!(found.id, ada.id);
+            assert_eq!(found.bio, "hi");
+            assert_eq!(found.locale.as_deref(), Some("en-GB"));
+
+            delete_profile(&db, ada.id).await.unwrap();
+            assert!(profile_by_id(&db, ada.id).await.unwrap().is_none());
+            put_profile(&db, &profile("ada")).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn followers_are_added_once() {
+        let db = Sqlite::new();
+        let bob = Url::parse("https://remote.example/users/bob").unwrap();
+        let follower = Follower {
+            actor: bob.clone(),
+            inbox: Url::parse("https://remote.example/inbox").unwrap(),
+        };
+        let (ada, cy) = (Uuid::new_v4(), Uuid::new_v4());
+        block_on(async {
+            add_follower(&db, ada, &follower).await.unwrap();
+            add_follower(&db, ada, &follower).await.unwrap();
+            add_follower(&db, cy, &follower).await.unwrap();
+            assert_eq!(followers_of(&db, ada).await.unwrap().len(), 1);
+            remove_follower(&db, cy, &bob).await.unwrap();
+            assert!(followers_of(&db, cy).await.unwrap().is_empty());
+            delete_followers(&db, ada).await.unwrap();
+            assert!(followers_of(&db, ada).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn posts_by_people_someone_follows() {
+        let db = Sqlite::new();
+        let (ada, cy, dee, eve) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        let noon = Utc.ymd(2020, 9, 2).and_hms(12, 0, 0);
+        block_on(async {
+            follow(&db, eve, cy).await.unwrap();
+            follow(&db, eve, ada).await.unwrap();
+            follow(&db, eve, ada).await.unwrap();
+            add_posts(
+                &db,
+                &[
+                    post(ada, "ada later", noon + Duration::hours(1)),
+                    post(cy, "cy", noon),
+                    post(dee, "not followed", noon + Duration::hours(2)),
+                    post(eve, "eve's own", noon + Duration::hours(3)),
+                ],
+            )
+            .await
+            .unwrap();
+
+            let posts = posts_followed_by(&db, eve).await.unwrap();
+            let texts: Vec<_> = posts.iter().map(|p| p.text.as_str()).collect();
+            assert_eq!(texts, ["cy", "ada later"]);
+            assert_eq!(following_of(&db, eve).await.unwrap(), [cy, ada]);
+
+            unfollow(&db, eve, cy).await.unwrap();
+            assert_eq!(following_of(&db, eve).await.unwrap(), [ada]);
+            delete_followers(&db, ada).await.unwrap();
+            assert!(posts_followed_by(&db, eve).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn private_accounts_keep_follow_requests() {
+        let db = Sqlite::new();
+        let bob = Url::parse("https://remote.example/users/bob").unwrap();
+        let request = FollowRequest {
+            actor: bob.clone(),
+            inbox: Url::parse("https://remote.example/inbox").unwrap(),
+            activity: r#"{"type":"Follow"}"#.to_owned(),
+            created: Utc.ymd(2020, 9, 1).and_hms(12, 0, 0),
+        };
+        block_on(async {
+            let mut ada = profile("ada");
+            ada.is_private = true;
+            put_profile(&db, &ada).await.unwrap();
+            assert!(
+                profile_by_id(&db, ada.id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .is_private
+            );
+
+            add_follow_request(&db, ada.id, &request).await.unwrap();
+            add_follow_request(&db, ada.id, &request).await.unwrap();
+            let waiting = follow_requests_of(&db, ada.id).await.unwrap();
+            assert_eq!(waiting.len(), 1);
+            assert_eq!(
+                (&waiting[0].actor, waiting[0].activity.as_str()),
+                (&bob, r#"{"type":"Follow"}"#)
+            );
+            remove_follow_request(&db, ada.id, &bob).await.unwrap();
+            assert!(follow_requests_of(&db, ada.id).await.unwrap().is_empty());
+
+            add_follow_request(&db, ada.id, &request).await.unwrap();
+            delete_followers(&db, ada.id).await.unwrap();
+            assert!(follow_requests_of(&db, ada.id).await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn moderation_is_saved() {
+        let db = Sqlite::new();
+        block_on(async {
+            let mut ada = profile("ada");
+            put_profile(&db, &ada).await.unwrap();
+            ada.role = Role::Moderator;
+            ada.suspended = true;
+            put_profile(&db, &ada).await.unwrap();
+            let found = profile_by_id(&db, ada.id).await.unwrap().unwrap();
+            assert_eq!((found.role, found.suspended), (Role::Moderator, true));
+
+            let (spam, fine) = (
+                post(ada.id, "spam", Utc::now()),
+                post(ada.id, "fine", Utc::now()),
+            );
+            add_posts(&db, &[spam.clone(), fine]).await.unwrap();
+            let hidden = || async {
+                posts_by_user(&db, ada.id)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|p| (p.text, p.hidden))
+                    .collect::<Vec<_>>()
+            };
+            set_hidden(&db, ada.id, spam.id, true).await.unwrap();
+            assert_eq!(
+                hidden().await,
+                [("spam".to_owned(), true), ("fine".to_owned(), false)]
+            );
+            // A moderator can change their mind.
+            set_hidden(&db, ada.id, spam.id, false).await.unwrap();
+            assert_eq!(
+                hidden().await,
+                [("spam".to_owned(), false), ("fine".to_owned(), false)]
+            );
+
+            let report = Report::new(spam.id, ada.id, None, "ads".to_owned(), spam.text).unwrap();
+            add_report(&db, &report).await.unwrap();
+            let open = reports(&db).await.unwrap();
+            assert_eq!(open.len(), 1);
+            assert_eq!(
+                (open[0].reason.as_str(), open[0].reporter_id),
+                ("ads", None)
+            );
+            delete_reports_of_post(&db, spam.id).await.unwrap();
+            assert!(reports(&db).await.unwrap().is_empty());
+            add_report(&db, &report).await.unwrap();
+            delete_reports_of_author(&db, ada.id).await.unwrap();
+            assert!(reports(&db).await.unwrap().is_empty());
+        });
+    }
+}
@@ -0,0 +1,431 @@
+//! How models are laid out in KV. Every value is MessagePack wrapped in a
+//! small envelope saying which version of its schema it was written with,
+//! so a value written by older code can be migrated when it's read, rather
+//! than silently failing to decode.
+//!
+//! The envelope is the byte 0xc1, which MessagePack never uses, then the
+//! version, then the value with named fields. Values written before there
+//! was an envelope are version 0: bare MessagePack with positional fields.
+//!
+//! Adding a field with `#[serde(default)]` doesn't need a new version.
+//! Renaming, removing or changing the type of one does: bump `VERSION`,
+//! copy the old shape into a frozen struct that `migrate` reads, and add a
+//! fixture of the old bytes to the tests below.
+//!
+//! Values that are read, changed and written back, like a user's posts, go
+//! through a `Store` and `update`, so two requests changing the same value
+//! at once can't silently undo each other.
+//!
+//! With the `sql` feature, none of this is used: models are rows instead.
+#![cfg_attr(feature = "sql", allow(dead_code))]
+use futures::future::LocalBoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+const MARKER: u8 = 0xc1;
+
+/// How many times `update` starts again after losing a race with another
+/// write, before giving up.
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+/// Something stored in KV, and the history of how it has been laid out.
+pub trait Schema: Serialize + DeserializeOwned {
+    /// The version values are written with.
+    const VERSION: u8;
+
+    /// Reads the payload of a value written with `version`, which is older
+    /// than `VERSION`, into the current shape.
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, String>;
+}
+
+pub fn encode<T: Schema>(val: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![MARKER, T::VERSION];
+    rmp_serde::encode::write_named(&mut bytes, val).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+pub fn decode<T: Schema>(bytes: &[u8]) -> Result<T, String> {
+    match bytes {
+        [MARKER, version, payload @ ..] if *version == T::VERSION => {
+            rmp_serde::from_read_ref(payload).map_err(|e| e.to_string())
+        }
+        [MARKER, version, payload @ ..] if *version < T::VERSION => T::migrate(*version, payload),
+        [MARKER, version, ..] => Err(format!(
+            "written with schema version {}, but this code only knows up to {}",
+            version,
+            T::VERSION
+        )),
+        unversioned => T::migrate(0, unversioned),
+    }
+}
+
+/// Whether `bytes` were written with the current version of `T`'s schema,
+/// so reading them doesn't need `migrate`.
+pub fn is_current<T: Schema>(bytes: &[u8]) -> bool {
+    matches!(bytes, [MARKER, version, ..] if *version == T::VERSION)
+}
+
+/// For `migrate` to read a version whose shape hasn't changed since.
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    rmp_serde::from_read_ref(payload).map_err(|e| e.to_string())
+}
+
+/// A stored value, with a tag that changes whenever the value does.
+pub struct Versioned {
+    pub bytes: Vec<u8>,
+    pub etag: String,
+}
+
+impl Versioned {
+    /// Tags `bytes` by their hash, for stores that don't tag values themselves.
+    #[cfg_attr(all(feature = "durable-timelines", not(test)), allow(dead_code))]
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let digest = Sha256::digest(&bytes);
+        let etag = base64::encode_config(&digest[..16], base64::URL_SAFE_NO_PAD);
+        Self { bytes, etag }
+    }
+}
+
+/// Somewhere values are kept by key, which can refuse a write if the value
+/// changed since it was read.
+pub trait Store {
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>>;
+
+    /// Saves `bytes` under `key` if the value there still has tag `etag`,
+    /// or if `etag` is None, if there's no value there. Returns whether it
+    /// saved them.
+    fn put_if_match<'a>(
+        &'a self,
+        key: &'a str,
+        bytes: Vec<u8>,
+        etag: Option<&'a str>,
+    ) -> LocalBoxFuture<'a, Result<bool, String>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// Other writes to the value kept winning, so we gave up.
+    Conflict,
+    Failed(String),
+}
+
+/// Reads the value under `key` from `store`, or `T::default()` if there
+/// isn't one, and saves it with `change` made to it. If something else
+/// saved the value in between, starts again with what they saved, so
+/// neither change is lost. Returns the value as saved.
+pub async fn update<S, T, F>(store: &S, key: &str, mut change: F) -> Result<T, UpdateError>
+where
+    S: Store,
+    T: Schema + Default,
+    F: FnMut(&mut T),
+{
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        let current = store.get(key).await.map_err(UpdateError::Failed)?;
+        let (mut val, etag) = match current {
+            Some(current) => (
+                decode(&current.bytes).map_err(UpdateError::Failed)?,
+                Some(current.etag),
+            ),
+            None => (T::default(), None),
+        };
+        change(&mut val);
+        let bytes = encode(&val).map_err(UpdateError::Failed)?;
+        if store
+            .put_if_match(key, bytes, etag.as_deref())
+            .await
+            .map_err(UpdateError::Failed)?
+        {
+            return Ok(val);
+        }
+    }
+    Err(UpdateError::Conflict)
+}
+
+/// Writes `stored`, the value under `key`, back in the current version if
+/// it was written with an older one, so it's only migrated once.
+pub async fn upgrade<S, T>(store: &S, key: &str, stored: &Versioned) -> Result<(), UpdateError>
+where
+    S: Store,
+    T: Schema + Default,
+{
+    if is_current::<T>(&stored.bytes) {
+        return Ok(());
+    }
+    update(store, key, |_: &mut T| {}).await.map(|_| ())
+}
+
+#[cfg(test)]
+pub mod memory {
+    //! A store in memory, for tests.
+    use super::*;
+    use futures::future::FutureExt;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    #[derive(Default)]
+    pub struct MemoryStore {
+        values: RefCell<HashMap<String, Vec<u8>>>,
+        /// How many writes were refused because the value had changed.
+        pub conflicts: Cell<usize>,
+    }
+
+    impl Store for MemoryStore {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            // Reads what's there now, but finishes later, like a real
+            // store does, so other requests get a chance to run meanwhile.
+            let val = self.values.borrow().get(key).cloned().map(Versioned::new);
+            YieldOnce(false).map(move |_| Ok(val)).boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            let mut values = self.values.borrow_mut();
+            let current = values.get(key).cloned().map(Versioned::new);
+            let saved = if current.as_ref().map(|v| v.etag.as_str()) == etag {
+                values.insert(key.to_owned(), bytes);
+                true
+            } else {
+                self.conflicts.set(self.conflicts.get() + 1);
+                false
+            };
+            futures::future::ready(Ok(saved)).boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            self.values.borrow_mut().remove(key);
+            futures::future::ready(Ok(())).boxed_local()
+        }
+    }
+
+    /// Returns Pending the first time it's polled.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryStore;
+    use super::*;
+    use crate::models::followers::Follower;
+    use crate::models::posts::Post;
+    use crate::models::users::{Profile, Role};
+    use chrono::{offset::Utc, TimeZone};
+    use url::Url;
+    use uuid::Uuid;
+
+    // Bytes of each format as it was actually written. Never change these:
+    // they're how we know old data still reads.
+    const POSTS_V0_BASELINE: &[u8] = include_bytes!("storage/posts-v0-baseline.msgpack");
+    const POSTS_V0_WITH_IDS: &[u8] = include_bytes!("storage/posts-v0-with-ids.msgpack");
+    const POSTS_V1: &[u8] = include_bytes!("storage/posts-v1.msgpack");
+    const PROFILE_V0_BASELINE: &[u8] = include_bytes!("storage/profile-v0-baseline.msgpack");
+    const PROFILE_V0_WITH_LANGUAGE: &[u8] =
+        include_bytes!("storage/profile-v0-with-language.msgpack");
+    const PROFILE_V0_WITH_TIMEZONE: &[u8] =
+        include_bytes!("storage/profile-v0-with-timezone.msgpack");
+    const PROFILE_V0_WITH_BIO: &[u8] = include_bytes!("storage/profile-v0-with-bio.msgpack");
+    const PROFILE_V1: &[u8] = include_bytes!("storage/profile-v1.msgpack");
+    const FOLLOWERS_V0: &[u8] = include_bytes!("storage/followers-v0.msgpack");
+    const FOLLOWERS_V1: &[u8] = include_bytes!("storage/followers-v1.msgpack");
+
+    fn user_id() -> Uuid {
+        Uuid::parse_str("fc53b101-1756-4b8f-b5fe-b71d103e9f20").unwrap()
+    }
+
+    fn post_id() -> Uuid {
+        Uuid::parse_str("6f4c8bd3-58a1-4d6c-9a0e-d4a4b1d1c2a1").unwrap()
+    }
+
+    fn profile() -> Profile {
+        Profile {
+            username: "ada".to_owned(),
+            date_joined: Utc.ymd(2020, 8, 1).and_hms(12, 0, 0),
+            id: user_id(),
+            pic: Url::parse("https://quiet.example/avatar/ada/256").unwrap(),
+            email: "ada@example.com".to_owned(),
+            language: Some("es".to_owned()),
+            timezone: "Europe/Madrid".to_owned(),
+            locale: Some("es-ES".to_owned()),
+            bio: "hola".to_owned(),
+            role: Role::User,
+            suspended: false,
+            is_private: false,
+        }
+    }
+
+    #[test]
+    fn posts_from_before_ids_and_dates_read() {
+        let posts: Vec<Post> = decode(POSTS_V0_BASELINE).unwrap();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0].text, "first");
+        assert_eq!(
+            posts[1].link.as_ref().unwrap().as_str(),
+            "https://example.com/"
+        );
+        assert_eq!(posts[1].user_id, user_id());
+        assert_eq!(posts[0].created, Utc.timestamp(0, 0));
+        // They're given IDs, which are the same each time they're read.
+        assert!(!posts[0].id.is_nil());
+        assert_ne!(posts[0].id, posts[1].id);
+        let again: Vec<Post> = decode(POSTS_V0_BASELINE).unwrap();
+        assert_eq!(again[0].id, posts[0].id);
+    }
+
+    #[test]
+    fn old_posts_are_written_back_upgraded() {
+        use futures::executor::block_on;
+        let store = MemoryStore::default();
+        block_on(async {
+            store
+                .put_if_match("posts", POSTS_V0_BASELINE.to_vec(), None)
+                .await
+                .unwrap();
+            let stored = store.get("posts").await.unwrap().unwrap();
+            assert!(!is_current::<Vec<Post>>(&stored.bytes));
+            let migrated: Vec<Post> = decode(&stored.bytes).unwrap();
+
+            upgrade::<_, Vec<Post>>(&store, "posts", &stored)
+                .await
+                .unwrap();
+            let stored = store.get("posts").await.unwrap().unwrap();
+            assert!(is_current::<Vec<Post>>(&stored.bytes));
+            let read: Vec<Post> = decode(&stored.bytes).unwrap();
+            let ids = |posts: &[Post]| posts.iter().map(|p| p.id).collect::<Vec<_>>();
+            assert_eq!(ids(&read), ids(&migrated));
+            // Undated posts keep their order.
+            assert!(read[0].created < read[1].created);
+
+            // Once it's current, there's nothing to write.
+            upgrade::<_, Vec<Post>>(&store, "posts", &stored)
+                .await
+                .unwrap();
+            assert_eq!(store.get("posts").await.unwrap().unwrap().etag, stored.etag);
+        });
+    }
+
+    #[test]
+    fn every_post_format_reads() {
+        for bytes in &[POSTS_V0_WITH_IDS, POSTS_V1] {
+            let posts: Vec<Post> = decode(bytes).unwrap();
+            assert_eq!(posts.len(), 1);
+            assert_eq!(posts[0].text, "with an id");
+            assert_eq!(posts[0].id, post_id());
+            assert_eq!(posts[0].created, Utc.ymd(2020, 9, 1).and_hms(12, 0, 0));
+        }
+    }
+
+    #[test]
+    fn every_profile_format_reads() {
+        let fixtures = [
+            PROFILE_V0_BASELINE,
+            PROFILE_V0_WITH_LANGUAGE,
+            PROFILE_V0_WITH_TIMEZONE,
+            PROFILE_V0_WITH_BIO,
+            PROFILE_V1,
+        ];
+        for bytes in &fixtures {
+            let read: Profile = decode(bytes).unwrap();
+            assert_eq!(read.username, "ada");
+            assert_eq!(read.id, user_id());
+            assert_eq!(read.email, "ada@example.com");
+            assert_eq!(read.date_joined, profile().date_joined);
+        }
+        let baseline: Profile = decode(PROFILE_V0_BASELINE).unwrap();
+        assert_eq!(
+            (baseline.language, baseline.timezone),
+            (None, "UTC".to_owned())
+        );
+        let with_bio: Profile = decode(PROFILE_V0_WITH_BIO).unwrap();
+        assert_eq!(with_bio.bio, "hola");
+        assert_eq!(with_bio.locale.as_deref(), Some("es-ES"));
+    }
+
+    #[test]
+    fn every_follower_format_reads() {
+        for bytes in &[FOLLOWERS_V0, FOLLOWERS_V1] {
+            let followers: Vec<Follower> = decode(bytes).unwrap();
+            assert_eq!(
+                followers[0].actor.as_str(),
+                "https://remote.example/users/bob"
+            );
+            assert_eq!(followers[0].inbox.as_str(), "https://remote.example/inbox");
+        }
+    }
+
+    /// If one of these fails, the stored format changed: bump the version
+    /// instead of updating the fixture.
+    #[test]
+    fn current_formats_match_their_fixtures() {
+        let post = Post {
+            user_id: user_id(),
+            id: post_id(),
+            created: Utc.ymd(2020, 9, 1).and_hms(12, 0, 0),
+            ..Post::fixture("with an id")
+        };
+        assert_eq!(encode(&vec![post]).unwrap(), POSTS_V1);
+        assert_eq!(encode(&profile()).unwrap(), PROFILE_V1);
+        let follower = Follower {
+            actor: Url::parse("https://remote.example/users/bob").unwrap(),
+            inbox: Url::parse("https://remote.example/inbox").unwrap(),
+        };
+        assert_eq!(encode(&vec![follower]).unwrap(), FOLLOWERS_V1);
+    }
+
+    #[test]
+    fn interleaved_updates_both_land() {
+        use futures::executor::block_on;
+        let store = MemoryStore::default();
+        let post = |text: &str| Post {
+            user_id: user_id(),
+            ..Post::fixture(text)
+        };
+        let (first, second) = (post("first"), post("second"));
+        // Both read the empty list before either writes, so the second
+        // write has to notice and start again.
+        let (a, b) = block_on(async {
+            futures::join!(
+                update(&store, "posts", |posts: &mut Vec<Post>| posts
+                    .push(first.clone())),
+                update(&store, "posts", |posts: &mut Vec<Post>| posts
+                    .push(second.clone())),
+            )
+        });
+        a.unwrap();
+        let saved = b.unwrap();
+        let texts: Vec<_> = saved.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(texts, vec!["first", "second"]);
+        assert_eq!(store.conflicts.get(), 1);
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut bytes = PROFILE_V1.to_vec();
+        bytes[1] = Profile::VERSION + 1;
+        assert!(decode::<Profile>(&bytes).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Users' posts kept in Durable Objects, one per user, when quiet is built
+//! with the `durable-timelines` feature. Unlike KV, a Durable Object always
+//! returns what was last written to it, and handles one request at a time,
+//! so it can compare and set for real.
+//!
+//! The object itself is `Timeline` in `worker/timeline.mjs`, deployed as its
+//! own script and bound to this worker as `TimelinesNs`. It speaks a small
+//! protocol over fetch:
+//!
+//! - `GET` returns the stored bytes with an `ETag` header, or 404.
+//! - `PUT` with `If-Match: <etag>`, or `If-None-Match: *` when there's
+//!   nothing stored yet, saves the body and returns 204 with the new ETag,
+//!   or 412 if the value changed.
+//! - `DELETE` forgets the value and returns 204.
+//!
+//! ETags count writes, so they never repeat even if a value changes back.
+use crate::models::storage::{Store, Versioned};
+use futures::future::LocalBoxFuture;
+
+#[cfg(feature = "durable-timelines")]
+pub use durable::DurableTimelines;
+
+#[cfg(feature = "durable-timelines")]
+mod durable {
+    use super::*;
+    use futures::future::FutureExt;
+    use http::StatusCode;
+    use js_sys::Promise;
+    use wasm_bindgen::prelude::*;
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+    use web_sys::{Headers, Request, RequestInit, Response};
+
+    /// The `Store` backed by Durable Objects. Keys name the object, which
+    /// holds one value.
+    pub struct DurableTimelines;
+
+    impl DurableTimelines {
+        async fn fetch(
+            &self,
+            key: &str,
+            method: &str,
+            headers: &[(&str, &str)],
+            body: Option<&[u8]>,
+        ) -> Result<Response, String> {
+            let build = || -> Result<Request, JsValue> {
+                let h = Headers::new()?;
+                for (name, value) in headers {
+                    h.append(name, value)?;
+                }
+                let init = RequestInit::new();
+                init.set_method(method);
+                init.set_headers(&h);
+                if let Some(body) = body {
+                    init.set_body(&js_sys::Uint8Array::from(body));
+                }
+                // Only the object sees this URL; the host is ignored.
+                Request::new_with_str_and_init("https://timeline/", &init)
+            };
+            let req = build().map_err(|e| format!("{:?}", e))?;
+            let stub = TimelinesNs::get(&TimelinesNs::id_from_name(key));
+            let resp = JsFuture::from(stub.fetch(&req))
+                .await
+                .map_err(|e| format!("timeline {} unreachable: {:?}", key, e))?;
+            Ok(resp.unchecked_into())
+        }
+    }
+
+    impl Store for DurableTimelines {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            async move {
+                let resp = self.fetch(key, "GET", &[], None).await?;
+                if resp.status() == StatusCode::NOT_FOUND.as_u16() {
+                    return Ok(None);
+                }
+                if !resp.ok() {
+                    return Err(format!("timeline {} returned {}", key, resp.status()));
+                }
+                let etag = resp.headers().get("etag").ok().flatten();
+                let etag = etag.ok_or_else(|| format!("timeline {} sent no ETag", key))?;
+                let buf = resp.array_buffer().map_err(|e| format!("{:?}", e))?;
+                let buf = JsFuture::from(buf).await.map_err(|e| format!("{:?}", e))?;
+                let bytes = js_sys::Uint8Array::new(&buf).to_vec();
+                Ok(Some(Versioned { bytes, etag }))
+            }
+            .boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            async move {
+                let condition = match etag {
+                    Some(etag) => ("if-match", etag),
+                    None => ("if-none-match", "*"),
+                };
+                let resp = self.fetch(key, "PUT", &[condition], Some(&bytes)).await?;
+                match StatusCode::from_u16(resp.status()) {
+                    Ok(StatusCode::PRECONDITION_FAILED) => Ok(false),
+                    Ok(status) if status.is_success() => Ok(true),
+                    _ => Err(format!("timeline {} returned {}", key, resp.status())),
+                }
+            }
+            .boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            async move {
+                let resp = self.fetch(key, "DELETE", &[], None).await?;
+                if !resp.ok() {
+                    return Err(format!("timeline {} returned {}", key, resp.status()));
+                }
+                Ok(())
+            }
+            .boxed_local()
+        }
+    }
+
+    // The Durable Object namespace bound as `TimelinesNs` in `wrangler.toml`.
+    #[wasm_bindgen]
+    extern "C" {
+        type TimelinesNs;
+        type DurableObjectId;
+        type TimelineStub;
+
+        #[wasm_bindgen(static_method_of = TimelinesNs, js_name = idFromName)]
+        fn id_from_name(name: &str) -> DurableObjectId;
+
+        #[wasm_bindgen(static_method_of = TimelinesNs)]
+        fn get(id: &DurableObjectId) -> TimelineStub;
+
+        #[wasm_bindgen(method)]
+        fn fetch(this: &TimelineStub, req: &Request) -> Promise;
+    }
+}
+
+/// Behaves like a Timeline object, in memory, so code that uses timelines
+/// can be tested without Workers.
+#[cfg(test)]
+pub mod local {
+    use super::*;
+    use futures::future::{ready, FutureExt};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// How many times an object has been written, and its value.
+    type Object = (u64, Option<Vec<u8>>);
+
+    #[derive(Default)]
+    pub struct LocalTimelines {
+        objects: RefCell<HashMap<String, Object>>,
+    }
+
+    impl Store for LocalTimelines {
+        fn get<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> LocalBoxFuture<'a, Result<Option<Versioned>, String>> {
+            let objects = self.objects.borrow();
+            let val = match objects.get(key) {
+                Some((writes, Some(bytes))) => Some(Versioned {
+                    bytes: bytes.clone(),
+                    etag: writes.to_string(),
+                }),
+                _ => None,
+            };
+            ready(Ok(val)).boxed_local()
+        }
+
+        fn put_if_match<'a>(
+            &'a self,
+            key: &'a str,
+            bytes: Vec<u8>,
+            etag: Option<&'a str>,
+        ) -> LocalBoxFuture<'a, Result<bool, String>> {
+            let mut objects = self.objects.borrow_mut();
+            let (writes, val) = objects.entry(key.to_owned()).or_insert((0, None));
+            let current = val.as_ref().map(|_| writes.to_string());
+            if current.as_deref() != etag {
+                return ready(Ok(false)).boxed_local();
+            }
+            *writes += 1;
+            *val = Some(bytes);
+            ready(Ok(true)).boxed_local()
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+            if let Some((writes, val)) = self.objects.borrow_mut().get_mut(key) {
+                *writes += 1;
+                *val = None;
+            }
+            ready(Ok(())).boxed_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::local::LocalTimelines;
+    use super::*;
+    use crate::models::posts::Post;
+    use crate::models::storage::{self, decode};
+    use futures::executor::block_on;
+
+    #[test]
+    fn posts_are_there_as_soon_as_they_are_saved() {
+        let timelines = LocalTimelines::default();
+        block_on(async {
+            for text in &["one", "two"] {
+                let new = Post::fixture(text);
+                storage::update(&timelines, "ada", |posts: &mut Vec<Post>| {
+                    posts.push(new.clone())
+                })
+                .await
+                .unwrap();
+                let stored = timelines.get("ada").await.unwrap().unwrap();
+                let posts: Vec<Post> = decode(&stored.bytes).unwrap();
+                assert_eq!(posts.last().unwrap().text, *text);
+            }
+        });
+    }
+
+    #[test]
+    fn stale_writes_are_refused() {
+        let timelines = LocalTimelines::default();
+        block_on(async {
+            assert!(timelines.put_if_match("ada", vec![1], None).await.unwrap());
+            let first = timelines.get("ada").await.unwrap().unwrap();
+            assert!(timelines
+                .put_if_match("ada", vec![2], Some(&first.etag))
+                .await
+                .unwrap());
+            // Someone who read the first value can't overwrite the second,
+            // even by writing back the same bytes.
+            assert!(!timelines
+                .put_if_match("ada", vec![1], Some(&first.etag))
+                .await
+                .unwrap());
+            assert!(!timelines.put_if_match("ada", vec![3], None).await.unwrap());
+
+            timelines.delete("ada").await.unwrap();
+            assert!(timelines.get("ada").await.unwrap().is_none());
+            assert!(!timelines
+                .put_if_match("ada", vec![4], Some(&first.etag))
+                .await
+                .unwrap());
+            assert!(timelines.put_if_match("ada", vec![4], None).await.unwrap());
+        });
+    }
+}
@@ -1,12 +1,21 @@
+use crate::activitypub;
+use crate::cache;
 use crate::console_logf;
-use crate::twoface;
+use crate::email;
+use crate::flash;
+use crate::i18n::{self, Message};
+#[cfg(feature = "sql")]
+use crate::models::sql;
+use crate::models::storage::{self, Schema};
+use crate::models::{avatars, blocks, followers, posts, reports};
+use crate::ratelimit::{self, Action};
+use crate::sessions;
 use crate::twoface::*;
 use crate::utils::*;
 use chrono::{offset::Utc, DateTime};
 use http::StatusCode;
 use js_sys::Promise;
-use rmp_serde::Serializer;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::convert::TryFrom;
 use url::Url;
 use uuid::Uuid;
@@ -15,122 +24,715 @@ use wasm_bindgen_futures::JsFuture;
 use web_sys::{Request, Response};
 
 const MAX_USERNAME_LENGTH: usize = 32;
+const MAX_BIO_CHARS: usize = 500;
 
-pub async fn new_user_profile(req: Request) -> Result<Response, Response> {
-    let json_f = req.json().map_err(|e| {
-        Error {
-            internal: format!("error getting json future: {:?}", e),
-            external: twoface::External {
-                msg: "couldn't get JSON from request".to_owned(),
-            },
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-        }
-        .into_response()
+/// Validates the profile in the request body and saves it. Anyone can only
+/// sign up if the `OPEN_SIGNUPS` variable is "true".
+pub async fn create_profile(req: Request) -> Fallible<Profile> {
+    if global_var("OPEN_SIGNUPS").as_deref() != Some("true") {
+        return Err(Error {
+            internal: "sign-ups are closed".to_owned(),
+            external: Message::new("error-signups-closed"),
+            status: StatusCode::FORBIDDEN,
+        });
+    }
+    // Nobody's signed in while signing up, so only their IP address counts.
+    ratelimit::check(&req, Action::SignUp, None).await?;
+    let new: NewProfile = read_json(&req, "error-malformed-profile").await?;
+    let profile = Profile::try_from(new).map_err(|e| Error {
+        internal: e.to_string(),
+        external: e,
+        status: StatusCode::BAD_REQUEST,
     })?;
-    let json = JsFuture::from(json_f).await.map_err(|e| {
-        Error {
-            internal: format!("error awaiting json: {:?}", e),
-            external: twoface::External {
-                msg: "Error awaiting JSON".to_owned(),
-            },
-            status: StatusCode::BAD_REQUEST,
-        }
-        .into_response()
+    if profile_by_username(&profile.username).await?.is_some() {
+        return Err(Error {
+            internal: format!("username {} is taken", profile.username),
+            external: Message::new("error-username-taken"),
+            status: StatusCode::CONFLICT,
+        });
+    }
+    profile.clone().put().await?;
+    Ok(profile)
+}
+
+pub async fn save_settings(req: Request) -> Result<Response, Response> {
+    let accept_language = i18n::accept_language(&req);
+    let lang = i18n::negotiate(None, accept_language.as_deref());
+    let (profile, new_email) = update_settings(req)
+        .await
+        .map_err(|e| e.localized_response(&lang))?;
+    let lang = i18n::negotiate(profile.language.as_deref(), accept_language.as_deref());
+    let msg = match new_email {
+        Some(email) => Message::new("flash-email-verification-sent").arg("email", email),
+        None => Message::new("flash-settings-saved"),
+    };
+    let resp = success_response("settings saved", Some("/settings".to_owned()));
+    flash::set(&resp, &msg.localize(&lang));
+    Ok(resp)
+}
+
+/// Validates the settings in the request body and saves them to the
+/// viewer's profile. A new email address isn't saved until the user follows
+/// the link we send to it, so it's returned instead.
+pub async fn update_settings(req: Request) -> Fallible<(Profile, Option<String>)> {
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        external: Message::new("error-invalid-url"),
+        status: StatusCode::BAD_REQUEST,
     })?;
-    let new: NewProfile = json.into_serde().map_err(|e| {
-        Error {
-            internal: format!("error parsing profile: {:?}", e),
-            external: twoface::External {
-                msg: "Your profile was malformed".to_owned(),
-            },
-            status: StatusCode::BAD_REQUEST,
+    let mut profile = signed_in(&req).await?;
+    let settings: Settings = read_json(&req, "error-malformed-settings").await?;
+    let old_username = profile.username.clone();
+    let new_email = profile.apply(settings).map_err(|e| Error {
+        internal: e.to_string(),
+        external: e,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    if username_key(&old_username) != username_key(&profile.username) {
+        if let Some(other) = profile_by_username(&profile.username).await? {
+            if other.id != profile.id {
+                return Err(Error {
+                    internal: format!("username {} is taken", profile.username),
+                    external: Message::new("error-username-taken"),
+                    status: StatusCode::CONFLICT,
+                });
+            }
         }
-        .into_response()
+    }
+    // Make the link before saving anything, so if links can't be signed the
+    // other settings aren't saved either.
+    let link = match &new_email {
+        Some(email) => Some(email::verification_link(&url, profile.id, email)?),
+        None => None,
+    };
+    profile.clone().put_renamed(&old_username).await?;
+    if let (Some(email), Some(link)) = (&new_email, link) {
+        let lang = i18n::request_language(&req, Some(&profile));
+        let subject = Message::new("email-verify-subject").localize(&lang);
+        let body = Message::new("email-verify-body")
+            .arg("username", profile.username.clone())
+            .arg("email", email.clone())
+            .arg("link", link.to_string())
+            .localize(&lang);
+        email::send(email, &subject, &body).await?;
+    }
+    Ok((profile, new_email))
+}
+
+/// Makes the email address from a verification link the user's, if the
+/// link is genuine and was sent to whoever is using the site.
+pub async fn confirm_email(req: &Request) -> Fallible<Profile> {
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        external: Message::new("error-invalid-url"),
+        status: StatusCode::BAD_REQUEST,
     })?;
-    let profile = Profile::try_from(new).map_err(|e| {
-        Error {
-            internal: e.clone(),
-            external: twoface::External { msg: e },
+    let token = url
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    let (user_id, email) = email::verified_email(&token, Utc::now())?;
+    let profile = viewer(req).await?.filter(|p| p.id == user_id);
+    let Some(mut profile) = profile else {
+        return Err(Error {
+            internal: format!("verification link for {} opened by someone else", user_id),
+            external: Message::new("error-invalid-verification"),
+            status: StatusCode::FORBIDDEN,
+        });
+    };
+    profile.email = email;
+    profile.clone().put().await?;
+    Ok(profile)
+}
+
+pub async fn delete_account(req: Request) -> Result<Response, Response> {
+    let accept_language = i18n::accept_language(&req);
+    let profile = viewer(&req).await.ok().flatten();
+    let lang = i18n::negotiate(
+        profile.and_then(|p| p.language).as_deref(),
+        accept_language.as_deref(),
+    );
+    remove_account(req)
+        .await
+        .map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("account deleted", Some("/".to_owned()));
+    flash::set(
+        &resp,
+        &Message::new("flash-account-deleted").localize(&lang),
+    );
+    Ok(resp)
+}
+
+/// What the user sends to delete their account: their username, typed out
+/// so it can't happen by accident.
+#[derive(Deserialize)]
+struct DeleteAccount {
+    username: String,
+}
+
+/// Deletes the viewer's profile and everything that belongs to it. The
+/// profile goes last, so if anything fails the user can simply try again.
+pub async fn remove_account(req: Request) -> Fallible<()> {
+    let url = Url::parse(&req.url()).map_err(|e| Error {
+        internal: format!("couldn't parse request URL: {}", e),
+        external: Message::new("error-invalid-url"),
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    let profile = signed_in(&req).await?;
+    let confirmation: DeleteAccount = read_json(&req, "error-malformed-settings").await?;
+    if username_key(&confirmation.username) != username_key(&profile.username) {
+        return Err(Error {
+            internal: format!("confirmation {} doesn't match", confirmation.username),
+            external: Message::new("delete-confirm-mismatch"),
             status: StatusCode::BAD_REQUEST,
-        }
-        .into_response()
+        });
+    }
+    // Followers' servers can't be told once we've forgotten who they are.
+    if let Err(e) = activitypub::announce_account_deletion(&url, profile.id).await {
+        console_logf!(
+            "Couldn't announce deletion of {}: {}",
+            profile.id,
+            e.internal
+        );
+    }
+    posts::delete_all_posts_by_user(profile.id).await?;
+    followers::delete_followers(profile.id).await?;
+    blocks::delete_blocks(profile.id).await?;
+    reports::resolve_author(profile.id).await?;
+    avatars::delete_avatar(profile.id).await?;
+    profile.delete().await
+}
+
+/// `POST /settings/avatar`, from the upload form on the settings page.
+pub async fn save_avatar(req: Request) -> Result<Response, Response> {
+    let accept_language = i18n::accept_language(&req);
+    let lang = i18n::negotiate(None, accept_language.as_deref());
+    let profile = upload_avatar(&req)
+        .await
+        .map_err(|e| e.localized_response(&lang))?;
+    let lang = i18n::negotiate(profile.language.as_deref(), accept_language.as_deref());
+    let resp = success_response("avatar saved", Some("/settings".to_owned()));
+    flash::set(&resp, &Message::new("flash-avatar-saved").localize(&lang));
+    Ok(resp)
+}
+
+/// Makes the picture uploaded in `req` the avatar of whoever is signed in.
+pub async fn upload_avatar(req: &Request) -> Fallible<Profile> {
+    let mut profile = signed_in(req).await?;
+    profile.pic = avatars::save_upload(req, profile.id).await?;
+    profile.clone().put().await?;
+    Ok(profile)
+}
+
+/// Reads the request's JSON body, answering 400 with `malformed` if it
+/// isn't a `T`.
+pub async fn read_json<T: DeserializeOwned>(req: &Request, malformed: &'static str) -> Fallible<T> {
+    let json_f = req.json().map_err(|e| Error {
+        internal: format!("error getting json future: {:?}", e),
+        external: Message::new("error-read-json"),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
     })?;
-    profile.put().await.map_err(|e| e.into_response())?;
-    console_logf!("Successfully made new profile");
-    Ok(success_response("profile created", Some("/".to_owned())))
+    let json = JsFuture::from(json_f).await.map_err(|e| Error {
+        internal: format!("error awaiting json: {:?}", e),
+        external: Message::new("error-await-json"),
+        status: StatusCode::BAD_REQUEST,
+    })?;
+    from_js(&json).map_err(|e| Error {
+        internal: format!("error parsing request body: {:?}", e),
+        external: Message::new(malformed),
+        status: StatusCode::BAD_REQUEST,
+    })
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Profile {
     pub username: String,
     pub date_joined: DateTime<Utc>,
     pub id: Uuid,
     pub pic: Url,
     pub email: String,
+    /// The language the user wants quiet in. If it's None, or a language
+    /// quiet doesn't have, their browser's languages are used.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// IANA name of where the user lives, like "Europe/Madrid". Their day
+    /// starts and ends here.
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// How the user wants dates written, like "en-GB". If it's None, it
+    /// follows their language.
+    #[serde(default)]
+    pub locale: Option<String>,
+    /// A few words about the user, shown on their profile.
+    #[serde(default)]
+    pub bio: String,
+    /// What the user can do besides post. Only admins can change it.
+    #[serde(default, skip_serializing_if = "Role::is_user")]
+    pub role: Role,
+    /// Suspended by a moderator: the user can't post, and their posts
+    /// aren't shown.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub suspended: bool,
+    /// Only the user and the followers they've approved can read their posts.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub is_private: bool,
+}
+
+/// What someone can do on quiet besides post.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    /// Can see reports, hide posts and suspend users.
+    Moderator,
+    /// Can do what moderators can, suspend moderators, and give people roles.
+    Admin,
+}
+
+impl Role {
+    pub fn can_moderate(self) -> bool {
+        self != Role::User
+    }
+
+    fn is_user(&self) -> bool {
+        *self == Role::User
+    }
+}
+
+fn default_timezone() -> String {
+    "UTC".to_owned()
+}
+
+impl Schema for Profile {
+    const VERSION: u8 = 1;
+
+    fn migrate(version: u8, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => storage::decode_payload::<ProfileV0>(payload).map(Profile::from),
+            _ => Err(format!("no migration from profile version {}", version)),
+        }
+    }
+}
+
+/// Profiles as they were stored before versioning, with positional fields.
+/// Fields after `email` were added one by one, so older profiles lack them.
+#[derive(Deserialize)]
+struct ProfileV0 {
+    username: String,
+    date_joined: DateTime<Utc>,
+    id: Uuid,
+    pic: Url,
+    email: String,
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    bio: String,
+}
+
+impl From<ProfileV0> for Profile {
+    fn from(old: ProfileV0) -> Self {
+        Self {
+            username: old.username,
+            date_joined: old.date_joined,
+            id: old.id,
+            pic: old.pic,
+            email: old.email,
+            language: old.language,
+            timezone: old.timezone,
+            locale: old.locale,
+            bio: old.bio,
+            role: Role::User,
+            suspended: false,
+            is_private: false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 pub struct NewProfile {
     pub username: String,
     pub pic: String,
     pub email: String,
+    /// A language tag like "en" or "es".
+    #[serde(default)]
+    pub language: Option<String>,
+    /// An IANA time zone name like "America/New_York". Defaults to UTC.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// A locale tag like "en-GB", for how dates are written.
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+}
+
+/// What a user can change on the settings page.
+#[derive(Serialize, Deserialize)]
+pub struct Settings {
+    pub username: String,
+    pub email: String,
+    pub bio: String,
+    /// Empty means "whatever my browser asks for".
+    pub language: Option<String>,
+    pub timezone: String,
+    /// Empty means "follow my language".
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub is_private: bool,
 }
 
 impl TryFrom<NewProfile> for Profile {
-    type Error = String;
+    type Error = Message;
 
     fn try_from(new: NewProfile) -> Result<Self, Self::Error> {
-        if new.username.len() > MAX_USERNAME_LENGTH {
-            return Err(format!(
-                "Usernames can only have {} characters, but yours has {}",
-                MAX_USERNAME_LENGTH,
-                new.username.len()
-            ));
-        }
+        let username = validate_username(new.username)?;
         let id = Uuid::new_v4();
-        guard!(let Ok(pic) = Url::parse(&new.pic) else {
-            return Err("Your picture URL is invalid".to_owned());
-        });
-        let email_regex = regex::Regex::new(
-            r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})",
-        )
-        .unwrap();
-        if !email_regex.is_match(&new.email) {
-            return Err("Your email address is invalid".to_owned());
-        }
+        let pic = validate_pic(&new.pic)?;
+        let language = validate_language(new.language)?;
+        let timezone = validate_timezone(new.timezone.unwrap_or_else(default_timezone))?;
+        let locale = validate_locale(new.locale)?;
+        let email = validate_email(new.email)?;
+        let bio = validate_bio(new.bio.unwrap_or_default())?;
         Ok(Self {
-            username: new.username,
+            username,
             date_joined: Utc::now(),
             id,
             pic,
-            email: new.email,
+            email,
+            language,
+            timezone,
+            locale,
+            bio,
+            role: Role::User,
+            suspended: false,
+            is_private: false,
         })
     }
 }
 
+fn validate_username(username: String) -> Result<String, Message> {
+    if username.len() > MAX_USERNAME_LENGTH {
+        return Err(Message::new("username-too-long")
+            .arg("max", MAX_USERNAME_LENGTH)
+            .arg("count", username.len()));
+    }
+    Ok(username)
+}
+
+/// Pictures are shown to everyone who reads the user's posts elsewhere, so
+/// only web URLs are allowed.
+fn validate_pic(pic: &str) -> Result<Url, Message> {
+    Url::parse(pic)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+        .ok_or_else(|| Message::new("invalid-pic-url"))
+}
+
+fn validate_email(email: String) -> Result<String, Message> {
+    let email_regex = regex::Regex::new(
+        r"^([a-z0-9_+]([a-z0-9_+.]*[a-z0-9_+])?)@([a-z0-9]+([\-\.]{1}[a-z0-9]+)*\.[a-z]{2,6})",
+    )
+    .unwrap();
+    if !email_regex.is_match(&email) {
+        return Err(Message::new("invalid-email"));
+    }
+    Ok(email)
+}
+
+fn validate_bio(bio: String) -> Result<String, Message> {
+    if bio.chars().count() > MAX_BIO_CHARS {
+        return Err(Message::new("bio-too-long")
+            .arg("max", MAX_BIO_CHARS)
+            .arg("count", bio.chars().count()));
+    }
+    Ok(bio)
+}
+
+fn validate_language(language: Option<String>) -> Result<Option<String>, Message> {
+    let Some(language) = language.filter(|l| !l.is_empty()) else {
+        return Ok(None);
+    };
+    match i18n::supported(&language) {
+        Some(lang) => Ok(Some(lang.to_string())),
+        None => Err(Message::new("unsupported-language").arg("language", language)),
+    }
+}
+
+fn validate_timezone(timezone: String) -> Result<String, Message> {
+    match i18n::timezone(&timezone) {
+        Some(tz) => Ok(tz.name().to_owned()),
+        None => Err(Message::new("invalid-timezone").arg("timezone", timezone)),
+    }
+}
+
+fn validate_locale(locale: Option<String>) -> Result<Option<String>, Message> {
+    let Some(locale) = locale.filter(|l| !l.is_empty()) else {
+        return Ok(None);
+    };
+    if i18n::date_locale(&locale).is_none() {
+        return Err(Message::new("invalid-locale").arg("locale", locale));
+    }
+    // Store the canonical form, so "en-gb" and "en-GB" are the same.
+    Ok(locale
+        .parse::<i18n::LanguageIdentifier>()
+        .map(|l| l.to_string())
+        .ok())
+}
+
 impl Profile {
-    async fn put(self) -> Fallible<()> {
+    /// Changes the profile's settings, if they're all valid. The email
+    /// address is only validated: if it's new, it's returned, to be
+    /// verified before it's saved.
+    pub fn apply(&mut self, settings: Settings) -> Result<Option<String>, Message> {
+        let username = validate_username(settings.username)?;
+        let email = validate_email(settings.email)?;
+        let bio = validate_bio(settings.bio)?;
+        let language = validate_language(settings.language)?;
+        let timezone = validate_timezone(settings.timezone)?;
+        let locale = validate_locale(settings.locale)?;
+        self.username = username;
+        self.bio = bio;
+        self.language = language;
+        self.timezone = timezone;
+        self.locale = locale;
+        self.is_private = settings.is_private;
+        if email == self.email {
+            Ok(None)
+        } else {
+            Ok(Some(email))
+        }
+    }
+
+    /// The locale to write dates in for this user, when they read `lang`.
+    pub fn date_locale(&self, lang: &i18n::LanguageIdentifier) -> String {
+        match &self.locale {
+            Some(locale) => locale.clone(),
+            None => i18n::default_locale(lang).to_owned(),
+        }
+    }
+
+    /// A user called `username` with no role, for tests. Set whatever else
+    /// a test cares about with `Profile { ..Profile::fixture(username) }`.
+    #[cfg(test)]
+    pub fn fixture(username: &str) -> Self {
+        use chrono::TimeZone;
+        Self {
+            username: username.to_owned(),
+            date_joined: Utc.ymd(2020, 9, 1).and_hms(12, 0, 0),
+            id: Uuid::new_v4(),
+            pic: Url::parse("https://quiet.example/avatar/1/256").unwrap(),
+            email: format!("{}@example.com", username),
+            language: None,
+            timezone: default_timezone(),
+            locale: None,
+            bio: String::new(),
+            role: Role::User,
+            suspended: false,
+            is_private: false,
+        }
+    }
+}
+
+#[cfg(not(feature = "sql"))]
+impl Profile {
+    pub async fn put(self) -> Fallible<()> {
         let key = self.id.to_string();
-        let mut val_bytes = Vec::new();
-        self.serialize(&mut Serializer::new(&mut val_bytes))
+        let val_bytes = storage::encode(&self).map_err(|e| Error {
+            internal: e,
+            status: http::StatusCode::BAD_REQUEST,
+            external: Message::new("error-invalid-profile"),
+        })?;
+        JsFuture::from(UsersNs::put(&key, &val_bytes))
+            .await
             .map_err(|e| Error {
-                internal: e.to_string(),
-                status: http::StatusCode::BAD_REQUEST,
-                external: twoface::External {
-                    msg: "Invalid post".into(),
-                },
+                internal: format!("{:?}", e),
+                status: http::StatusCode::INTERNAL_SERVER_ERROR,
+                external: Message::new("error-save-profile"),
             })?;
-        JsFuture::from(UsersNs::put(&key, &val_bytes))
+        JsFuture::from(UsersNs::put(&username_key(&self.username), key.as_bytes()))
             .await
             .map_err(|e| Error {
                 internal: format!("{:?}", e),
                 status: http::StatusCode::INTERNAL_SERVER_ERROR,
-                external: twoface::External {
-                    msg: "Couldn't create user, please try again later".to_owned(),
-                },
+                external: Message::new("error-save-profile"),
             })?;
+        cache::invalidate(self.id).await;
+        Ok(())
+    }
+
+    /// Saves a profile whose username used to be `old_username`, moving its
+    /// entry in the username index.
+    async fn put_renamed(self, old_username: &str) -> Fallible<()> {
+        let old_key = username_key(old_username);
+        let renamed = old_key != username_key(&self.username);
+        // The new name is indexed before the old one is removed, so if this
+        // fails halfway the profile can still be found by its new name, and
+        // `profile_by_username` ignores the stale old one.
+        self.put().await?;
+        if renamed {
+            JsFuture::from(UsersNs::delete(&old_key))
+                .await
+                .map_err(|e| Error {
+                    internal: format!("{:?}", e),
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    external: Message::new("error-save-profile"),
+                })?;
+        }
         Ok(())
     }
+
+    async fn delete(self) -> Fallible<()> {
+        let delete = |key: String| async move {
+            JsFuture::from(UsersNs::delete(&key))
+                .await
+                .map_err(|e| Error {
+                    internal: format!("{:?}", e),
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    external: Message::new("error-delete-account"),
+                })
+        };
+        // Without its index entry the profile can't be found by name, but
+        // the viewer can still find it by ID to finish deleting it.
+        delete(username_key(&self.username)).await?;
+        delete(self.id.to_string()).await?;
+        cache::invalidate(self.id).await;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sql")]
+impl Profile {
+    pub async fn put(self) -> Fallible<()> {
+        sql::put_profile(&sql::database().await?, &self).await?;
+        cache::invalidate(self.id).await;
+        Ok(())
+    }
+
+    /// Usernames aren't indexed separately in the database, so renaming
+    /// is just saving.
+    async fn put_renamed(self, _old_username: &str) -> Fallible<()> {
+        self.put().await
+    }
+
+    async fn delete(self) -> Fallible<()> {
+        sql::delete_profile(&sql::database().await?, self.id).await?;
+        cache::invalidate(self.id).await;
+        Ok(())
+    }
+}
+
+/// Profiles are stored under their ID. To find one by username, look up
+/// this key, which holds the ID. Usernames are matched case-insensitively.
+fn username_key(username: &str) -> String {
+    format!("username:{}", username.to_lowercase())
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn profile_by_username(username: &str) -> Fallible<Option<Profile>> {
+    let promise = UsersNs::get(&username_key(username), "text");
+    let val = JsFuture::from(promise).await.map_err(|e| Error {
+        internal: format!("{:?}", e),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-profile"),
+    })?;
+    let Some(id) = val.as_string() else {
+        return Ok(None);
+    };
+    let id = Uuid::parse_str(&id).map_err(|e| Error {
+        internal: format!("username index for {} is corrupt: {}", username, e),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-profile"),
+    })?;
+    let profile = profile_by_id(id).await?;
+    // A rename that failed halfway can leave the old name pointing here.
+    Ok(profile.filter(|p| username_key(&p.username) == username_key(username)))
+}
+
+#[cfg(feature = "sql")]
+pub async fn profile_by_username(username: &str) -> Fallible<Option<Profile>> {
+    sql::profile_by_username(&sql::database().await?, username).await
+}
+
+/// Whoever is signed in, if they still have a profile.
+pub async fn viewer(req: &Request) -> Fallible<Option<Profile>> {
+    let Some(session) = sessions::current(req) else {
+        return Ok(None);
+    };
+    let Some(mut profile) = profile_by_id(session.user_id).await? else {
+        return Ok(None);
+    };
+    // Only admins can give people roles, so the first one is named by the
+    // `ADMIN_USER_ID` secret, and made an admin when they sign in.
+    if profile.role != Role::Admin && first_admin() == Some(profile.id) {
+        console_logf!("making {} the first admin", profile.id);
+        profile.role = Role::Admin;
+        profile.clone().put().await?;
+    }
+    Ok(Some(profile))
+}
+
+fn first_admin() -> Option<Uuid> {
+    global_var("ADMIN_USER_ID").and_then(|id| Uuid::parse_str(id.trim()).ok())
+}
+
+/// Whoever is signed in. Anything that changes someone's data has to know
+/// whose it is, so it refuses anyone who isn't. Changes also have to come
+/// with the session's CSRF token.
+pub async fn signed_in(req: &Request) -> Fallible<Profile> {
+    let profile = viewer(req).await?.ok_or_else(signed_out)?;
+    if !matches!(req.method().as_str(), "GET" | "HEAD") {
+        sessions::check_csrf(req)?;
+    }
+    Ok(profile)
+}
+
+/// The error for doing something that needs someone to be signed in.
+pub fn signed_out() -> Error {
+    Error {
+        internal: "not signed in".to_owned(),
+        external: Message::new("error-signed-out"),
+        status: StatusCode::UNAUTHORIZED,
+    }
+}
+
+/// Whether the user's posts are only for the followers they've approved.
+pub async fn is_private(id: Uuid) -> Fallible<bool> {
+    Ok(matches!(profile_by_id(id).await?, Some(p) if p.is_private))
+}
+
+#[cfg(not(feature = "sql"))]
+pub async fn profile_by_id(id: Uuid) -> Fallible<Option<Profile>> {
+    let promise = UsersNs::get(&id.to_string(), "arrayBuffer");
+    let val = JsFuture::from(promise).await.map_err(|e| Error {
+        internal: format!("{:?}", e),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-profile"),
+    })?;
+    if val.is_null() || val.is_undefined() {
+        return Ok(None);
+    }
+    let typebuf: js_sys::Uint8Array = js_sys::Uint8Array::new(&val);
+    let mut body = vec![0; typebuf.length() as usize];
+    typebuf.copy_to(&mut body[..]);
+
+    let profile: Profile = storage::decode(&body).map_err(|e| Error {
+        internal: e,
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        external: Message::new("error-load-profile"),
+    })?;
+    Ok(Some(profile))
+}
+
+#[cfg(feature = "sql")]
+pub async fn profile_by_id(id: Uuid) -> Fallible<Option<Profile>> {
+    sql::profile_by_id(&sql::database().await?, id).await
 }
 
 #[wasm_bindgen]
@@ -0,0 +1,204 @@
+//! Reporting posts, and what moderators do about reports. Anyone can report
+//! a post. Moderators and admins see the reports on `/moderation`, and can
+//! hide the post, suspend its author, or dismiss the reports. Hiding and
+//! suspending can be undone from the same page. Only admins can suspend
+//! moderators and admins, or give people roles. The first admin is whoever
+//! the `ADMIN_USER_ID` secret names.
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::posts;
+use crate::models::reports::{self, Report};
+use crate::models::users::{self, Profile, Role};
+use crate::ratelimit::{self, Action};
+use crate::sessions;
+use crate::twoface::*;
+use crate::utils::*;
+use http::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+use web_sys::{Request, Response};
+
+/// What someone sends to report a post.
+#[derive(Deserialize)]
+struct NewReport {
+    /// Who made the post.
+    user_id: Uuid,
+    #[serde(default)]
+    reason: String,
+}
+
+/// A moderator acting on a post.
+#[derive(Deserialize)]
+struct PostAction {
+    user_id: Uuid,
+    post_id: Uuid,
+}
+
+/// A moderator acting on a user.
+#[derive(Deserialize)]
+struct UserAction {
+    user_id: Uuid,
+}
+
+/// An admin giving someone a role.
+#[derive(Deserialize)]
+struct RoleChange {
+    user_id: Uuid,
+    role: Role,
+}
+
+/// `POST /post/<id>/report`. People don't have to be signed in, so reports
+/// are rate limited by IP address as well as by user. Anyone who is signed
+/// in has to send their CSRF token, so other sites can't report as them.
+pub async fn report_post(req: Request, post_id: Uuid) -> Result<Response, Response> {
+    let viewer = users::viewer(&req).await.ok().flatten();
+    let lang = i18n::request_language(&req, viewer.as_ref());
+    let report =
+        async {
+            let reporter = viewer.as_ref().map(|v| v.id);
+            if sessions::current(&req).is_some() {
+                sessions::check_csrf(&req)?;
+            }
+            ratelimit::check(&req, Action::Report, reporter).await?;
+            let new: NewReport = users::read_json(&req, "error-malformed-report").await?;
+            let post = posts::post_by_user(new.user_id, reporter, post_id).await?;
+            let post = post.ok_or_else(|| Error {
+                internal: format!("no post {} by {} to report", post_id, new.user_id),
+                external: Message::new("error-not-found"),
+                status: StatusCode::NOT_FOUND,
+            })?;
+            let report = Report::new(post.id, post.user_id, reporter, new.reason, post.text)
+                .map_err(|e| Error {
+                    internal: e.to_string(),
+                    external: e,
+                    status: StatusCode::BAD_REQUEST,
+                })?;
+            reports::add_report(report).await
+        };
+    report.await.map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("post reported", None);
+    flash::set(&resp, &Message::new("flash-post-reported").localize(&lang));
+    Ok(resp)
+}
+
+/// `POST /moderation/<action>`, from the buttons on the moderation page.
+pub async fn act(req: Request, action: String) -> Result<Response, Response> {
+    let viewer = users::viewer(&req).await.ok().flatten();
+    let lang = i18n::request_language(&req, viewer.as_ref());
+    let done = async {
+        let moderator = require_moderator(viewer.clone())?;
+        sessions::check_csrf(&req)?;
+        match action.as_str() {
+            "hide" => {
+                let target: PostAction = users::read_json(&req, "error-malformed-action").await?;
+                posts::set_hidden(target.user_id, target.post_id, true).await?;
+                reports::resolve_post(target.post_id).await?;
+                Ok("flash-post-hidden")
+            }
+            "dismiss" => {
+                let target: PostAction = users::read_json(&req, "error-malformed-action").await?;
+                reports::resolve_post(target.post_id).await?;
+                Ok("flash-reports-dismissed")
+            }
+            "unhide" => {
+                let target: PostAction = users::read_json(&req, "error-malformed-action").await?;
+                posts::set_hidden(target.user_id, target.post_id, false).await?;
+                Ok("flash-post-unhidden")
+            }
+            "suspend" | "unsuspend" => {
+                let target: UserAction = users::read_json(&req, "error-malformed-action").await?;
+                let mut profile = existing_profile(target.user_id).await?;
+                check_can_suspend(&moderator, &profile)?;
+                let suspend = action == "suspend";
+                profile.suspended = suspend;
+                profile.put().await?;
+                if suspend {
+                    reports::resolve_author(target.user_id).await?;
+                    Ok("flash-user-suspended")
+                } else {
+                    Ok("flash-user-unsuspended")
+                }
+            }
+            "role" => {
+                if moderator.role != Role::Admin {
+                    return Err(forbidden("error-not-admin"));
+                }
+                let change: RoleChange = users::read_json(&req, "error-malformed-action").await?;
+                let mut profile = existing_profile(change.user_id).await?;
+                profile.role = change.role;
+                profile.put().await?;
+                Ok("flash-role-changed")
+            }
+            _ => Err(Error {
+                internal: format!("no moderation action {}", action),
+                external: Message::new("error-page-not-found"),
+                status: StatusCode::NOT_FOUND,
+            }),
+        }
+    };
+    let flash_id = done.await.map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("done", Some("/moderation".to_owned()));
+    flash::set(&resp, &Message::new(flash_id).localize(&lang));
+    Ok(resp)
+}
+
+/// The viewer, if they can moderate. Suspended moderators can't.
+pub fn require_moderator(viewer: Option<Profile>) -> Fallible<Profile> {
+    match viewer {
+        Some(profile) if profile.role.can_moderate() && !profile.suspended => Ok(profile),
+        _ => Err(forbidden("error-not-moderator")),
+    }
+}
+
+/// Only admins can suspend people who can moderate, or lift their
+/// suspensions.
+fn check_can_suspend(moderator: &Profile, target: &Profile) -> Fallible<()> {
+    if target.role.can_moderate() && moderator.role != Role::Admin {
+        return Err(forbidden("error-not-admin"));
+    }
+    Ok(())
+}
+
+async fn existing_profile(user_id: Uuid) -> Fallible<Profile> {
+    users::profile_by_id(user_id).await?.ok_or_else(|| Error {
+        internal: format!("no profile {} to moderate", user_id),
+        external: Message::new("error-not-found"),
+        status: StatusCode::NOT_FOUND,
+    })
+}
+
+fn forbidden(external: &'static str) -> Error {
+    Error {
+        internal: external.to_owned(),
+        external: Message::new(external),
+        status: StatusCode::FORBIDDEN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_admins_suspend_moderators() {
+        let with_role = |role| Profile {
+            role,
+            ..Profile::fixture("ada")
+        };
+        for (moderator, target, allowed) in &[
+            (Role::Moderator, Role::User, true),
+            (Role::Moderator, Role::Moderator, false),
+            (Role::Moderator, Role::Admin, false),
+            (Role::Admin, Role::Moderator, true),
+            (Role::Admin, Role::Admin, true),
+        ] {
+            let checked = check_can_suspend(&with_role(*moderator), &with_role(*target));
+            assert_eq!(checked.is_ok(), *allowed, "{:?} {:?}", moderator, target);
+        }
+        let mut suspended = with_role(Role::Moderator);
+        suspended.suspended = true;
+        assert!(require_moderator(Some(suspended)).is_err());
+        assert!(require_moderator(None).is_err());
+        assert!(require_moderator(Some(with_role(Role::Moderator))).is_ok());
+    }
+}
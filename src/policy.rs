@@ -0,0 +1,370 @@
+//! Keeps spam and abuse off quiet. Before a post is saved it goes through
+//! the content policy: a list of rules, each of which can refuse it and say
+//! why, then, if one's configured, a classifier running somewhere else.
+//!
+//! The rules are set with worker variables:
+//!
+//! - `BLOCKED_DOMAINS`: domains posts can't link to, with their subdomains,
+//!   separated by commas.
+//! - `BANNED_WORDS`: words or phrases posts can't contain, separated by commas.
+//! - `MAX_LINKS`: how many links a post can have, counting its link. 3 if unset.
+//! - `CLASSIFIER_URL`: where to send posts to be classified, and
+//!   `CLASSIFIER_TOKEN`, a secret sent with them as a bearer token.
+use crate::console_logf;
+use crate::i18n::Message;
+use crate::models::posts::Post;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::Duration;
+use futures::future::{FutureExt, LocalBoxFuture};
+use http::StatusCode;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use url::Url;
+
+const DEFAULT_MAX_LINKS: usize = 3;
+/// How long someone has to wait before posting the same thing again.
+const REPEAT_WINDOW_HOURS: i64 = 24;
+
+lazy_static! {
+    /// Links written in a post's text. People leave off the scheme, so
+    /// anything starting with www. counts too.
+    static ref LINK: Regex = Regex::new(r#"(?i)\b(?:https?://|www\.)[^\s<>"]+"#).unwrap();
+}
+
+/// Something a post has to satisfy to be saved.
+pub trait Rule {
+    /// Refuses `post`, saying why. `recent` are the author's posts so far.
+    fn check(&self, post: &Post, recent: &[Post]) -> Result<(), Message>;
+}
+
+/// Posts can't link to these domains or their subdomains.
+pub struct BlockedDomains(pub Vec<String>);
+
+/// Posts can't contain these words or phrases, whatever their case.
+pub struct BannedWords(pub Vec<String>);
+
+/// Posts can only have this many links, counting their link.
+pub struct MaxLinks(pub usize);
+
+/// Posts can't repeat one their author made within this long.
+pub struct NoRepeats(pub Duration);
+
+impl Rule for BlockedDomains {
+    fn check(&self, post: &Post, _recent: &[Post]) -> Result<(), Message> {
+        for host in links(post).iter().filter_map(|l| l.host_str()) {
+            let host = host.trim_end_matches('.').to_lowercase();
+            let blocked = self.0.iter().find(|domain| {
+                host == **domain
+                    || matches!(host.strip_suffix(domain.as_str()), Some(sub) if sub.ends_with('.'))
+            });
+            if let Some(domain) = blocked {
+                return Err(Message::new("policy-blocked-domain").arg("domain", domain.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule for BannedWords {
+    fn check(&self, post: &Post, _recent: &[Post]) -> Result<(), Message> {
+        // Padded with spaces so only whole words match.
+        let text = format!(" {} ", words(&post.text));
+        for banned in &self.0 {
+            let phrase = words(banned);
+            if !phrase.is_empty() && text.contains(&format!(" {} ", phrase)) {
+                return Err(Message::new("policy-banned-word").arg("word", banned.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule for MaxLinks {
+    fn check(&self, post: &Post, _recent: &[Post]) -> Result<(), Message> {
+        let count = links(post).len();
+        if count > self.0 {
+            return Err(Message::new("policy-too-many-links")
+                .arg("max", self.0)
+                .arg("count", count));
+        }
+        Ok(())
+    }
+}
+
+impl Rule for NoRepeats {
+    fn check(&self, post: &Post, recent: &[Post]) -> Result<(), Message> {
+        let text = words(&post.text);
+        let repeated = recent.iter().any(|p| {
+            post.created - p.created < self.0 && p.link == post.link && words(&p.text) == text
+        });
+        if repeated {
+            return Err(Message::new("policy-repeated"));
+        }
+        Ok(())
+    }
+}
+
+/// The links in a post: its link, then any in its text.
+fn links(post: &Post) -> Vec<Url> {
+    let in_text = LINK.find_iter(&post.text).filter_map(|m| {
+        let link = m.as_str().trim_end_matches(|c| ".,;:!?)]}'".contains(c));
+        if link.to_lowercase().starts_with("www.") {
+            Url::parse(&format!("http://{}", link)).ok()
+        } else {
+            Url::parse(link).ok()
+        }
+    });
+    post.link.iter().cloned().chain(in_text).collect()
+}
+
+/// `text` in lowercase, with every run of punctuation and spaces made into
+/// one space, so the same words compare equal however they're written.
+fn words(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What a classifier thinks of a post.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Refuse it, for this reason, which is shown to the author.
+    Reject(String),
+}
+
+/// Decides whether posts are spam or abuse, outside quiet.
+pub trait Classifier {
+    fn classify<'a>(&'a self, post: &'a Post) -> LocalBoxFuture<'a, Result<Verdict, String>>;
+}
+
+/// A classifier reached over HTTP. Posts are POSTed to it as JSON with
+/// `text`, `link` and `user_id`, and it answers `{"allow": true}` or
+/// `{"allow": false, "reason": "..."}`.
+pub struct HttpClassifier {
+    pub url: Url,
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Classification {
+    allow: bool,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl Classifier for HttpClassifier {
+    fn classify<'a>(&'a self, post: &'a Post) -> LocalBoxFuture<'a, Result<Verdict, String>> {
+        async move {
+            let body = json!({
+                "text": post.text,
+                "link": post.link,
+                "user_id": post.user_id,
+            });
+            let mut headers = vec![("content-type", "application/json".to_owned())];
+            if let Some(token) = &self.token {
+                headers.push(("authorization", format!("Bearer {}", token)));
+            }
+            let resp = fetch::post(&self.url, &headers, &body.to_string()).await?;
+            let c: Classification = serde_json::from_str(&resp)
+                .map_err(|e| format!("classifier answered {:?}: {}", resp, e))?;
+            Ok(if c.allow {
+                Verdict::Allow
+            } else {
+                Verdict::Reject(c.reason.unwrap_or_default())
+            })
+        }
+        .boxed_local()
+    }
+}
+
+mod fetch {
+    use url::Url;
+    use wasm_bindgen::{JsCast, JsValue};
+    use wasm_bindgen_futures::JsFuture;
+    use web_sys::{Headers, Request, RequestInit, Response, ServiceWorkerGlobalScope};
+
+    /// POSTs `body` to `url` and returns what comes back.
+    pub async fn post(url: &Url, headers: &[(&str, String)], body: &str) -> Result<String, String> {
+        let send = async {
+            let h = Headers::new()?;
+            for (name, value) in headers {
+                h.append(name, value)?;
+            }
+            let init = RequestInit::new();
+            init.set_method("POST");
+            init.set_headers(&h);
+            init.set_body(&JsValue::from_str(body));
+            let req = Request::new_with_str_and_init(url.as_str(), &init)?;
+            let global: ServiceWorkerGlobalScope = js_sys::global().unchecked_into();
+            let resp: Response = JsFuture::from(global.fetch_with_request(&req))
+                .await?
+                .unchecked_into();
+            if !resp.ok() {
+                return Err(JsValue::from_str(&format!("HTTP {}", resp.status())));
+            }
+            let text = JsFuture::from(resp.text()?).await?;
+            Ok(text.as_string().unwrap_or_default())
+        };
+        send.await
+            .map_err(|e: JsValue| format!("couldn't reach {}: {:?}", url, e))
+    }
+}
+
+/// The rules posts have to satisfy, and the classifier, if any.
+pub struct Policy {
+    pub rules: Vec<Box<dyn Rule>>,
+    pub classifier: Option<Box<dyn Classifier>>,
+}
+
+impl Policy {
+    /// The policy set with the worker's variables.
+    pub fn configured() -> Self {
+        let list = |name| -> Vec<String> {
+            global_var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        let max_links = global_var("MAX_LINKS")
+            .and_then(|m| m.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_LINKS);
+        let classifier = global_var("CLASSIFIER_URL")
+            .and_then(|u| Url::parse(&u).ok())
+            .map(|url| {
+                Box::new(HttpClassifier {
+                    url,
+                    token: global_var("CLASSIFIER_TOKEN"),
+                }) as Box<dyn Classifier>
+            });
+        Self {
+            rules: vec![
+                Box::new(BlockedDomains(list("BLOCKED_DOMAINS"))),
+                Box::new(BannedWords(list("BANNED_WORDS"))),
+                Box::new(MaxLinks(max_links)),
+                Box::new(NoRepeats(Duration::hours(REPEAT_WINDOW_HOURS))),
+            ],
+            classifier,
+        }
+    }
+
+    /// Refuses `post` if it breaks a rule or the classifier rejects it.
+    /// If the classifier can't be reached the post is let through, so
+    /// nobody is kept from posting while it's down.
+    pub async fn check(&self, post: &Post, recent: &[Post]) -> Fallible<()> {
+        let refuse = |external: Message| Error {
+            internal: format!("post by {} refused: {:?}", post.user_id, external),
+            external,
+            status: StatusCode::BAD_REQUEST,
+        };
+        for rule in &self.rules {
+            rule.check(post, recent).map_err(refuse)?;
+        }
+        if let Some(classifier) = &self.classifier {
+            match classifier.classify(post).await {
+                Ok(Verdict::Allow) => {}
+                Ok(Verdict::Reject(reason)) => {
+                    return Err(refuse(
+                        Message::new("policy-classifier-rejected").arg("reason", reason),
+                    ))
+                }
+                Err(e) => console_logf!("couldn't classify post by {}: {}", post.user_id, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::ready;
+
+    fn post(text: &str, link: Option<&str>) -> Post {
+        Post {
+            link: link.map(|l| Url::parse(l).unwrap()),
+            ..Post::fixture(text)
+        }
+    }
+
+    fn refused(rule: &dyn Rule, post: &Post) -> Option<String> {
+        rule.check(post, &[]).err().map(|m| m.to_string())
+    }
+
+    #[test]
+    fn blocked_domains_include_subdomains_and_links_in_text() {
+        let rule = BlockedDomains(vec!["spam.example".to_owned()]);
+        let blocked = |text, link| refused(&rule, &post(text, link)).is_some();
+        assert!(blocked("look", Some("https://spam.example/buy")));
+        assert!(blocked("look", Some("https://WWW.Spam.Example./buy")));
+        assert!(blocked("look at www.spam.example/buy.", None));
+        assert!(!blocked("look", Some("https://notspam.example/")));
+        assert!(!blocked("spam.example is a spam site", None));
+    }
+
+    #[test]
+    fn banned_words_are_whole_words() {
+        let rule = BannedWords(vec!["casino".to_owned(), "free money".to_owned()]);
+        let banned = |text| refused(&rule, &post(text, None)).is_some();
+        assert!(banned("Best CASINO in town!"));
+        assert!(banned("get FREE...money now"));
+        assert!(!banned("casinos are closed"));
+        assert!(!banned("free of money worries"));
+    }
+
+    #[test]
+    fn links_are_counted() {
+        let rule = MaxLinks(2);
+        let text = "see https://a.example and www.b.example";
+        assert!(refused(&rule, &post(text, None)).is_none());
+        let message = refused(&rule, &post(text, Some("https://c.example"))).unwrap();
+        assert!(message.contains('2') && message.contains('3'));
+    }
+
+    #[test]
+    fn repeats_are_refused_for_a_while() {
+        let rule = NoRepeats(Duration::hours(24));
+        let mut earlier = post("Buy my book!", Some("https://book.example"));
+        let mut again = post("buy my book", Some("https://book.example"));
+        again.user_id = earlier.user_id;
+        assert!(rule.check(&again, &[earlier.clone()]).is_err());
+        let elsewhere = post("buy my book", Some("https://other.example"));
+        assert!(rule.check(&elsewhere, &[earlier.clone()]).is_ok());
+        earlier.created = again.created - Duration::hours(25);
+        assert!(rule.check(&again, &[earlier]).is_ok());
+    }
+
+    struct Fixed(Verdict);
+
+    impl Classifier for Fixed {
+        fn classify<'a>(&'a self, _post: &'a Post) -> LocalBoxFuture<'a, Result<Verdict, String>> {
+            ready(Ok(self.0.clone())).boxed_local()
+        }
+    }
+
+    #[test]
+    fn classifiers_can_reject_posts() {
+        let policy = |verdict| Policy {
+            rules: Vec::new(),
+            classifier: Some(Box::new(Fixed(verdict))),
+        };
+        let p = post("hello", None);
+        block_on(async {
+            assert!(policy(Verdict::Allow).check(&p, &[]).await.is_ok());
+            let e = policy(Verdict::Reject("phishing".to_owned()))
+                .check(&p, &[])
+                .await
+                .unwrap_err();
+            assert_eq!(e.status, StatusCode::BAD_REQUEST);
+            assert!(e.external.to_string().contains("phishing"));
+        });
+    }
+}
@@ -0,0 +1,240 @@
+//! Limits how often people can post, sign up, sign in or report posts, so
+//! nobody can flood quiet.
+//! Each action has a token bucket per user and per client IP address: every
+//! request takes a token, tokens come back steadily over time, and when a
+//! bucket is empty the request is refused with 429 Too Many Requests.
+//!
+//! Buckets are kept in KV, which isn't transactional, so two requests at
+//! the same moment can both take the last token. That's fine for stopping
+//! floods, which is all this is for.
+use crate::console_logf;
+use crate::i18n::Message;
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime, Duration};
+use http::StatusCode;
+use js_sys::Promise;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::Request;
+
+/// Something people can only do so often.
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Post,
+    SignUp,
+    SignIn,
+    /// Anyone can report, so this is all that keeps the moderation queue
+    /// from being flooded.
+    Report,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Post => "post",
+            Action::SignUp => "signup",
+            Action::SignIn => "signin",
+            Action::Report => "report",
+        }
+    }
+
+    /// How often the action is allowed. A worker variable like
+    /// `RATE_LIMIT_POST = "10/60"`, for ten every minute, overrides the
+    /// default.
+    fn limit(self) -> Limit {
+        let default = match self {
+            Action::Post => Limit {
+                burst: 10,
+                per: Duration::minutes(1),
+            },
+            Action::SignUp => Limit {
+                burst: 3,
+                per: Duration::hours(1),
+            },
+            // Each one sends an email.
+            Action::SignIn => Limit {
+                burst: 5,
+                per: Duration::hours(1),
+            },
+            Action::Report => Limit {
+                burst: 10,
+                per: Duration::hours(1),
+            },
+        };
+        let var = format!("RATE_LIMIT_{}", self.name().to_uppercase());
+        global_var(&var)
+            .and_then(|l| Limit::parse(&l))
+            .unwrap_or(default)
+    }
+}
+
+/// Up to `burst` requests at once, refilling at `burst` every `per`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Bucket {
+    tokens: f64,
+    updated: DateTime<Utc>,
+}
+
+impl Limit {
+    /// Reads limits written like "10/60": ten requests every 60 seconds.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(2, '/');
+        let burst: u32 = parts.next()?.trim().parse().ok()?;
+        let secs: i64 = parts.next()?.trim().parse().ok()?;
+        if burst == 0 || secs <= 0 {
+            return None;
+        }
+        Some(Self {
+            burst,
+            per: Duration::seconds(secs),
+        })
+    }
+
+    /// Takes a token from `bucket`, which is full if it's new. Returns the
+    /// bucket without it, or if it's empty, how long until it won't be.
+    fn take(&self, bucket: Option<Bucket>, now: DateTime<Utc>) -> Result<Bucket, Duration> {
+        let burst = f64::from(self.burst);
+        let per_token = self.per.num_milliseconds() as f64 / burst;
+        let tokens = match bucket {
+            Some(b) => {
+                let elapsed = (now - b.updated).num_milliseconds().max(0) as f64;
+                (b.tokens + elapsed / per_token).min(burst)
+            }
+            None => burst,
+        };
+        if tokens < 1.0 {
+            let wait = ((1.0 - tokens) * per_token).ceil() as i64;
+            return Err(Duration::milliseconds(wait));
+        }
+        Ok(Bucket {
+            tokens: tokens - 1.0,
+            updated: now,
+        })
+    }
+}
+
+/// Lets `req` do `action` if neither the user nor their IP address has
+/// done it too often lately.
+pub async fn check(req: &Request, action: Action, user_id: Option<Uuid>) -> Fallible<()> {
+    let limit = action.limit();
+    let ip = req.headers().get("cf-connecting-ip").ok().flatten();
+    let keys: Vec<String> = user_id
+        .map(|id| format!("{}:user:{}", action.name(), id))
+        .into_iter()
+        .chain(ip.map(|ip| format!("{}:ip:{}", action.name(), ip)))
+        .collect();
+    let now = Utc::now();
+    let mut taken = Vec::with_capacity(keys.len());
+    for key in &keys {
+        match limit.take(bucket(key).await, now) {
+            Ok(bucket) => taken.push((key, bucket)),
+            Err(wait) => {
+                // Round up, so they don't come back a moment too soon.
+                let secs = (wait.num_milliseconds() + 999) / 1000;
+                return Err(Error {
+                    internal: format!("rate limited {} for {}s", key, secs),
+                    external: Message::new("error-rate-limited").arg("retry_after", secs),
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                });
+            }
+        }
+    }
+    // A full bucket is the same as none, so there's no need to keep one
+    // once it would have filled up again.
+    let ttl = limit.per.num_seconds().max(60);
+    for (key, bucket) in taken {
+        let val = serde_json::to_string(&bucket).unwrap();
+        let options = js_sys::Object::new();
+        js_sys::Reflect::set(
+            &options,
+            &JsValue::from_str("expirationTtl"),
+            &JsValue::from_f64(ttl as f64),
+        )
+        .ok();
+        if let Err(e) = JsFuture::from(RateLimitNs::put(key, &val, &options)).await {
+            console_logf!("couldn't save rate limit {}: {:?}", key, e);
+        }
+    }
+    Ok(())
+}
+
+/// The bucket under `key`. If it can't be read, the request is let through
+/// rather than blaming the user for it.
+async fn bucket(key: &str) -> Option<Bucket> {
+    let val = match JsFuture::from(RateLimitNs::get(key, "text")).await {
+        Ok(val) => val,
+        Err(e) => {
+            console_logf!("couldn't read rate limit {}: {:?}", key, e);
+            return None;
+        }
+    };
+    val.as_string().and_then(|v| serde_json::from_str(&v).ok())
+}
+
+#[wasm_bindgen]
+extern "C" {
+    type RateLimitNs;
+
+    #[wasm_bindgen(static_method_of = RateLimitNs)]
+    fn get(key: &str, data_type: &str) -> Promise;
+
+    #[wasm_bindgen(static_method_of = RateLimitNs)]
+    fn put(key: &str, val: &str, options: &js_sys::Object) -> Promise;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn bursts_are_allowed_then_tokens_trickle_back() {
+        let limit = Limit {
+            burst: 3,
+            per: Duration::minutes(1),
+        };
+        let start = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let mut bucket = None;
+        for _ in 0..3 {
+            bucket = Some(limit.take(bucket, start).unwrap());
+        }
+        assert_eq!(
+            limit.take(bucket.clone(), start),
+            Err(Duration::seconds(20))
+        );
+        let later = start + Duration::seconds(15);
+        assert_eq!(limit.take(bucket.clone(), later), Err(Duration::seconds(5)));
+
+        let refilled = limit.take(bucket, start + Duration::seconds(20)).unwrap();
+        assert!(refilled.tokens.abs() < 1e-9);
+        // However long it's left, it never holds more than a burst.
+        let idle = limit
+            .take(Some(refilled), start + Duration::days(1))
+            .unwrap();
+        assert!((idle.tokens - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn limits_read_from_variables() {
+        assert_eq!(
+            Limit::parse("10/60"),
+            Some(Limit {
+                burst: 10,
+                per: Duration::minutes(1)
+            })
+        );
+        assert_eq!(Limit::parse(" 5 / 3600 ").map(|l| l.burst), Some(5));
+        assert_eq!(Limit::parse("0/60"), None);
+        assert_eq!(Limit::parse("10"), None);
+        assert_eq!(Limit::parse("ten/60"), None);
+    }
+}
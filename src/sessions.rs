@@ -0,0 +1,186 @@
+//! Who's signed in. A session is a signed cookie naming the user, so there's
+//! nothing to store on our side. People sign in by opening a link we email
+//! to the address on their profile.
+//!
+//! Each session has a CSRF token, which pages put in their
+//! `<meta name="csrf-token">`. Anything that changes someone's data has to
+//! send it back, so other sites can't make a signed-in browser do so.
+use crate::console_logf;
+use crate::cookies;
+use crate::email;
+use crate::flash;
+use crate::i18n::{self, Message};
+use crate::models::users;
+use crate::ratelimit::{self, Action};
+use crate::twoface::*;
+use crate::utils::*;
+use chrono::{offset::Utc, DateTime, Duration};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use uuid::Uuid;
+use web_sys::{Request, Response};
+
+const COOKIE: &str = "quiet_session";
+
+/// What CSRF tokens are signed for.
+const CSRF: &str = "csrf";
+
+/// Where scripts send the CSRF token.
+const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Where links, which can't set headers, send it instead.
+const CSRF_PARAM: &str = "csrf";
+
+/// People have to sign in again after this long.
+const SIGNED_IN_FOR_DAYS: i64 = 30;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub user_id: Uuid,
+    /// New each time someone signs in, so no two sessions are the same.
+    pub id: Uuid,
+    /// Unix time the session ends. It's signed along with the rest, so it
+    /// holds even if the browser keeps the cookie longer.
+    pub expires: i64,
+}
+
+impl Session {
+    pub fn new(user_id: Uuid, now: DateTime<Utc>) -> Self {
+        Self {
+            user_id,
+            id: Uuid::new_v4(),
+            expires: (now + Duration::days(SIGNED_IN_FOR_DAYS)).timestamp(),
+        }
+    }
+
+    /// The token the session's pages send back with changes.
+    pub fn csrf_token(&self) -> Option<String> {
+        cookies::sign(CSRF, &self.id.to_string())
+    }
+
+    /// Whether `token` is this session's CSRF token.
+    fn sent_csrf_token(&self, token: &str) -> bool {
+        cookies::verify(CSRF, token) == Some(self.id.to_string())
+    }
+
+    /// The session in a cookie's (already verified) value, unless it's over.
+    fn parse(json: &str, now: DateTime<Utc>) -> Option<Self> {
+        serde_json::from_str(json)
+            .ok()
+            .filter(|s: &Session| s.expires >= now.timestamp())
+    }
+}
+
+/// The session `req` was sent with, if it has a genuine one that isn't over.
+pub fn current(req: &Request) -> Option<Session> {
+    let signed = cookies::get(req, COOKIE)?;
+    let json = cookies::verify(COOKIE, &signed)?;
+    Session::parse(&json, Utc::now())
+}
+
+/// Refuses a request that doesn't come with its session's CSRF token.
+pub fn check_csrf(req: &Request) -> Fallible<()> {
+    let from_header = req.headers().get(CSRF_HEADER).ok().flatten();
+    // Only a GET can send it in the URL, where it might be logged, since
+    // that's all a link can do.
+    let from_query = || {
+        if req.method() != "GET" {
+            return None;
+        }
+        let url = Url::parse(&req.url()).ok()?;
+        let token = url.query_pairs().find(|(k, _)| k == CSRF_PARAM)?.1;
+        Some(token.into_owned())
+    };
+    let token = from_header.or_else(from_query).unwrap_or_default();
+    match current(req) {
+        Some(session) if session.sent_csrf_token(&token) => Ok(()),
+        _ => Err(Error {
+            internal: "missing or wrong CSRF token".to_owned(),
+            external: Message::new("error-csrf"),
+            status: StatusCode::FORBIDDEN,
+        }),
+    }
+}
+
+/// Signs the user in on whichever browser gets `resp`.
+pub fn start(resp: &Response, user_id: Uuid) -> Fallible<()> {
+    let session = Session::new(user_id, Utc::now());
+    let json = serde_json::to_string(&session).unwrap();
+    let Some(signed) = cookies::sign(COOKIE, &json) else {
+        return Err(Error {
+            internal: "SESSION_SECRET isn't set, so nobody can sign in".to_owned(),
+            external: Message::new("error-no-sign-in"),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        });
+    };
+    let max_age = Duration::days(SIGNED_IN_FOR_DAYS).num_seconds() as u32;
+    let cookie = cookies::set_cookie(COOKIE, &signed, max_age);
+    resp.headers()
+        .append("set-cookie", &cookie)
+        .map_err(|e| Error {
+            internal: format!("couldn't set session cookie: {:?}", e),
+            external: Message::new("error-build-response"),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+        })
+}
+
+/// A `Set-Cookie` header value that signs whoever's signed in out.
+pub fn end() -> String {
+    cookies::clear_cookie(COOKIE)
+}
+
+/// What the sign-in page sends.
+#[derive(Deserialize)]
+struct SignInRequest {
+    username: String,
+}
+
+/// `POST /sign-in`. Emails a link to sign in to the user's address. The
+/// answer is the same whether or not there's such a user, so it can't be
+/// used to find out who has an account.
+pub async fn send_sign_in_link(req: Request) -> Result<Response, Response> {
+    let lang = i18n::request_language(&req, None);
+    let sent = async {
+        ratelimit::check(&req, Action::SignIn, None).await?;
+        let url = Url::parse(&req.url()).map_err(|e| Error {
+            internal: format!("couldn't parse request URL: {}", e),
+            external: Message::new("error-invalid-url"),
+            status: StatusCode::BAD_REQUEST,
+        })?;
+        let body: SignInRequest = users::read_json(&req, "error-malformed-sign-in").await?;
+        let Some(profile) = users::profile_by_username(&body.username).await? else {
+            console_logf!("sign-in link asked for unknown user {}", body.username);
+            return Ok(());
+        };
+        let link = email::sign_in_link(&url, profile.id)?;
+        let lang = i18n::request_language(&req, Some(&profile));
+        let subject = Message::new("email-sign-in-subject").localize(&lang);
+        let text = Message::new("email-sign-in-body")
+            .arg("username", profile.username.clone())
+            .arg("link", link.to_string())
+            .localize(&lang);
+        email::send(&profile.email, &subject, &text).await
+    };
+    sent.await.map_err(|e| e.localized_response(&lang))?;
+    let resp = success_response("sign-in link sent", Some("/".to_owned()));
+    flash::set(&resp, &Message::new("flash-sign-in-sent").localize(&lang));
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn sessions_end() {
+        let now = Utc.ymd(2020, 9, 1).and_hms(12, 0, 0);
+        let session = Session::new(Uuid::new_v4(), now);
+        let json = serde_json::to_string(&session).unwrap();
+        assert_eq!(Session::parse(&json, now), Some(session));
+        let later = now + Duration::days(SIGNED_IN_FOR_DAYS) + Duration::seconds(1);
+        assert_eq!(Session::parse(&json, later), None);
+        assert_eq!(Session::parse("{}", now), None);
+    }
+}
@@ -1,15 +1,36 @@
-use handlebars::Handlebars;
+use crate::i18n::{self, LanguageIdentifier};
+use crate::models::{posts, reports};
+use chrono::{offset::Utc, DateTime};
+use fluent_bundle::{FluentArgs, FluentValue};
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError};
 use lazy_static::lazy_static;
+use serde::Serialize;
+use uuid::Uuid;
 
+#[derive(Clone)]
 pub enum TemplateName {
     Base,
     Home,
     Error,
     NewPost,
     PostList,
+    Settings,
+    Moderation,
+    SignIn,
 }
 
 impl TemplateName {
+    pub const ALL: [TemplateName; 8] = [
+        Self::Base,
+        Self::Home,
+        Self::Error,
+        Self::NewPost,
+        Self::PostList,
+        Self::Settings,
+        Self::Moderation,
+        Self::SignIn,
+    ];
+
     pub fn name(&self) -> &'static str {
         match self {
             Self::Base => "base",
@@ -17,18 +38,376 @@ impl TemplateName {
             Self::Error => "error",
             Self::NewPost => "new_post",
             Self::PostList => "post_list",
+            Self::Settings => "settings",
+            Self::Moderation => "moderation",
+            Self::SignIn => "sign_in",
+        }
+    }
+
+    /// The template's source, embedded at compile time, so a variant without
+    /// a template file doesn't build.
+    fn source(&self) -> &'static str {
+        match self {
+            Self::Base => include_str!("templates/base.html"),
+            Self::Home => include_str!("templates/home.html"),
+            Self::Error => include_str!("templates/error.html"),
+            Self::NewPost => include_str!("templates/new_post.html"),
+            Self::PostList => include_str!("templates/post_list.html"),
+            Self::Settings => include_str!("templates/settings.html"),
+            Self::Moderation => include_str!("templates/moderation.html"),
+            Self::SignIn => include_str!("templates/sign_in.html"),
         }
     }
 }
+
 lazy_static! {
     pub static ref HBARS: Handlebars<'static> = {
-        // Register templates
         let mut hb = Handlebars::new();
-        hb.register_template_string(&TemplateName::Base.name(), include_str!("templates/base.html")).unwrap();
-        hb.register_template_string(&TemplateName::Home.name(), include_str!("templates/home.html")).unwrap();
-        hb.register_template_string(&TemplateName::Error.name(), include_str!("templates/error.html")).unwrap();
-        hb.register_template_string(&TemplateName::NewPost.name(), include_str!("templates/new_post.html")).unwrap();
-        hb.register_template_string(&TemplateName::PostList.name(), include_str!("templates/post_list.html")).unwrap();
+        // Rendering a variable the data doesn't have is an error, rather than
+        // silently rendering nothing.
+        hb.set_strict_mode(true);
+        hb.register_helper("t", Box::new(translate));
+        hb.register_helper("date", Box::new(date));
+        for template in TemplateName::ALL.iter() {
+            hb.register_template_string(template.name(), template.source())
+                .unwrap_or_else(|e| panic!("invalid template {}: {}", template.name(), e));
+        }
         hb
     };
 }
+
+/// `{{t "message-id" name=value}}` renders a message from the i18n catalog,
+/// in the page's `lang`. Unknown IDs are errors, like missing variables.
+fn translate(
+    h: &Helper,
+    hb: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let id = h
+        .param(0)
+        .and_then(|p| p.value().as_str())
+        .ok_or_else(|| RenderError::new("t needs a message ID"))?;
+    let lang: LanguageIdentifier = root_str(ctx, "lang")?
+        .parse()
+        .map_err(|_| RenderError::new("`lang` isn't a language tag"))?;
+    let mut args = FluentArgs::new();
+    for (name, value) in h.hash() {
+        let value = match value.value() {
+            serde_json::Value::Number(n) => FluentValue::from(n.as_f64().unwrap_or_default()),
+            serde_json::Value::String(s) => FluentValue::from(s.clone()),
+            other => FluentValue::from(other.to_string()),
+        };
+        args.set(*name, value);
+    }
+    let text = i18n::format(&lang, id, Some(&args))
+        .ok_or_else(|| RenderError::new(format!("no message {}", id)))?;
+    out.write(&hb.get_escape_fn()(&text))?;
+    Ok(())
+}
+
+/// `{{date created}}` renders a timestamp in the viewer's `timezone` and `locale`.
+fn date(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let date = h
+        .param(0)
+        .and_then(|p| p.value().as_str())
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        .ok_or_else(|| RenderError::new("date needs a timestamp"))?;
+    let formatted = i18n::format_date(
+        date.with_timezone(&Utc),
+        root_str(ctx, "timezone")?,
+        root_str(ctx, "locale")?,
+    );
+    out.write(&formatted)?;
+    Ok(())
+}
+
+/// A string from the page's top-level context, which helpers need in
+/// every template, however deeply they're nested.
+fn root_str<'a>(ctx: &'a Context, name: &str) -> Result<&'a str, RenderError> {
+    ctx.data()
+        .get(name)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| RenderError::new(format!("the page has no `{}`", name)))
+}
+
+/// Data every page needs, for `base.html`.
+#[derive(Serialize)]
+pub struct BaseContext {
+    pub title: String,
+    /// The language the page is written in.
+    pub lang: LanguageIdentifier,
+    /// IANA time zone that dates on the page are shown in.
+    pub timezone: String,
+    /// Locale tag, like "en-GB", for how dates on the page are written.
+    pub locale: String,
+    /// The signed-in user, if there is one.
+    pub current_user: Option<CurrentUser>,
+    /// One-off messages to show at the top of the page.
+    pub flash: Vec<String>,
+    /// The signed-in user's CSRF token, which forms on the page have to
+    /// send back.
+    pub csrf_token: Option<String>,
+}
+
+impl BaseContext {
+    pub fn new(title: impl Into<String>, lang: &LanguageIdentifier) -> Self {
+        Self {
+            title: title.into(),
+            lang: lang.clone(),
+            timezone: "UTC".to_owned(),
+            locale: i18n::default_locale(lang).to_owned(),
+            current_user: None,
+            flash: Vec::new(),
+            csrf_token: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CurrentUser {
+    pub id: Uuid,
+    pub username: String,
+    /// Whether to link to the moderation page.
+    pub moderator: bool,
+}
+
+/// For `home.html`, which is also used for profiles.
+#[derive(Serialize)]
+pub struct HomeContext {
+    /// Rendered by `post_list.html`.
+    pub posts: Vec<ListedPost>,
+    pub older_url: Option<String>,
+    /// What the viewer can do about whoever's profile it is, unless it's
+    /// their own or the home page.
+    pub profile: Option<ProfileActions>,
+}
+
+/// A post in a list, with its author's name to show beside their avatar.
+#[derive(Serialize)]
+pub struct ListedPost {
+    #[serde(flatten)]
+    pub post: posts::Post,
+    pub author: String,
+}
+
+#[derive(Serialize)]
+pub struct ProfileActions {
+    pub user_id: Uuid,
+    /// Whether the viewer follows them.
+    pub following: bool,
+    /// Whether the viewer has muted them.
+    pub muted: bool,
+}
+
+#[derive(Serialize)]
+pub struct ErrorContext {
+    pub error_message: String,
+    pub http_error: String,
+}
+
+#[derive(Serialize)]
+pub struct NewPostContext {}
+
+#[derive(Serialize)]
+pub struct SignInContext {}
+
+#[derive(Serialize)]
+pub struct SettingsContext {
+    pub languages: Vec<LanguageOption>,
+    /// Every IANA time zone name, to suggest while typing.
+    pub timezones: Vec<&'static str>,
+    /// The user's chosen locale, empty if they haven't chosen one.
+    pub chosen_locale: String,
+    pub username: String,
+    pub email: String,
+    pub bio: String,
+    pub is_private: bool,
+    /// The actors asking to follow the user, oldest first.
+    pub follow_requests: Vec<String>,
+    /// Everyone the user has blocked, by ID or actor URL.
+    pub blocked: Vec<String>,
+    /// Everyone the user has muted, by ID or actor URL.
+    pub muted: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ModerationContext {
+    /// Oldest first.
+    pub reports: Vec<reports::Report>,
+    /// Admins can also give people roles.
+    pub is_admin: bool,
+}
+
+/// One choice in the settings page's language picker.
+#[derive(Serialize)]
+pub struct LanguageOption {
+    pub tag: String,
+    /// What the language calls itself.
+    pub name: String,
+    pub selected: bool,
+}
+
+/// What a page template is rendered with: the page's own context, plus
+/// everything `base.html` needs.
+#[derive(Serialize)]
+struct Page<C> {
+    #[serde(flatten)]
+    base: BaseContext,
+    #[serde(flatten)]
+    page: C,
+}
+
+pub fn render_page<C: Serialize>(
+    template: TemplateName,
+    base: BaseContext,
+    ctx: C,
+) -> Result<String, RenderError> {
+    HBARS.render(template.name(), &Page { base, page: ctx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{offset::Utc, TimeZone};
+
+    fn post() -> ListedPost {
+        ListedPost {
+            post: posts::Post {
+                link: Some(url::Url::parse("https://example.com/").unwrap()),
+                ..posts::Post::fixture("hello")
+            },
+            author: "eve".to_owned(),
+        }
+    }
+
+    fn base() -> BaseContext {
+        BaseContext {
+            title: "quiet".to_owned(),
+            lang: "es".parse().unwrap(),
+            timezone: "Europe/Madrid".to_owned(),
+            locale: "es-ES".to_owned(),
+            current_user: Some(CurrentUser {
+                id: Uuid::new_v4(),
+                username: "adam".to_owned(),
+                moderator: true,
+            }),
+            flash: vec!["you made a post".to_owned()],
+            csrf_token: Some("token".to_owned()),
+        }
+    }
+
+    /// Renders each template with fixture data, the way the views do.
+    fn render_fixture(template: TemplateName) -> Result<String, RenderError> {
+        match template {
+            // The base layout expects the page that uses it to define a `page` partial.
+            TemplateName::Base => HBARS
+                .render_template(
+                    "{{#*inline \"page\"}}fixture page{{/inline}}{{> base}}",
+                    &base(),
+                )
+                .map_err(|e| RenderError::new(e.to_string())),
+            TemplateName::Home => render_page(
+                template,
+                base(),
+                HomeContext {
+                    posts: vec![post()],
+                    older_url: Some("/?before=cDE6MQ".to_owned()),
+                    profile: Some(ProfileActions {
+                        user_id: Uuid::new_v4(),
+                        following: true,
+                        muted: true,
+                    }),
+                },
+            ),
+            TemplateName::Error => render_page(
+                template,
+                base(),
+                ErrorContext {
+                    error_message: "Page not found".to_owned(),
+                    http_error: "404 Not Found".to_owned(),
+                },
+            ),
+            TemplateName::NewPost => render_page(template, base(), NewPostContext {}),
+            TemplateName::SignIn => render_page(template, base(), SignInContext {}),
+            TemplateName::Settings => render_page(
+                template,
+                base(),
+                SettingsContext {
+                    languages: vec![LanguageOption {
+                        tag: "es".to_owned(),
+                        name: "Español".to_owned(),
+                        selected: true,
+                    }],
+                    timezones: vec!["Europe/Madrid"],
+                    chosen_locale: String::new(),
+                    username: "adam".to_owned(),
+                    email: "adam@example.com".to_owned(),
+                    bio: "Writes one post a day".to_owned(),
+                    is_private: true,
+                    follow_requests: vec!["https://remote.example/users/carol".to_owned()],
+                    blocked: vec!["https://remote.example/users/bob".to_owned()],
+                    muted: vec![],
+                },
+            ),
+            TemplateName::Moderation => render_page(
+                template,
+                base(),
+                ModerationContext {
+                    reports: vec![reports::Report {
+                        id: Uuid::new_v4(),
+                        post_id: Uuid::new_v4(),
+                        author_id: Uuid::new_v4(),
+                        reporter_id: None,
+                        reason: "spam".to_owned(),
+                        text: "buy now".to_owned(),
+                        created: Utc.ymd(2020, 9, 1).and_hms(12, 0, 0),
+                    }],
+                    is_admin: true,
+                },
+            ),
+            TemplateName::PostList => HBARS.render(
+                template.name(),
+                &Page {
+                    base: base(),
+                    page: HomeContext {
+                        posts: vec![post()],
+                        older_url: None,
+                        profile: None,
+                    },
+                },
+            ),
+        }
+    }
+
+    #[test]
+    fn every_template_renders() {
+        for template in TemplateName::ALL.iter() {
+            if let Err(e) = render_fixture(template.clone()) {
+                panic!("couldn't render {}: {}", template.name(), e);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_variables_are_errors() {
+        // Like a page whose context forgot a field its template uses.
+        let rendered = HBARS.render(TemplateName::Error.name(), &base());
+        assert!(rendered.is_err());
+    }
+
+    #[test]
+    fn t_renders_in_the_page_language() {
+        let rendered = HBARS.render_template("{{t \"nav-about\"}}", &base());
+        assert_eq!(rendered.unwrap(), "acerca de");
+        let rendered = HBARS.render_template("{{t \"no-such-message\"}}", &base());
+        assert!(rendered.is_err());
+    }
+}
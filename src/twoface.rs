@@ -1,31 +1,58 @@
 use crate::console_logf;
+use crate::i18n::{self, LanguageIdentifier, Message};
 use http::StatusCode;
 use serde::Serialize;
 use std::fmt;
 use wasm_bindgen::prelude::*;
-use web_sys::{Response, ResponseInit};
+use web_sys::{Headers, Response, ResponseInit};
 
 pub type Fallible<T> = Result<T, Error>;
 
+/// An error with status 429 Too Many Requests or 503 Service Unavailable,
+/// whose message has a `retry_after` number, tells clients with the
+/// Retry-After header how many seconds to wait.
 #[derive(Debug)]
 pub struct Error {
     pub internal: String,
-    pub external: External,
+    /// What the user is told, translated when the response is made.
+    pub external: Message,
     pub status: StatusCode,
 }
 
+/// The body of an error response.
 #[derive(Debug, Serialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 pub struct External {
     pub msg: String,
 }
 
 impl Error {
+    /// An error response in the default language, for clients that aren't
+    /// people, like other ActivityPub servers.
     pub fn into_response(self) -> Response {
+        self.localized_response(&i18n::default_language())
+    }
+
+    pub fn localized_response(self, lang: &LanguageIdentifier) -> Response {
         console_logf!("{:?}", self.internal);
-        let mut init = ResponseInit::new();
-        init.status(self.status.into());
+        let init = ResponseInit::new();
+        init.set_status(self.status.into());
+        let headers = Headers::new()
+            .and_then(|h| h.append("content-type", "application/json").map(|_| h))
+            .map_err(|e| console_logf!("Error making response {:?}", e))
+            .unwrap();
+        if let Some(secs) = self.retry_after() {
+            headers
+                .append("retry-after", &secs.to_string())
+                .map_err(|e| console_logf!("Error making response {:?}", e))
+                .unwrap();
+        }
+        init.set_headers(&JsValue::from(headers));
         console_logf!("adam 1");
-        let body = serde_json::to_string(&self.external)
+        let external = External {
+            msg: self.external.localize(lang),
+        };
+        let body = serde_json::to_string(&external)
             .map_err(|e| console_logf!("Error making response {:?}", e))
             .unwrap();
         let resp = Response::new_with_opt_str_and_init(Some(&body), &init)
@@ -36,15 +63,27 @@ impl Error {
     }
 }
 
-impl Into<JsValue> for Error {
-    fn into(self) -> JsValue {
-        console_logf!("{:?}", self);
-        JsValue::from_str(&self.to_string())
+impl Error {
+    fn retry_after(&self) -> Option<u64> {
+        match self.status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => self
+                .external
+                .number_arg("retry_after")
+                .map(|secs| secs.max(0.0).ceil() as u64),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(e: Error) -> JsValue {
+        console_logf!("{:?}", e);
+        JsValue::from_str(&e.to_string())
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "HTTP {}: {}", self.status, self.external.msg)
+        write!(f, "HTTP {}: {}", self.status, self.external)
     }
 }
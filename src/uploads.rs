@@ -0,0 +1,59 @@
+//! Files people upload through multipart forms.
+use crate::i18n::Message;
+use crate::twoface::*;
+use http::StatusCode;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Blob, FormData, Request};
+
+pub struct Upload {
+    /// As the browser reported it, so don't trust it.
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Reads the file uploaded in form field `field` of `req`, if it's no
+/// bigger than `max_mb` megabytes.
+pub async fn read(req: &Request, field: &str, max_mb: u32) -> Fallible<Upload> {
+    let max_bytes = f64::from(max_mb) * 1024.0 * 1024.0;
+    let too_large = || Error {
+        internal: format!("upload in {} is too large", field),
+        external: Message::new("upload-too-large").arg("max", max_mb),
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+    };
+    // Check the whole body's size first, so a huge one isn't parsed at all.
+    let content_length = req.headers().get("content-length").ok().flatten();
+    if let Some(Ok(length)) = content_length.map(|l| l.parse::<f64>()) {
+        // Leave room for the rest of the form around the file.
+        if length > max_bytes + 64.0 * 1024.0 {
+            return Err(too_large());
+        }
+    }
+    let read_error = |e: JsValue| Error {
+        internal: format!("couldn't read upload: {:?}", e),
+        external: Message::new("error-read-form"),
+        status: StatusCode::BAD_REQUEST,
+    };
+    let form: FormData = JsFuture::from(req.form_data().map_err(read_error)?)
+        .await
+        .map_err(read_error)?
+        .unchecked_into();
+    let Ok(file) = form.get(field).dyn_into::<Blob>() else {
+        return Err(Error {
+            internal: format!("form has no file in {}", field),
+            external: Message::new("upload-missing"),
+            status: StatusCode::BAD_REQUEST,
+        });
+    };
+    if file.size() > max_bytes {
+        return Err(too_large());
+    }
+    let buf = JsFuture::from(file.array_buffer())
+        .await
+        .map_err(read_error)?;
+    Ok(Upload {
+        content_type: file.type_(),
+        bytes: js_sys::Uint8Array::new(&buf).to_vec(),
+    })
+}
@@ -1,4 +1,5 @@
 use cfg_if::cfg_if;
+use chrono::{offset::Utc, DateTime};
 use js_sys::Error;
 use std::fmt::Display;
 use wasm_bindgen::prelude::*;
@@ -75,21 +76,50 @@ macro_rules! console_logf {
 }
 
 pub fn success_response(body: &str, url: Option<String>) -> Response {
-    let mut init = ResponseInit::new();
-    init.status(http::StatusCode::OK.into());
+    let init = ResponseInit::new();
+    init.set_status(http::StatusCode::OK.into());
     if let Some(url) = url {
         let headers = redirect_headers(&url)
             .map_err(|e| console_logf!("Error making response: {:?}", e))
             .unwrap();
-        init.headers(&JsValue::from(headers));
+        init.set_headers(&JsValue::from(headers));
     }
     Response::new_with_opt_str_and_init(Some(body), &init)
         .map_err(|e| console_logf!("Error making response: {:?}", e))
         .unwrap()
 }
 
+/// Formats a time the way HTTP headers like `Date` and `Last-Modified` want it.
+pub fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// For `#[serde(skip_serializing_if)]`, so flags that are almost always off
+/// aren't stored.
+pub fn is_false(b: &bool) -> bool {
+    !b
+}
+
 pub fn redirect_headers(url: &str) -> Result<Headers, JsValue> {
     let headers = Headers::new()?;
     headers.set("location", url)?;
     Ok(headers)
 }
+
+/// Reads a variable or secret bound to the worker, e.g. with `wrangler secret put`.
+/// Returns None if it isn't set.
+pub fn global_var(name: &str) -> Option<String> {
+    js_sys::Reflect::get(&js_sys::global(), &JsValue::from_str(name))
+        .ok()
+        .and_then(|v| v.as_string())
+}
+
+/// Reads a JS value, like a parsed request body, into a Rust type by way of
+/// JSON.
+pub fn from_js<T: serde::de::DeserializeOwned>(val: &JsValue) -> Result<T, String> {
+    let json = js_sys::JSON::stringify(val)
+        .map_err(|e| format!("{:?}", e))?
+        .as_string()
+        .unwrap_or_default();
+    serde_json::from_str(&json).map_err(|e| e.to_string())
+}
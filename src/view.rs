@@ -1,98 +1,418 @@
-use crate::models::posts;
-use crate::templates::{TemplateName, HBARS};
+use crate::cache;
+use crate::email;
+use crate::flash;
+use crate::i18n::{self, LanguageIdentifier, Message};
+use crate::models::blocks::{self, Account, Blocks};
+use crate::models::{followers, posts, reports, users, users::Profile, users::Role};
+use crate::moderation;
+use crate::sessions;
+use crate::templates::{
+    self, BaseContext, CurrentUser, ErrorContext, HomeContext, LanguageOption, ListedPost,
+    ModerationContext, NewPostContext, ProfileActions, SettingsContext, SignInContext,
+    TemplateName,
+};
 use crate::twoface;
 use crate::utils::*;
+use chrono::offset::Utc;
+use http::StatusCode;
 use js_sys::Promise;
-use lazy_static::lazy_static;
 use serde::Serialize;
-use std::collections::BTreeMap;
+use std::collections::HashMap;
+use url::Url;
 use uuid::Uuid;
 use wasm_bindgen::prelude::*;
 use web_sys::{Headers, Request, Response, ResponseInit};
 
-lazy_static! {
-    static ref BASE: &'static str = TemplateName::Base.name();
-}
-
-pub fn generate_error_response(error: twoface::Error) -> JsResult {
+pub fn generate_error_response(error: twoface::Error, lang: &LanguageIdentifier) -> JsResult {
     let status = error.status;
-    let http_error = format!(
-        "{} {}",
-        status.as_u16(),
-        status.canonical_reason().unwrap_or("Unknown Error")
-    );
-    let data: BTreeMap<_, _> = [
-        ("title", "Error"),
-        ("error_message", &error.external.msg),
-        ("http_error", &http_error),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    let body = HBARS
-        .render(TemplateName::Error.name(), &data)
-        .ok_or_js_err_with_msg("failed to render error page")?;
-
-    let headers = Headers::new()?;
-    headers.append("content-type", "text/html")?;
-    let resp = generate_response(&body, status.as_u16(), &headers)?;
-    Ok(JsValue::from(resp))
+    let ctx = ErrorContext {
+        error_message: error.external.localize(lang),
+        http_error: format!(
+            "{} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Unknown Error")
+        ),
+    };
+    let title = Message::new("error-title").localize(lang);
+    render_page(
+        TemplateName::Error,
+        BaseContext::new(title, lang),
+        ctx,
+        status,
+    )
 }
 
-pub fn render_error(error: twoface::Error) -> Promise {
-    match generate_error_response(error) {
+pub fn render_error(error: twoface::Error, lang: &LanguageIdentifier) -> Promise {
+    match generate_error_response(error, lang) {
         Ok(v) => Promise::resolve(&v),
         Err(e) => Promise::reject(&e),
     }
 }
 
 fn generate_response(body: &str, status: u16, headers: &Headers) -> Result<Response, JsValue> {
-    let mut init = ResponseInit::new();
-    init.status(status);
-    init.headers(&JsValue::from(headers));
+    let init = ResponseInit::new();
+    init.set_status(status);
+    init.set_headers(&JsValue::from(headers));
     Response::new_with_opt_str_and_init(Some(body), &init)
 }
 
-pub async fn render_home(_: Request) -> JsResult {
-    let test_user = Uuid::parse_str("fc53b101-1756-4b8f-b5fe-b71d103e9f20").unwrap();
-    let posts = match posts::all_posts_by_user(test_user).await {
-        Ok(p) => p,
-        Err(e) => return generate_error_response(e),
-    };
-    #[derive(Serialize)]
-    struct Data {
-        title: String,
-        parent: String,
-        posts: Vec<posts::Post>,
-        post_list_template: String,
+/// Who is looking at the page `req` asks for, and the base context for
+/// showing it to them in their language, time zone and date format.
+async fn base_context(req: &Request) -> twoface::Fallible<(Option<Profile>, BaseContext)> {
+    let viewer = users::viewer(req).await?;
+    let lang = i18n::request_language(req, viewer.as_ref());
+    let mut base = BaseContext::new("quiet", &lang);
+    base.flash = flash::take(req);
+    if let Some(viewer) = &viewer {
+        base.csrf_token = sessions::current(req).and_then(|s| s.csrf_token());
+        base.timezone = viewer.timezone.clone();
+        base.locale = viewer.date_locale(&lang);
+        base.current_user = Some(CurrentUser {
+            id: viewer.id,
+            username: viewer.username.clone(),
+            moderator: viewer.role.can_moderate(),
+        });
     }
-    let data = Data {
-        title: "quiet".to_owned(),
-        parent: BASE.to_string(),
-        posts,
-        post_list_template: TemplateName::PostList.name().to_owned(),
-    };
-    let body = HBARS
-        .render(TemplateName::Home.name(), &data)
-        .ok_or_js_err_with_msg("failed to render homepage")?;
+    Ok((viewer, base))
+}
+
+/// Renders a page template into an HTML response.
+fn render_page<C: Serialize>(
+    template: TemplateName,
+    base: BaseContext,
+    ctx: C,
+    status: StatusCode,
+) -> JsResult {
+    let name = template.name();
+    let shows_flash = !base.flash.is_empty();
+    let body = templates::render_page(template, base, ctx)
+        .ok_or_js_err_with_msg(&format!("failed to render {}", name))?;
     let headers = Headers::new()?;
     headers.append("content-type", "text/html")?;
-    let resp = generate_response(&body, 200, &headers)?;
+    if shows_flash {
+        headers.append("set-cookie", &flash::clear())?;
+    }
+    let resp = generate_response(&body, status.as_u16(), &headers)?;
+    Ok(JsValue::from(resp))
+}
+
+/// Renders a page template into HTML that can be cached.
+fn render_cacheable<C: Serialize>(
+    template: TemplateName,
+    base: BaseContext,
+    ctx: C,
+) -> twoface::Fallible<cache::Page> {
+    let name = template.name();
+    let body = templates::render_page(template, base, ctx).map_err(|e| twoface::Error {
+        internal: format!("failed to render {}: {}", name, e),
+        external: Message::new("error-build-response"),
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+    Ok(cache::Page::new(body, "text/html", None))
+}
+
+/// Whose posts a page lists.
+enum Listing {
+    /// The viewer's home feed.
+    Home,
+    /// One user's profile.
+    Profile(Uuid),
+}
+
+/// `GET /`, the signed-in viewer's feed. Anyone else is sent to sign in.
+pub async fn render_home(req: Request) -> JsResult {
+    render_post_list(req, Listing::Home).await
+}
+
+pub async fn render_profile(req: Request, user_id: Uuid) -> JsResult {
+    render_post_list(req, Listing::Profile(user_id)).await
+}
+
+/// Pairs each post with its author's username. Posts by deleted users are
+/// left out.
+async fn with_authors(posts: Vec<posts::Post>) -> twoface::Fallible<Vec<ListedPost>> {
+    let mut authors: HashMap<Uuid, Option<String>> = HashMap::new();
+    let mut listed = Vec::with_capacity(posts.len());
+    for post in posts {
+        let author = match authors.get(&post.user_id) {
+            Some(author) => author.clone(),
+            None => {
+                let profile = users::profile_by_id(post.user_id).await?;
+                let author = profile.map(|p| p.username);
+                authors.insert(post.user_id, author.clone());
+                author
+            }
+        };
+        if let Some(author) = author {
+            listed.push(ListedPost { post, author });
+        }
+    }
+    Ok(listed)
+}
+
+/// Renders one page of posts, linking to the next page of older ones.
+async fn render_post_list(req: Request, listing: Listing) -> JsResult {
+    let url = Url::parse(&req.url()).ok_or_js_err()?;
+    let (viewer, mut base) = match base_context(&req).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    let feed = matches!(listing, Listing::Home);
+    let user_id = match (listing, &viewer) {
+        (Listing::Home, Some(viewer)) => {
+            base.title = "quiet".to_owned();
+            viewer.id
+        }
+        (Listing::Home, None) => {
+            let headers = redirect_headers("/sign-in")?;
+            let resp = generate_response("", StatusCode::SEE_OTHER.as_u16(), &headers)?;
+            return Ok(JsValue::from(resp));
+        }
+        (Listing::Profile(user_id), _) => {
+            base.title = format!("quiet. {}", user_id);
+            user_id
+        }
+    };
+    let lang = base.lang.clone();
+    let query = match posts::PageQuery::from_url(&url) {
+        Ok(q) => q,
+        Err(e) => {
+            return generate_error_response(
+                twoface::Error {
+                    internal: e.to_string(),
+                    external: e,
+                    status: StatusCode::BAD_REQUEST,
+                },
+                &lang,
+            )
+        }
+    };
+    let viewer_id = viewer.as_ref().map(|v| v.id);
+    let viewer_blocks = match &viewer {
+        Some(v) => match blocks::either_blocks(v.id, user_id).await {
+            Ok(true) => {
+                return generate_error_response(blocks::blocked_error(v.id, user_id), &lang)
+            }
+            Ok(false) => blocks::blocks_of(v.id).await,
+            Err(e) => Err(e),
+        },
+        None => Ok(Blocks::default()),
+    };
+    let viewer_blocks = match viewer_blocks {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    let following = match viewer_id {
+        Some(id) => followers::following_of(id).await,
+        None => Ok(Vec::new()),
+    };
+    let following = match following {
+        Ok(f) => f,
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    // Buttons to follow, block or mute whoever's profile it is. Blocked
+    // profiles aren't shown, so they can only be unblocked from the
+    // settings page.
+    let actions = match &viewer {
+        Some(v) if v.id != user_id => Some(ProfileActions {
+            user_id,
+            following: following.contains(&user_id),
+            muted: viewer_blocks.muted.contains(&Account::Local(user_id)),
+        }),
+        _ => None,
+    };
+    let shows_flash = !base.flash.is_empty();
+    let render = async {
+        let page = if feed {
+            posts::page_of_home_feed(user_id, &following, &viewer_blocks, &query).await?
+        } else {
+            posts::page_of_posts_by_user(user_id, viewer_id, &query).await?
+        };
+        let older_url = query.older_url(url.path(), &page);
+        let ctx = HomeContext {
+            older_url,
+            posts: with_authors(page.posts).await?,
+            profile: actions,
+        };
+        render_cacheable(TemplateName::Home, base, ctx)
+    };
+    // A flash is only shown once, so pages with one aren't cached.
+    let page = if shows_flash {
+        render.await
+    } else {
+        // The page shows the viewer's name, time zone and date format, and
+        // their session's CSRF token. The home feed shows the posts of
+        // everyone they follow too.
+        let mut users: Vec<Uuid> = std::iter::once(user_id).chain(viewer_id).collect();
+        if feed {
+            users.extend(&following);
+        }
+        let lang_tag = lang.to_string();
+        let session = sessions::current(&req).map(|s| s.id.to_string());
+        let mut vary = vec![("lang", lang_tag.as_str())];
+        vary.extend(session.as_deref().map(|id| ("session", id)));
+        cache::page(&url, &vary, &users, render).await
+    };
+    let page = match page {
+        Ok(p) => p,
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    // It has the viewer's details in it, so browsers may keep it but must
+    // check it's still current.
+    let resp = page.response(&req, "private, no-cache")?;
+    if shows_flash {
+        resp.headers().append("set-cookie", &flash::clear())?;
+    }
+    Ok(JsValue::from(resp))
+}
+
+pub async fn render_sign_in(req: Request) -> JsResult {
+    let (_, mut base) = match base_context(&req).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    base.title = Message::new("sign-in-title").localize(&base.lang);
+    render_page(TemplateName::SignIn, base, SignInContext {}, StatusCode::OK)
+}
+
+pub async fn render_new_post(req: Request) -> JsResult {
+    let (viewer, mut base) = match base_context(&req).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    if viewer.is_none() {
+        return generate_error_response(users::signed_out(), &base.lang);
+    }
+    base.title = Message::new("new-post-title").localize(&base.lang);
+    render_page(
+        TemplateName::NewPost,
+        base,
+        NewPostContext {},
+        StatusCode::OK,
+    )
+}
+
+pub async fn render_settings(req: Request) -> JsResult {
+    let (viewer, mut base) = match base_context(&req).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    let Some(viewer) = viewer else {
+        return generate_error_response(users::signed_out(), &base.lang);
+    };
+    base.title = Message::new("settings-title").localize(&base.lang);
+    let viewer_blocks = match blocks::blocks_of(viewer.id).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &base.lang),
+    };
+    let follow_requests = match followers::follow_requests_of(viewer.id).await {
+        Ok(r) => r,
+        Err(e) => return generate_error_response(e, &base.lang),
+    };
+    let chosen = viewer.language.and_then(|l| i18n::supported(&l));
+    let ctx = SettingsContext {
+        languages: i18n::languages()
+            .map(|lang| LanguageOption {
+                tag: lang.to_string(),
+                name: Message::new("language-name").localize(lang),
+                selected: chosen.as_ref() == Some(lang),
+            })
+            .collect(),
+        timezones: chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect(),
+        chosen_locale: viewer.locale.unwrap_or_default(),
+        username: viewer.username,
+        email: viewer.email,
+        bio: viewer.bio,
+        is_private: viewer.is_private,
+        follow_requests: follow_requests
+            .iter()
+            .map(|r| r.actor.to_string())
+            .collect(),
+        blocked: viewer_blocks
+            .blocked
+            .iter()
+            .map(|a| a.to_string())
+            .collect(),
+        muted: viewer_blocks.muted.iter().map(|a| a.to_string()).collect(),
+    };
+    render_page(TemplateName::Settings, base, ctx, StatusCode::OK)
+}
 
+/// `GET /moderation`: the reports waiting for a moderator.
+pub async fn render_moderation(req: Request) -> JsResult {
+    let (viewer, mut base) = match base_context(&req).await {
+        Ok(b) => b,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    let moderator = match moderation::require_moderator(viewer) {
+        Ok(m) => m,
+        Err(e) => return generate_error_response(e, &base.lang),
+    };
+    base.title = Message::new("moderation-title").localize(&base.lang);
+    let reports = match reports::open_reports().await {
+        Ok(r) => r,
+        Err(e) => return generate_error_response(e, &base.lang),
+    };
+    let ctx = ModerationContext {
+        reports,
+        is_admin: moderator.role == Role::Admin,
+    };
+    render_page(TemplateName::Moderation, base, ctx, StatusCode::OK)
+}
+
+/// `GET /sign-in/confirm`, from the link we email to sign in.
+pub async fn confirm_sign_in(req: Request) -> JsResult {
+    let lang = i18n::request_language(&req, None);
+    let url = Url::parse(&req.url()).ok_or_js_err()?;
+    let token = url
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    let user_id = match email::signed_in_user(&token, Utc::now()) {
+        Ok(id) => id,
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    let profile = match users::profile_by_id(user_id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return generate_error_response(users::signed_out(), &lang),
+        Err(e) => return generate_error_response(e, &lang),
+    };
+    let lang = i18n::request_language(&req, Some(&profile));
+    let headers = redirect_headers("/")?;
+    let resp = generate_response("", StatusCode::SEE_OTHER.as_u16(), &headers)?;
+    if let Err(e) = sessions::start(&resp, profile.id) {
+        return generate_error_response(e, &lang);
+    }
+    let msg = Message::new("flash-signed-in").arg("username", profile.username);
+    flash::set(&resp, &msg.localize(&lang));
     Ok(JsValue::from(resp))
 }
 
-pub async fn render_new_post(_: Request) -> JsResult {
-    let data: BTreeMap<_, _> = [("title", "quiet. new post."), ("parent", *BASE)]
-        .iter()
-        .cloned()
-        .collect();
-    let body = HBARS
-        .render(TemplateName::NewPost.name(), &data)
-        .ok_or_js_err_with_msg("failed to render new_post")?;
-    let headers = Headers::new()?;
-    headers.append("content-type", "text/html")?;
-    let resp = generate_response(&body, 200, &headers)?;
+/// `POST /sign-out`, from the button on every page. It needs the CSRF token
+/// like anything else that changes things, so other sites can't sign people
+/// out.
+pub async fn sign_out(req: Request) -> JsResult {
+    let lang = i18n::request_language(&req, None);
+    if let Err(e) = sessions::check_csrf(&req) {
+        return Ok(JsValue::from(e.localized_response(&lang)));
+    }
+    let resp = success_response("signed out", Some("/".to_owned()));
+    resp.headers().append("set-cookie", &sessions::end())?;
+    flash::set(&resp, &Message::new("flash-signed-out").localize(&lang));
+    Ok(JsValue::from(resp))
+}
 
+/// `GET /settings/verify-email`, from the link we email to confirm a new address.
+pub async fn confirm_email(req: Request) -> JsResult {
+    let profile = match users::confirm_email(&req).await {
+        Ok(p) => p,
+        Err(e) => return generate_error_response(e, &i18n::request_language(&req, None)),
+    };
+    let lang = i18n::request_language(&req, Some(&profile));
+    let headers = redirect_headers("/settings")?;
+    let resp = generate_response("", StatusCode::SEE_OTHER.as_u16(), &headers)?;
+    let msg = Message::new("flash-email-changed").arg("email", profile.email);
+    flash::set(&resp, &msg.localize(&lang));
     Ok(JsValue::from(resp))
 }
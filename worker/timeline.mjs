@@ -0,0 +1,50 @@
+// The Durable Object behind the `durable-timelines` feature: one per user,
+// holding their posts as the bytes the Rust code gives it. See
+// src/models/timelines.rs for the protocol. Deploy it as its own
+// module-syntax script named "quiet-timelines", exporting Timeline.
+
+export class Timeline {
+  constructor(state) {
+    this.storage = state.storage;
+  }
+
+  async fetch(request) {
+    // Each write bumps this, and it's the ETag, so it never repeats.
+    const writes = (await this.storage.get("writes")) || 0;
+    const value = await this.storage.get("value");
+    const etag = value === undefined ? null : String(writes);
+
+    switch (request.method) {
+      case "GET":
+        if (etag === null) {
+          return new Response(null, { status: 404 });
+        }
+        return new Response(value, { headers: { etag } });
+
+      case "PUT": {
+        const ifMatch = request.headers.get("if-match");
+        const expected = request.headers.get("if-none-match") === "*" ? null : ifMatch;
+        if (expected !== etag) {
+          return new Response(null, { status: 412 });
+        }
+        const bytes = new Uint8Array(await request.arrayBuffer());
+        await this.storage.put({ writes: writes + 1, value: bytes });
+        return new Response(null, { status: 204, headers: { etag: String(writes + 1) } });
+      }
+
+      case "DELETE":
+        await this.storage.put("writes", writes + 1);
+        await this.storage.delete("value");
+        return new Response(null, { status: 204 });
+
+      default:
+        return new Response(null, { status: 405 });
+    }
+  }
+}
+
+export default {
+  fetch() {
+    return new Response(null, { status: 404 });
+  },
+};